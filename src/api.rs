@@ -1,206 +1,2646 @@
-use std::marker::PhantomData;
+use crate::db::{self, Appointment, Claims, DB};
+use crate::payments::{PaymentProvider, PaymentSettings};
+use crate::storage::{AttachmentSettings, Storage};
+use crate::{hl7, oidc, payments, print, rbac};
+use chrono::{DateTime, Duration, FixedOffset};
+use derive_more::From;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Header, RawStr, Status};
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::response::content::Html;
+use rocket::response::status::{BadRequest, Custom, Forbidden};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::result::Result;
+
+#[post("/register", format = "json", data = "<data>")]
+pub fn register(data: Json<db::RegisterData>) {
+    let db = DB::default().unwrap();
+    db.register(data.0).unwrap();
+}
+
+#[post("/login", format = "json", data = "<data>")]
+pub fn login(
+    data: Json<db::LoginData>,
+    client: db::ClientInfo,
+    policy: &State<db::LoginPolicy>,
+) -> Result<Json<db::LoginOutcome>, Custom<String>> {
+    let db = DB::default().unwrap();
+
+    db.login(data.0, &client, policy)
+        .map(Json::from)
+        .map_err(login_error)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpLoginInput {
+    challenge: String,
+    code: String,
+}
+
+#[post("/login/totp", format = "json", data = "<input>")]
+pub fn login_totp(
+    input: Json<TotpLoginInput>,
+    client: db::ClientInfo,
+    policy: &State<db::LoginPolicy>,
+) -> Result<String, Custom<String>> {
+    let db = DB::default().unwrap();
+    db.login_totp(&input.challenge, &input.code, &client, policy)
+        .map_err(login_error)
+}
+
+fn login_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidPassword => Custom(
+            Status::Unauthorized,
+            "Invalid email or password".to_string(),
+        ),
+        db::Error::InvalidTotpCode | db::Error::JWTError(_) => {
+            Custom(Status::Unauthorized, "Invalid code".to_string())
+        }
+        db::Error::LoginThrottled(secs) => Custom(
+            Status::TooManyRequests,
+            format!("Too many failed attempts, retry in {} seconds", secs),
+        ),
+        db::Error::AccountLocked(secs) => Custom(
+            Status::TooManyRequests,
+            format!("Account locked, retry in {} seconds", secs),
+        ),
+        db::Error::PasswordLoginDisabled => Custom(
+            Status::Forbidden,
+            "Password login is disabled for this account, use SSO".to_string(),
+        ),
+        db::Error::AccountDeactivated => {
+            Custom(Status::Forbidden, "Account is deactivated".to_string())
+        }
+        db::Error::SsoAccountNotFound => Custom(
+            Status::Forbidden,
+            "No staff account matches this identity".to_string(),
+        ),
+        e => panic!("{:?}", e),
+    }
+}
+
+fn sso_config(settings: &oidc::OidcSettings) -> Result<&oidc::OidcConfig, Custom<String>> {
+    settings
+        .oidc
+        .as_ref()
+        .ok_or_else(|| Custom(Status::NotFound, "SSO is not configured".to_string()))
+}
+
+fn sso_error(e: oidc::Error) -> Custom<String> {
+    match e {
+        oidc::Error::Http(_)
+        | oidc::Error::Request(_)
+        | oidc::Error::Json(_)
+        | oidc::Error::Provider(_) => Custom(
+            Status::BadGateway,
+            format!("Identity provider error: {}", e),
+        ),
+        _ => Custom(Status::Unauthorized, "Invalid identity token".to_string()),
+    }
+}
+
+#[get("/oidc/login")]
+pub async fn oidc_login(settings: &State<oidc::OidcSettings>) -> Result<Redirect, Custom<String>> {
+    let config = sso_config(settings)?;
+    let metadata = oidc::discover(config).await.map_err(sso_error)?;
+
+    let (state, nonce) = DB::default().unwrap().begin_oidc_login().unwrap();
+    Ok(Redirect::to(oidc::authorization_url(
+        config, &metadata, &state, &nonce,
+    )))
+}
+
+#[get("/oidc/callback?<code>&<state>")]
+pub async fn oidc_callback(
+    code: &str,
+    state: &str,
+    client: db::ClientInfo,
+    settings: &State<oidc::OidcSettings>,
+) -> Result<String, Custom<String>> {
+    let config = sso_config(settings)?;
+    let nonce = DB::default()
+        .unwrap()
+        .take_oidc_state(state)
+        .unwrap()
+        .ok_or_else(|| Custom(Status::Unauthorized, "Invalid or expired state".to_string()))?;
+
+    let metadata = oidc::discover(config).await.map_err(sso_error)?;
+    let id_token = oidc::exchange_code(config, &metadata, code)
+        .await
+        .map_err(sso_error)?;
+    let claims = oidc::verify_id_token(config, &metadata, &id_token, &nonce)
+        .await
+        .map_err(sso_error)?;
+
+    DB::default()
+        .unwrap()
+        .login_sso(&claims.iss, &claims.sub, claims.verified_email(), &client)
+        .map_err(login_error)
+}
+
+#[post("/totp/enroll")]
+pub fn totp_enroll(
+    auth: TotpEnrollmentGuard,
+) -> Result<Json<db::TotpEnrollment>, BadRequest<String>> {
+    let db = DB::default().unwrap();
+    match db.begin_totp_enrollment(auth.account_id) {
+        Ok(enrollment) => Ok(Json::from(enrollment)),
+        Err(db::Error::TotpAlreadyEnabled) => {
+            Err(BadRequest(Some("2FA already enabled".to_string())))
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCodeInput {
+    code: String,
+}
+
+#[post("/totp/confirm", format = "json", data = "<input>")]
+pub fn totp_confirm(
+    input: Json<TotpCodeInput>,
+    auth: TotpEnrollmentGuard,
+) -> Result<Json<Vec<String>>, BadRequest<String>> {
+    let db = DB::default().unwrap();
+    match db.confirm_totp_enrollment(auth.account_id, &input.code) {
+        Ok(recovery_codes) => Ok(Json::from(recovery_codes)),
+        Err(db::Error::InvalidTotpCode) => Err(BadRequest(Some("Invalid code".to_string()))),
+        Err(db::Error::TotpAlreadyEnabled) => {
+            Err(BadRequest(Some("2FA already enabled".to_string())))
+        }
+        Err(db::Error::TotpNotEnrolled) => Err(BadRequest(Some("2FA not enrolled".to_string()))),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/totp/recovery_codes", format = "json", data = "<input>")]
+pub fn totp_recovery_codes(
+    input: Json<TotpCodeInput>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<String>>, BadRequest<String>> {
+    let db = DB::default().unwrap();
+    match db.verify_totp(auth.claims.sub, &input.code, db::unix_now()) {
+        Ok(true) => Ok(Json::from(
+            db.reset_recovery_codes(auth.claims.sub).unwrap(),
+        )),
+        Ok(false) => Err(BadRequest(Some("Invalid code".to_string()))),
+        Err(db::Error::TotpNotEnrolled) => Err(BadRequest(Some("2FA not enrolled".to_string()))),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpDisableInput {
+    password: Option<String>,
+    /// A TOTP or recovery code, used when no password is given.
+    code: Option<String>,
+}
+
+#[post("/totp/disable", format = "json", data = "<input>")]
+pub fn totp_disable(
+    input: Json<TotpDisableInput>,
+    auth: AccountGuard,
+) -> Result<(), BadRequest<String>> {
+    let db = DB::default().unwrap();
+    match db.disable_totp(
+        auth.claims.sub,
+        input.password.as_deref(),
+        input.code.as_deref(),
+    ) {
+        Ok(()) => Ok(()),
+        Err(db::Error::InvalidPassword) => Err(BadRequest(Some("Invalid password".to_string()))),
+        Err(db::Error::InvalidTotpCode) => Err(BadRequest(Some("Invalid code".to_string()))),
+        Err(db::Error::TotpNotEnrolled) => Err(BadRequest(Some("2FA not enrolled".to_string()))),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/account")]
+pub fn account_profile(auth: AccountGuard) -> Json<db::AccountProfile> {
+    let db = DB::default().unwrap();
+    Json::from(db.account_profile(auth.claims.sub).unwrap())
+}
+
+#[post("/account", format = "json", data = "<input>")]
+pub fn update_account_profile(
+    input: Json<db::ProfileUpdate>,
+    auth: AccountGuard,
+) -> Result<(), Custom<String>> {
+    // API keys act for their creator but shouldn't edit the creator's profile.
+    if auth.claims.sid.is_none() {
+        return Err(Custom(Status::Forbidden, "Not Authorized".to_string()));
+    }
+
+    let db = DB::default().unwrap();
+    match db.update_profile(auth.claims.sub, &input, auth.claims.sid) {
+        Ok(()) => Ok(()),
+        Err(db::Error::InvalidPassword) => Err(Custom(
+            Status::Forbidden,
+            "Current password is incorrect".to_string(),
+        )),
+        Err(db::Error::EmailTaken) => Err(Custom(
+            Status::Conflict,
+            "Email is already in use".to_string(),
+        )),
+        Err(db::Error::InvalidProfile(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/accounts/<account_id>/deactivate")]
+pub fn deactivate_account(
+    account_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<i32>>, Custom<String>> {
+    auth.require(rbac::ACCOUNTS_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+    if account_id == auth.claims.sub {
+        return Err(Custom(
+            Status::BadRequest,
+            "Can't deactivate your own account".to_string(),
+        ));
+    }
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(
+        db.deactivate_account(account_id, auth.claims.sub).unwrap(),
+    ))
+}
+
+#[post("/accounts/<account_id>/reactivate")]
+pub fn reactivate_account(account_id: i32, auth: AccountGuard) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::ACCOUNTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.reactivate_account(account_id).unwrap();
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    session: db::Session,
+    current: bool,
+}
+
+#[get("/sessions")]
+pub fn sessions(auth: AccountGuard) -> Json<Vec<SessionView>> {
+    let db = DB::default().unwrap();
+    Json::from(
+        db.sessions(auth.claims.sub)
+            .unwrap()
+            .into_iter()
+            .map(|session| SessionView {
+                current: auth.claims.sid == Some(session.id),
+                session,
+            })
+            .collect::<Vec<SessionView>>(),
+    )
+}
+
+#[delete("/sessions/<session_id>")]
+pub fn revoke_session(session_id: i32, auth: AccountGuard) -> Option<()> {
+    let db = DB::default().unwrap();
+    match db.revoke_session(auth.claims.sub, session_id) {
+        Ok(()) => Some(()),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => None,
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[delete("/sessions")]
+pub fn revoke_sessions(auth: AccountGuard) -> Status {
+    let db = DB::default().unwrap();
+    db.revoke_sessions(auth.claims.sub).unwrap();
+    Status::Ok
+}
+
+#[post("/accounts/<account_id>/logout")]
+pub fn force_logout(account_id: i32, auth: AccountGuard) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::SESSIONS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.revoke_sessions(account_id).unwrap();
+    db.revoke_api_keys(account_id).unwrap();
+    Ok(())
+}
+
+#[get("/lockouts")]
+pub fn lockouts(auth: AccountGuard) -> Result<Json<Vec<db::LoginThrottle>>, Forbidden<String>> {
+    auth.require(rbac::LOCKOUTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.login_throttles(db::unix_now()).unwrap()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClearLockoutInput {
+    scope: db::ThrottleScope,
+    value: String,
+}
+
+#[post("/lockouts/clear", format = "json", data = "<input>")]
+pub fn clear_lockout(
+    input: Json<ClearLockoutInput>,
+    auth: AccountGuard,
+) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::LOCKOUTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.clear_login_throttle(input.scope, &input.value).unwrap();
+    Ok(())
+}
+
+#[get("/roles")]
+pub fn roles(auth: AccountGuard) -> Result<Json<Vec<db::Role>>, Forbidden<String>> {
+    auth.require(rbac::ROLES_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.roles().unwrap()))
+}
+
+#[post("/roles", format = "json", data = "<input>")]
+pub fn save_role(input: Json<db::Role>, auth: AccountGuard) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::ROLES_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.save_role(&input).unwrap();
+    Ok(())
+}
+
+#[post("/accounts/<account_id>/roles/<role>")]
+pub fn assign_role(
+    account_id: i32,
+    role: &str,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    auth.require(rbac::ROLES_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    match db.assign_role(account_id, role) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[delete("/accounts/<account_id>/roles/<role>")]
+pub fn revoke_role(
+    account_id: i32,
+    role: &str,
+    auth: AccountGuard,
+) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::ROLES_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.revoke_role(account_id, role).unwrap();
+    Ok(())
+}
+
+#[get("/api_keys")]
+pub fn api_keys(auth: AccountGuard) -> Result<Json<Vec<db::ApiKey>>, Forbidden<String>> {
+    auth.require(rbac::API_KEYS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.api_keys().unwrap()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    id: i32,
+    key: String,
+}
+
+#[post("/api_keys", format = "json", data = "<data>")]
+pub fn create_api_key(
+    data: Json<db::ApiKeyData>,
+    auth: AccountGuard,
+) -> Result<Json<CreatedApiKey>, Forbidden<String>> {
+    auth.require(rbac::API_KEYS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    match db.create_api_key(auth.claims.sub, &data) {
+        Ok((id, key)) => Ok(Json::from(CreatedApiKey { id, key })),
+        Err(db::Error::PermissionsNotHeld(permissions)) => Err(Forbidden(Some(format!(
+            "permissions not held: {}",
+            permissions.join(", ")
+        )))),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[delete("/api_keys/<api_key_id>")]
+pub fn revoke_api_key(api_key_id: i32, auth: AccountGuard) -> Result<(), Forbidden<String>> {
+    auth.require(rbac::API_KEYS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    db.revoke_api_key(api_key_id).unwrap();
+    Ok(())
+}
+
+#[get("/dependents")]
+pub fn dependents(auth: AccountGuard) -> Result<Json<Vec<db::Dependent>>, Forbidden<String>> {
+    auth.require(rbac::DEPENDENTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.dependents(auth.claims.sub).unwrap()))
+}
+
+#[post("/dependents", format = "json", data = "<data>")]
+pub fn create_dependent(
+    data: Json<db::DependentData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Forbidden<String>> {
+    auth.require(rbac::DEPENDENTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(
+        db.create_dependent(auth.claims.sub, data.0).unwrap(),
+    ))
+}
+
+#[delete("/dependents/<dependent_id>")]
+pub fn remove_dependent(
+    dependent_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    auth.require(rbac::DEPENDENTS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    match db.remove_dependent(auth.claims.sub, dependent_id) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum DoctorSortParam {
+    #[field(value = "name")]
+    Name,
+    #[field(value = "fee")]
+    FeeAsc,
+    #[field(value = "-fee")]
+    FeeDesc,
+    #[field(value = "rating")]
+    Rating,
+}
+
+#[derive(Debug, FromForm)]
+pub struct DoctorSearchParams {
+    q: Option<String>,
+    specialty: Option<String>,
+    clinic: Option<String>,
+    language: Option<String>,
+    /// `YYYY-MM-DD`
+    available_on: Option<String>,
+    sort: Option<DoctorSortParam>,
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+#[get("/doctors?<params..>")]
+pub fn doctors(params: DoctorSearchParams) -> Result<Json<db::DoctorPage>, BadRequest<String>> {
+    let available_on = match &params.available_on {
+        Some(date) => Some(
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| BadRequest(Some("available_on must be YYYY-MM-DD".to_string())))?,
+        ),
+        None => None,
+    };
+    let sort = match params.sort.unwrap_or(DoctorSortParam::Name) {
+        DoctorSortParam::Name => db::DoctorSort::Name,
+        DoctorSortParam::FeeAsc => db::DoctorSort::FeeAsc,
+        DoctorSortParam::FeeDesc => db::DoctorSort::FeeDesc,
+        DoctorSortParam::Rating => db::DoctorSort::Rating,
+    };
+
+    let db = DB::default().unwrap();
+    let query = db::DoctorQuery {
+        name: params.q,
+        specialty: params.specialty,
+        clinic: params.clinic,
+        language: params.language,
+        available_on,
+        sort,
+        limit: params.limit.unwrap_or(20),
+        cursor: params.cursor,
+    };
+    match db.search_doctors(&query) {
+        Ok(page) => Ok(Json::from(page)),
+        Err(db::Error::InvalidCursor) => Err(BadRequest(Some("Invalid cursor".to_string()))),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/doctors/search?<q>&<limit>")]
+pub fn search_doctors(q: &str, limit: Option<u32>) -> Json<Vec<db::DoctorMatch>> {
+    let db = DB::default().unwrap();
+    Json::from(db.full_text_search_doctors(q, limit.unwrap_or(20)).unwrap())
+}
+
+#[get("/doctors/<doctor_id>")]
+pub fn doctor_info(doctor_id: i32) -> Option<Json<db::DoctorInfo>> {
+    let db = DB::default().unwrap();
+    match db.get_doctor_info(doctor_id) {
+        Ok(info) => Some(Json::from(info)),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => None,
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/doctors/<doctor_id>/profile", format = "json", data = "<input>")]
+pub fn update_doctor_profile(
+    doctor_id: i32,
+    input: Json<db::DoctorProfileUpdate>,
+    auth: AccountGuard,
+) -> Result<Json<db::DoctorInfo>, Custom<String>> {
+    auth.authorize(rbac::DOCTOR_PROFILE_EDIT, &[doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.update_doctor_profile(doctor_id, &input) {
+        Ok(()) => Ok(Json::from(db.get_doctor_info(doctor_id).unwrap())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+            Err(Custom(Status::NotFound, "Doctor not found".to_string()))
+        }
+        Err(db::Error::InvalidProfile(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookedTimeslotsView {
+    patient_id: Option<i32>,
+    start_date: DateTime<FixedOffset>,
+    duration: i32,
+}
+
+#[get("/doctors/<doctor_id>/reviews")]
+pub fn doctor_reviews(doctor_id: i32) -> Json<Vec<db::Review>> {
+    let db = DB::default().unwrap();
+    Json::from(db.doctor_reviews(doctor_id).unwrap())
+}
+
+#[post(
+    "/appointments/<appointment_id>/review",
+    format = "json",
+    data = "<input>"
+)]
+pub fn review_appointment(
+    appointment_id: i32,
+    input: Json<db::ReviewData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    // Only the patient's side reviews, not the doctor.
+    auth.authorize(
+        rbac::REVIEW_WRITE,
+        &db.patient_owners(appointment.patient_id).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.create_review(appointment_id, auth.claims.sub, &input) {
+        Ok(id) => Ok(Json::from(id)),
+        Err(db::Error::InvalidReview(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(db::Error::ReviewExists) => Err(Custom(
+            Status::Conflict,
+            "Appointment already reviewed".to_string(),
+        )),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum ReviewStatusParam {
+    #[field(value = "visible")]
+    Visible,
+    #[field(value = "flagged")]
+    Flagged,
+    #[field(value = "hidden")]
+    Hidden,
+}
+
+#[get("/reviews?<status>")]
+pub fn reviews(
+    status: Option<ReviewStatusParam>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Review>>, Forbidden<String>> {
+    auth.require(rbac::REVIEWS_MODERATE)?;
+
+    let status = match status.unwrap_or(ReviewStatusParam::Flagged) {
+        ReviewStatusParam::Visible => db::ReviewStatus::Visible,
+        ReviewStatusParam::Flagged => db::ReviewStatus::Flagged,
+        ReviewStatusParam::Hidden => db::ReviewStatus::Hidden,
+    };
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.reviews_with_status(status).unwrap()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerateReviewInput {
+    status: db::ReviewStatus,
+}
+
+#[post("/reviews/<review_id>/moderate", format = "json", data = "<input>")]
+pub fn moderate_review(
+    review_id: i32,
+    input: Json<ModerateReviewInput>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    auth.require(rbac::REVIEWS_MODERATE)?;
+
+    let db = DB::default().unwrap();
+    match db.moderate_review(review_id, input.status, auth.claims.sub) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/appointment_types")]
+pub fn appointment_types() -> Json<Vec<db::AppointmentType>> {
+    let db = DB::default().unwrap();
+    Json::from(db.appointment_types().unwrap())
+}
+
+#[post("/appointment_types", format = "json", data = "<input>")]
+pub fn create_appointment_type(
+    input: Json<db::AppointmentTypeData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    auth.require(rbac::APPOINTMENT_TYPES_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.save_appointment_type(None, &input) {
+        Ok(id) => Ok(Json::from(id)),
+        Err(db::Error::DBError(rusqlite::Error::SqliteFailure(_, _))) => Err(Custom(
+            Status::Conflict,
+            "Appointment type already exists".to_string(),
+        )),
+        Err(db::Error::InvalidAppointmentType(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/appointment_types/<type_id>", format = "json", data = "<input>")]
+pub fn update_appointment_type(
+    type_id: i32,
+    input: Json<db::AppointmentTypeData>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::APPOINTMENT_TYPES_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    if db.get_appointment_type(type_id).is_err() {
+        return Ok(None);
+    }
+    match db.save_appointment_type(Some(type_id), &input) {
+        Ok(_) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::SqliteFailure(_, _))) => Err(Custom(
+            Status::Conflict,
+            "Appointment type already exists".to_string(),
+        )),
+        Err(db::Error::InvalidAppointmentType(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/questionnaires?<appointment_type_id>")]
+pub fn questionnaires(appointment_type_id: Option<i32>) -> Json<Vec<db::Questionnaire>> {
+    let db = DB::default().unwrap();
+    Json::from(db.questionnaires(appointment_type_id).unwrap())
+}
+
+fn questionnaire_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidQuestionnaire(reason) => Custom(Status::BadRequest, reason),
+        db::Error::UnknownAppointmentType => {
+            Custom(Status::BadRequest, "unknown appointment type".to_string())
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[post("/questionnaires", format = "json", data = "<input>")]
+pub fn create_questionnaire(
+    input: Json<db::QuestionnaireData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    auth.authorize(rbac::QUESTIONNAIRES_MANAGE, &[auth.claims.sub])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    db.create_questionnaire(auth.claims.sub, &input)
+        .map(Json::from)
+        .map_err(questionnaire_error)
+}
+
+#[post(
+    "/questionnaires/<questionnaire_id>",
+    format = "json",
+    data = "<input>"
+)]
+pub fn update_questionnaire(
+    questionnaire_id: i32,
+    input: Json<db::QuestionnaireData>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let questionnaire = match db.get_questionnaire(questionnaire_id) {
+        Ok(questionnaire) => questionnaire,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+    auth.authorize(rbac::QUESTIONNAIRES_MANAGE, &[questionnaire.created_by])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.update_questionnaire(questionnaire_id, &input) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(questionnaire_error(e)),
+    }
+}
+
+#[get("/appointments/<appointment_id>/questionnaires")]
+pub fn appointment_questionnaires(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::AppointmentQuestionnaire>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+    auth.authorize(rbac::APPOINTMENT_READ, &db.appointment_owners(&ap).unwrap())?;
+    if !clinical_access(&db, &ap, &auth) {
+        return Err(Forbidden(Some("Not Authorized".to_string())));
+    }
+
+    Ok(Json::from(
+        db.appointment_questionnaires(appointment_id).unwrap(),
+    ))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachQuestionnaireInput {
+    questionnaire_id: i32,
+    #[serde(default)]
+    required: bool,
+}
+
+#[post(
+    "/appointments/<appointment_id>/questionnaires",
+    format = "json",
+    data = "<input>"
+)]
+pub fn attach_questionnaire(
+    appointment_id: i32,
+    input: Json<AttachQuestionnaireInput>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    auth.authorize(rbac::QUESTIONNAIRES_MANAGE, &[auth.claims.sub])?;
+
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+    auth.authorize(rbac::APPOINTMENT_READ, &db.appointment_owners(&ap).unwrap())?;
+
+    match db.attach_questionnaire(appointment_id, input.questionnaire_id, input.required) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Answers are keyed by question key. Unanswered optional questions can be
+/// left out or set to null.
+#[post(
+    "/appointments/<appointment_id>/questionnaires/<questionnaire_id>/answers",
+    format = "json",
+    data = "<input>"
+)]
+pub fn submit_answers(
+    appointment_id: i32,
+    questionnaire_id: i32,
+    input: Json<BTreeMap<String, serde_json::Value>>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+    auth.authorize(
+        rbac::QUESTIONNAIRE_ANSWER,
+        &db.patient_owners(ap.patient_id).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.submit_answers(appointment_id, questionnaire_id, &input) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(db::Error::InvalidAnswers(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/referrals", format = "json", data = "<input>")]
+pub fn create_referral(
+    input: Json<db::ReferralData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    auth.require(rbac::REFERRAL_CREATE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.create_referral(auth.claims.sub, &input) {
+        Ok(id) => Ok(Json::from(id)),
+        Err(db::Error::InvalidReferral(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/referrals/<referral_id>")]
+pub fn referral(
+    referral_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Referral>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let referral = match db.get_referral(referral_id) {
+        Ok(referral) => referral,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(rbac::REFERRAL_READ, &db.referral_owners(&referral).unwrap())?;
+    Ok(Some(Json::from(referral)))
+}
+
+/// Referrals the calling doctor made or received.
+#[get("/referrals")]
+pub fn doctor_referrals(auth: AccountGuard) -> Result<Json<Vec<db::Referral>>, Forbidden<String>> {
+    auth.require(rbac::REFERRAL_CREATE)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.doctor_referrals(auth.claims.sub).unwrap()))
+}
+
+#[get("/patients/<patient_id>/referrals")]
+pub fn patient_referrals(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Referral>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(rbac::REFERRAL_READ, &db.patient_owners(patient_id).unwrap())?;
+
+    Ok(Json::from(db.patient_referrals(patient_id).unwrap()))
+}
+
+#[get("/doctors/<doctor_id>/slots", format = "json", data = "<input>")]
+pub fn doctor_booked_slots(
+    doctor_id: i32,
+    input: Json<DateInput>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<BookedTimeslotsView>>, Forbidden<String>> {
+    auth.require(rbac::DOCTOR_SLOTS_READ)?;
+
+    let db = DB::default().unwrap();
+    let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
+    let appointments = db.get_doctor_appointments(doctor_id, date).unwrap();
+
+    let show_patients = auth.can(rbac::DOCTOR_SLOTS_PATIENTS);
+
+    Ok(Json::from(
+        appointments
+            .into_iter()
+            .map(|a| BookedTimeslotsView {
+                patient_id: match show_patients {
+                    true => Some(a.patient_id),
+                    false => None,
+                },
+                start_date: a.start_date,
+                duration: a.duration,
+            })
+            .collect::<Vec<BookedTimeslotsView>>(),
+    ))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookInput {
+    start_date: DateTime<FixedOffset>,
+    duration: i32,
+    /// Set when booking for someone else, e.g. a dependent or by front-desk staff.
+    patient_id: Option<i32>,
+    appointment_type_id: Option<i32>,
+    referral_id: Option<i32>,
+    /// Card token from the payment provider, for types that take a deposit.
+    payment_token: Option<String>,
+}
+
+#[post("/doctors/<doctor_id>/book", format = "json", data = "<input>")]
+pub fn book_doctor(
+    doctor_id: i32,
+    input: Json<BookInput>,
+    auth: AccountGuard,
+    payments: &State<Box<dyn PaymentProvider>>,
+) -> Result<Json<i32>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let patient_id = input.patient_id.unwrap_or(auth.claims.sub);
+    auth.authorize(
+        rbac::APPOINTMENT_BOOK,
+        &db.patient_owners(patient_id).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let valid_request = db
+        .is_valid_appointment_request(doctor_id, &input.start_date, input.duration)
+        .unwrap();
+
+    if !valid_request {
+        return Err(Custom(Status::BadRequest, "doctor unavailable".to_string()));
+    }
+
+    // Unknown types are reported by `book_appointment`.
+    let deposit = input
+        .appointment_type_id
+        .and_then(|type_id| db.get_appointment_type(type_id).ok())
+        .and_then(|appointment_type| appointment_type.deposit);
+    // The deposit is held before booking, so a declined card doesn't take
+    // the slot. Holds that are never captured lapse with the provider.
+    let payment = match deposit {
+        Some(amount) => {
+            let token = input.payment_token.as_deref().ok_or_else(|| {
+                Custom(
+                    Status::PaymentRequired,
+                    format!(
+                        "appointment type requires a deposit of {}; set payment_token",
+                        amount
+                    ),
+                )
+            })?;
+            let reference = payments.authorize(amount, token).map_err(payment_error)?;
+            Some((reference, amount))
+        }
+        None => None,
+    };
+    // Anything failing from here on releases the hold, unexpected errors
+    // included.
+    let release = || {
+        if let Some((reference, _)) = &payment {
+            // A hold that can't be released lapses with the provider.
+            payments.void(reference).ok();
+        }
+    };
+
+    let request = db::AppointmentRequest {
+        doctor_id: doctor_id,
+        patient_id,
+        start_date: input.start_date,
+        duration: input.duration,
+        appointment_type_id: input.appointment_type_id,
+        referral_id: input.referral_id,
+    };
+    // The booking and its payment are committed together, once the deposit
+    // is captured.
+    let tx = db.transaction().unwrap();
+    let appointment_id = db.book_appointment(request, auth.claims.sub).map_err(|e| {
+        release();
+        booking_error(e)
+    })?;
+    if let Some((reference, amount)) = &payment {
+        db.record_payment(appointment_id, reference, *amount)
+            .unwrap_or_else(|e| {
+                release();
+                panic!("{:?}", e)
+            });
+        payments.capture(reference, *amount).map_err(|e| {
+            release();
+            payment_error(e)
+        })?;
+    }
+    tx.commit().unwrap();
+
+    Ok(Json::from(appointment_id))
+}
+
+fn booking_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidReferral(reason) => Custom(Status::BadRequest, reason),
+        db::Error::ReferralRequired => Custom(
+            Status::BadRequest,
+            "appointment type requires a referral".to_string(),
+        ),
+        db::Error::UnknownAppointmentType => {
+            Custom(Status::BadRequest, "unknown appointment type".to_string())
+        }
+        db::Error::UnknownPatient => Custom(Status::NotFound, "unknown patient".to_string()),
+        db::Error::ConsentRequired(codes) => Custom(
+            Status::BadRequest,
+            format!("patient must accept consents: {}", codes.join(", ")),
+        ),
+        e => panic!("{:?}", e),
+    }
+}
+
+fn payment_error(e: payments::Error) -> Custom<String> {
+    match e {
+        payments::Error::Declined(reason) => Custom(Status::PaymentRequired, reason),
+        payments::Error::Provider(reason) => Custom(
+            Status::BadGateway,
+            format!("payment provider error: {}", reason),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RescheduleInput {
+    start_date: DateTime<FixedOffset>,
+    duration: i32,
+}
+
+#[post(
+    "/appointments/<appointment_id>/reschedule",
+    format = "json",
+    data = "<input>"
+)]
+pub fn reschedule_appointment(
+    appointment_id: i32,
+    input: Json<RescheduleInput>,
+    auth: AccountGuard,
+) -> Result<(), Custom<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(
+        rbac::APPOINTMENT_RESCHEDULE,
+        &db.appointment_owners(&appointment).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let valid_request = db
+        .is_valid_appointment_request(appointment.doctor_id, &input.start_date, input.duration)
+        .unwrap();
+    if !valid_request {
+        return Err(Custom(Status::BadRequest, "doctor unavailable".to_string()));
+    }
+
+    match db.reschedule_appointment(
+        appointment_id,
+        &input.start_date,
+        input.duration,
+        auth.claims.sub,
+    ) {
+        Ok(()) => Ok(()),
+        Err(db::Error::AppointmentStatus(status)) => Err(Custom(
+            Status::Conflict,
+            format!("can't reschedule a {:?} appointment", status),
+        )),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post("/appointments/<appointment_id>/check_in")]
+pub fn check_in_appointment(appointment_id: i32, auth: AccountGuard) -> Result<(), Custom<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(
+        rbac::APPOINTMENT_CHECK_IN,
+        &db.appointment_owners(&appointment).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.set_appointment_status(
+        appointment_id,
+        db::AppointmentStatus::CheckedIn,
+        auth.claims.sub,
+    ) {
+        Ok(()) => Ok(()),
+        Err(db::Error::QuestionnairesPending) => Err(Custom(
+            Status::Conflict,
+            "required questionnaires haven't been answered".to_string(),
+        )),
+        Err(db::Error::AppointmentStatus(status)) => Err(Custom(
+            Status::Conflict,
+            format!("can't check in a {:?} appointment", status),
+        )),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Marks a checked-in appointment done, which drafts its invoice.
+#[post("/appointments/<appointment_id>/complete")]
+pub fn complete_appointment(appointment_id: i32, auth: AccountGuard) -> Result<(), Custom<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(rbac::APPOINTMENT_COMPLETE, &[appointment.doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.set_appointment_status(appointment_id, db::AppointmentStatus::Done, auth.claims.sub) {
+        Ok(()) => Ok(()),
+        Err(db::Error::AppointmentStatus(status)) => Err(Custom(
+            Status::Conflict,
+            format!("can't complete a {:?} appointment", status),
+        )),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/appointments/<appointment_id>/actions")]
+pub fn appointment_actions(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::AppointmentActionRecord>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(
+        rbac::APPOINTMENT_READ,
+        &db.appointment_owners(&appointment).unwrap(),
+    )?;
+
+    Ok(Json::from(
+        db.get_appointment_actions(appointment_id).unwrap(),
+    ))
+}
+
+#[post("/appointments/<appointment_id>/cancel")]
+pub fn cancel_appointment(
+    appointment_id: i32,
+    auth: AccountGuard,
+    payments: &State<Box<dyn PaymentProvider>>,
+    settings: &State<PaymentSettings>,
+) -> Result<(), Custom<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(
+        rbac::APPOINTMENT_CANCEL,
+        &db.appointment_owners(&appointment).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    // The cancellation only sticks once the deposit is refunded.
+    let tx = db.transaction().unwrap();
+    match db.set_appointment_status(
+        appointment_id,
+        db::AppointmentStatus::Cancelled,
+        auth.claims.sub,
+    ) {
+        Ok(()) => {}
+        Err(db::Error::AppointmentStatus(status)) => {
+            return Err(Custom(
+                Status::Conflict,
+                format!("can't cancel a {:?} appointment", status),
+            ))
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+
+    // Appointments flagged for rebooking were called off by the clinic.
+    let by_clinic = appointment.status == db::AppointmentStatus::NeedsRebooking
+        || !db
+            .patient_owners(appointment.patient_id)
+            .unwrap()
+            .contains(&auth.claims.sub);
+    if matches!(
+        appointment.status,
+        db::AppointmentStatus::Booked | db::AppointmentStatus::NeedsRebooking
+    ) {
+        let notice_secs = appointment.start_date.timestamp() - db::unix_now();
+        refund_deposit(
+            &db,
+            appointment_id,
+            notice_secs,
+            by_clinic,
+            payments.as_ref(),
+            settings,
+        )?;
+    }
+    tx.commit().unwrap();
+
+    Ok(())
+}
+
+/// Refunds the appointment's deposit as far as the cancellation policy allows.
+fn refund_deposit(
+    db: &DB,
+    appointment_id: i32,
+    notice_secs: i64,
+    by_clinic: bool,
+    payments: &dyn PaymentProvider,
+    settings: &PaymentSettings,
+) -> Result<(), Custom<String>> {
+    let payment = match db.appointment_payment(appointment_id) {
+        Ok(payment) if payment.refunded == 0 => payment,
+        Ok(_) | Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(()),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    let amount = settings.refund_amount(payment.amount, notice_secs, by_clinic);
+    if amount > 0 {
+        db.record_refund(payment.id, amount).unwrap();
+        payments
+            .refund(&payment.reference, amount)
+            .map_err(payment_error)?;
+    }
+    Ok(())
+}
+
+/// The deposit paid when booking, if the appointment type takes one.
+#[get("/appointments/<appointment_id>/payment")]
+pub fn appointment_payment(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Payment>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let appointment = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(
+        rbac::APPOINTMENT_READ,
+        &db.appointment_owners(&appointment).unwrap(),
+    )?;
+
+    match db.appointment_payment(appointment_id) {
+        Ok(payment) => Ok(Some(Json::from(payment))),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/doctors/available", format = "json", data = "<input>")]
+pub fn available_doctors(
+    input: Json<DateInput>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<i32>>, Forbidden<String>> {
+    auth.require(rbac::DOCTOR_AVAILABILITY_READ)?;
+
+    let db = DB::default().unwrap();
+    let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
+    Ok(Json::from(
+        db.doctors_stats(date.date())
+            .unwrap()
+            .into_iter()
+            .filter(|stats| stats.booked_mins < 8 * 60 && stats.appointments_count < 12)
+            .map(|s| s.doctor_id)
+            .collect::<Vec<i32>>(),
+    ))
+}
+
+#[get("/appointments/<appointment_id>")]
+pub fn appointment_details(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<AppointmentDetails>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(rbac::APPOINTMENT_READ, &db.appointment_owners(&ap).unwrap())?;
+
+    let (visit_summary, questionnaires) = match clinical_access(&db, &ap, &auth) {
+        true => (
+            db.visit_summary(appointment_id).unwrap(),
+            db.appointment_questionnaires(appointment_id).unwrap(),
+        ),
+        false => (None, Vec::new()),
+    };
+
+    Ok(Json::from(AppointmentDetails {
+        appointment: ap,
+        visit_summary,
+        questionnaires,
+    }))
+}
+
+/// Clinical details of a visit are for the patient's side and whoever may
+/// read the notes, not for everyone who can see the schedule.
+fn clinical_access(db: &DB, ap: &Appointment, auth: &AccountGuard) -> bool {
+    db.patient_owners(ap.patient_id)
+        .unwrap()
+        .contains(&auth.claims.sub)
+        || auth
+            .authorize(rbac::VISIT_NOTE_READ, &[ap.doctor_id])
+            .is_ok()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentDetails {
+    #[serde(flatten)]
+    appointment: Appointment,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visit_summary: Option<db::VisitSummary>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    questionnaires: Vec<db::AppointmentQuestionnaire>,
+}
+
+#[get("/appointments/<appointment_id>/notes")]
+pub fn visit_notes(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<Vec<db::VisitNote>>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let ap = match db.get_appointment(appointment_id) {
+        Ok(ap) => ap,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(rbac::VISIT_NOTE_READ, &[ap.doctor_id])?;
+
+    Ok(Some(Json::from(db.visit_notes(appointment_id).unwrap())))
+}
+
+#[post(
+    "/appointments/<appointment_id>/notes",
+    format = "json",
+    data = "<input>"
+)]
+pub fn add_visit_note(
+    appointment_id: i32,
+    input: Json<db::VisitNoteData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(rbac::VISIT_NOTE_WRITE, &[ap.doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.add_visit_note(appointment_id, auth.claims.sub, &input) {
+        Ok(version) => Ok(Json::from(version)),
+        Err(db::Error::InvalidVisitNote(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[post(
+    "/appointments/<appointment_id>/prescriptions",
+    format = "json",
+    data = "<input>"
+)]
+pub fn create_prescription(
+    appointment_id: i32,
+    input: Json<db::PrescriptionData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(rbac::PRESCRIPTION_WRITE, &[ap.doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.create_prescription(appointment_id, &input) {
+        Ok(id) => Ok(Json::from(id)),
+        Err(db::Error::InvalidPrescription(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(db::Error::AllergyConflict(allergies)) => Err(Custom(
+            Status::Conflict,
+            format!(
+                "patient is allergic to {}; set allergy_override to prescribe anyway",
+                allergies.join(", ")
+            ),
+        )),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Loads the prescription if `auth` may read it.
+fn readable_prescription(
+    db: &DB,
+    prescription_id: i32,
+    auth: &AccountGuard,
+) -> Result<Option<db::Prescription>, Forbidden<String>> {
+    let prescription = match db.get_prescription(prescription_id) {
+        Ok(prescription) => prescription,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(
+        rbac::PRESCRIPTION_READ,
+        &db.prescription_owners(&prescription).unwrap(),
+    )?;
+    Ok(Some(prescription))
+}
+
+#[get("/prescriptions/<prescription_id>")]
+pub fn prescription(
+    prescription_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Prescription>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    Ok(readable_prescription(&db, prescription_id, &auth)?.map(Json::from))
+}
+
+#[get("/prescriptions/<prescription_id>/print")]
+pub fn print_prescription(
+    prescription_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Html<String>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let prescription = match readable_prescription(&db, prescription_id, &auth)? {
+        Some(prescription) => prescription,
+        None => return Ok(None),
+    };
+
+    let patient = db.get_patient_profile(prescription.patient_id).unwrap();
+    let doctor = db.get_doctor_info(prescription.doctor_id).unwrap();
+    Ok(Some(Html(print::prescription_html(
+        &prescription,
+        &patient,
+        &doctor,
+    ))))
+}
+
+#[post("/prescriptions/<prescription_id>/cancel")]
+pub fn cancel_prescription(
+    prescription_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let prescription = match db.get_prescription(prescription_id) {
+        Ok(prescription) => prescription,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(rbac::PRESCRIPTION_WRITE, &[prescription.doctor_id])?;
+
+    db.cancel_prescription(prescription_id).unwrap();
+    Ok(Some(()))
+}
+
+fn lab_order_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidLabOrder(reason) | db::Error::InvalidLabResult(reason) => {
+            Custom(Status::BadRequest, reason)
+        }
+        db::Error::LabOrderStatus(status) => {
+            Custom(Status::Conflict, format!("order is {:?}", status))
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[post(
+    "/appointments/<appointment_id>/lab_orders",
+    format = "json",
+    data = "<input>"
+)]
+pub fn create_lab_order(
+    appointment_id: i32,
+    input: Json<db::LabOrderData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let ap = db.get_appointment(appointment_id).unwrap();
+
+    auth.authorize(rbac::LAB_ORDER, &[ap.doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    db.create_lab_order(appointment_id, &input)
+        .map(Json::from)
+        .map_err(lab_order_error)
+}
+
+/// The patient's side only sees results once the doctor has released them.
+fn visible_lab_order(db: &DB, mut order: db::LabOrder, auth: &AccountGuard) -> db::LabOrder {
+    if order.status != db::LabOrderStatus::Reviewed
+        && db
+            .patient_owners(order.patient_id)
+            .unwrap()
+            .contains(&auth.claims.sub)
+    {
+        order.results.clear();
+    }
+    order
+}
+
+/// Loads the lab order if it exists, for checks by the caller.
+fn find_lab_order(db: &DB, order_id: i32) -> Option<db::LabOrder> {
+    match db.get_lab_order(order_id) {
+        Ok(order) => Some(order),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => None,
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[get("/lab_orders/<order_id>")]
+pub fn lab_order(
+    order_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::LabOrder>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let order = match find_lab_order(&db, order_id) {
+        Some(order) => order,
+        None => return Ok(None),
+    };
+
+    auth.authorize(rbac::LAB_READ, &db.lab_order_owners(&order).unwrap())?;
+    Ok(Some(Json::from(visible_lab_order(&db, order, &auth))))
+}
+
+#[post("/lab_orders/<order_id>/collected")]
+pub fn collect_lab_order(order_id: i32, auth: AccountGuard) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::LAB_RESULTS_RECORD)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.mark_lab_collected(order_id) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(lab_order_error(e)),
+    }
+}
+
+#[post("/lab_orders/<order_id>/results", format = "json", data = "<input>")]
+pub fn record_lab_results(
+    order_id: i32,
+    input: Json<Vec<db::LabResultData>>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::LAB_RESULTS_RECORD)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.record_lab_results(order_id, &input) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(lab_order_error(e)),
+    }
+}
+
+/// Releases the results to the patient, who gets a message about them.
+#[post("/lab_orders/<order_id>/review")]
+pub fn review_lab_order(order_id: i32, auth: AccountGuard) -> Result<Option<()>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let order = match find_lab_order(&db, order_id) {
+        Some(order) => order,
+        None => return Ok(None),
+    };
+
+    auth.authorize(rbac::LAB_ORDER, &[order.doctor_id])
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    db.review_lab_order(order_id).map_err(lab_order_error)?;
+    Ok(Some(()))
+}
+
+/// Records the results of every order in an HL7 v2 ORU^R01 message. Nothing
+/// is recorded unless all of them are accepted.
+#[post("/lab_results/import", data = "<message>")]
+pub fn import_lab_results(
+    message: String,
+    auth: AccountGuard,
+) -> Result<Json<Vec<i32>>, Custom<String>> {
+    auth.require(rbac::LAB_RESULTS_RECORD)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let orders = hl7::parse_oru(&message).map_err(|reason| Custom(Status::BadRequest, reason))?;
+    let db = DB::default().unwrap();
+    let tx = db.transaction().unwrap();
+    for order in orders.iter() {
+        match db.record_lab_results(order.order_id, &order.results) {
+            Ok(()) => {}
+            Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                return Err(Custom(
+                    Status::BadRequest,
+                    format!("unknown lab order {}", order.order_id),
+                ))
+            }
+            Err(e) => {
+                let Custom(status, reason) = lab_order_error(e);
+                return Err(Custom(
+                    status,
+                    format!("order {}: {}", order.order_id, reason),
+                ));
+            }
+        }
+    }
+    tx.commit().unwrap();
+
+    Ok(Json::from(
+        orders
+            .iter()
+            .map(|order| order.order_id)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum InvoiceStatusParam {
+    #[field(value = "draft")]
+    Draft,
+    #[field(value = "issued")]
+    Issued,
+    #[field(value = "paid")]
+    Paid,
+    #[field(value = "void")]
+    Void,
+}
+
+#[get("/invoices?<status>&<patient_id>")]
+pub fn invoices(
+    status: Option<InvoiceStatusParam>,
+    patient_id: Option<i32>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Invoice>>, Forbidden<String>> {
+    auth.require(rbac::INVOICES_MANAGE)?;
+
+    let status = status.map(|status| match status {
+        InvoiceStatusParam::Draft => db::InvoiceStatus::Draft,
+        InvoiceStatusParam::Issued => db::InvoiceStatus::Issued,
+        InvoiceStatusParam::Paid => db::InvoiceStatus::Paid,
+        InvoiceStatusParam::Void => db::InvoiceStatus::Void,
+    });
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.invoices(status, patient_id).unwrap()))
+}
+
+/// Returns the invoice if `auth` may read it. Drafts are only visible to
+/// those who can manage invoices.
+fn readable_invoice(
+    db: &DB,
+    invoice: Result<db::Invoice, db::Error>,
+    auth: &AccountGuard,
+) -> Result<Option<Json<db::Invoice>>, Forbidden<String>> {
+    let invoice = match invoice {
+        Ok(invoice) => invoice,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    if invoice.status == db::InvoiceStatus::Draft {
+        auth.require(rbac::INVOICES_MANAGE)?;
+    } else {
+        auth.authorize(
+            rbac::INVOICE_READ,
+            &db.patient_owners(invoice.patient_id).unwrap(),
+        )?;
+    }
+    Ok(Some(Json::from(invoice)))
+}
+
+#[get("/invoices/<invoice_id>")]
+pub fn invoice(
+    invoice_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Invoice>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    readable_invoice(&db, db.get_invoice(invoice_id), &auth)
+}
+
+#[get("/appointments/<appointment_id>/invoice")]
+pub fn appointment_invoice(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Invoice>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    readable_invoice(&db, db.appointment_invoice(appointment_id), &auth)
+}
+
+fn invoice_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidInvoice(reason) => Custom(Status::BadRequest, reason),
+        db::Error::InvoiceStatus(status) => {
+            Custom(Status::Conflict, format!("invoice is {:?}", status))
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+/// Replaces the lines of a draft invoice.
+#[post("/invoices/<invoice_id>/lines", format = "json", data = "<input>")]
+pub fn update_invoice_lines(
+    invoice_id: i32,
+    input: Json<Vec<db::InvoiceLineData>>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::INVOICES_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.set_invoice_lines(invoice_id, &input) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(invoice_error(e)),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvoiceStatusInput {
+    status: db::InvoiceStatus,
+    /// Required when voiding.
+    reason: Option<String>,
+}
+
+#[post("/invoices/<invoice_id>/status", format = "json", data = "<input>")]
+pub fn update_invoice_status(
+    invoice_id: i32,
+    input: Json<InvoiceStatusInput>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::INVOICES_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.set_invoice_status(invoice_id, input.status, input.reason.as_deref()) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(invoice_error(e)),
+    }
+}
+
+#[get("/insurance_plans")]
+pub fn insurance_plans() -> Json<Vec<db::InsurancePlan>> {
+    let db = DB::default().unwrap();
+    Json::from(db.insurance_plans().unwrap())
+}
+
+fn insurance_plan_error(e: db::Error) -> Custom<String> {
+    match e {
+        db::Error::InvalidInsurancePlan(reason) => Custom(Status::BadRequest, reason),
+        db::Error::UnknownAppointmentType => {
+            Custom(Status::BadRequest, "unknown appointment type".to_string())
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[post("/insurance_plans", format = "json", data = "<input>")]
+pub fn create_insurance_plan(
+    input: Json<db::InsurancePlanData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    auth.require(rbac::INSURANCE_PLANS_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    db.save_insurance_plan(None, &input)
+        .map(Json::from)
+        .map_err(insurance_plan_error)
+}
+
+#[post("/insurance_plans/<plan_id>", format = "json", data = "<input>")]
+pub fn update_insurance_plan(
+    plan_id: i32,
+    input: Json<db::InsurancePlanData>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::INSURANCE_PLANS_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.save_insurance_plan(Some(plan_id), &input) {
+        Ok(_) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => Err(insurance_plan_error(e)),
+    }
+}
+
+/// Checks the patient's policy against a visit with the doctor on `date`
+/// (`YYYY-MM-DD`, today by default).
+#[get("/patients/<patient_id>/coverage?<doctor_id>&<appointment_type_id>&<date>")]
+pub fn patient_coverage(
+    patient_id: i32,
+    doctor_id: i32,
+    appointment_type_id: Option<i32>,
+    date: Option<String>,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::Coverage>>, Custom<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(
+        rbac::PATIENT_PROFILE_INSURANCE,
+        &db.patient_owners(patient_id).unwrap(),
+    )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let date = match date {
+        Some(date) => chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map_err(|_| Custom(Status::BadRequest, "date must be YYYY-MM-DD".to_string()))?,
+        None => chrono::Utc::now().naive_utc().date(),
+    };
+    match db.check_coverage(patient_id, doctor_id, appointment_type_id, date) {
+        Ok(coverage) => Ok(Some(Json::from(coverage))),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum ClaimStatusParam {
+    #[field(value = "pending")]
+    Pending,
+    #[field(value = "submitted")]
+    Submitted,
+    #[field(value = "approved")]
+    Approved,
+    #[field(value = "denied")]
+    Denied,
+    #[field(value = "paid")]
+    Paid,
+    #[field(value = "cancelled")]
+    Cancelled,
+}
+
+#[get("/claims?<status>&<patient_id>")]
+pub fn claims(
+    status: Option<ClaimStatusParam>,
+    patient_id: Option<i32>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::InsuranceClaim>>, Forbidden<String>> {
+    auth.require(rbac::CLAIMS_MANAGE)?;
 
-use crate::db::{self, AccountType, Appointment, Claims, DB};
-use chrono::{DateTime, Duration, FixedOffset};
-use derive_more::From;
-use rocket::http::{Accept, Status};
-use rocket::request::{self, FromRequest, Outcome, Request};
-use rocket::response::status::{BadRequest, Forbidden};
-use rocket::serde::json::Json;
-use rocket::State;
-use serde::{Deserialize, Serialize};
+    let status = status.map(|status| match status {
+        ClaimStatusParam::Pending => db::ClaimStatus::Pending,
+        ClaimStatusParam::Submitted => db::ClaimStatus::Submitted,
+        ClaimStatusParam::Approved => db::ClaimStatus::Approved,
+        ClaimStatusParam::Denied => db::ClaimStatus::Denied,
+        ClaimStatusParam::Paid => db::ClaimStatus::Paid,
+        ClaimStatusParam::Cancelled => db::ClaimStatus::Cancelled,
+    });
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.claims(status, patient_id).unwrap()))
+}
 
-use std::result::Result;
+#[get("/claims/<claim_id>")]
+pub fn claim(
+    claim_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<db::InsuranceClaim>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let claim = match db.get_claim(claim_id) {
+        Ok(claim) => claim,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(
+        rbac::CLAIM_READ,
+        &db.patient_owners(claim.patient_id).unwrap(),
+    )?;
+    Ok(Some(Json::from(claim)))
+}
+
+/// Writes every pending claim to a new batch and marks them submitted. 404
+/// when nothing is pending.
+#[post("/claims/export")]
+pub fn export_claims(
+    auth: AccountGuard,
+) -> Result<Option<Json<db::ClaimBatch>>, Forbidden<String>> {
+    auth.require(rbac::CLAIMS_MANAGE)?;
 
-#[post("/register", format = "json", data = "<data>")]
-pub fn register(data: Json<db::RegisterData>) {
     let db = DB::default().unwrap();
-    db.register(data.0).unwrap();
+    Ok(db.export_claims().unwrap().map(Json::from))
 }
 
-#[post("/login", format = "json", data = "<data>")]
-pub fn login(data: Json<db::LoginData>) -> String {
+/// The batch file as sent to the clearinghouse.
+#[get("/claim_batches/<batch_id>")]
+pub fn claim_batch(
+    batch_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<(ContentType, String)>, Forbidden<String>> {
+    auth.require(rbac::CLAIMS_MANAGE)?;
+
+    let db = DB::default().unwrap();
+    match db.get_claim_batch(batch_id) {
+        Ok(batch) => Ok(Some((ContentType::Plain, batch.contents))),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimStatusInput {
+    status: db::ClaimStatus,
+    /// Required when paid.
+    paid_amount: Option<i64>,
+    /// Required when denied.
+    reason: Option<String>,
+}
+
+#[post("/claims/<claim_id>/status", format = "json", data = "<input>")]
+pub fn update_claim_status(
+    claim_id: i32,
+    input: Json<ClaimStatusInput>,
+    auth: AccountGuard,
+) -> Result<Option<()>, Custom<String>> {
+    auth.require(rbac::CLAIMS_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
     let db = DB::default().unwrap();
-    db.login(data.0).unwrap()
+    match db.set_claim_status(
+        claim_id,
+        input.status,
+        input.paid_amount,
+        input.reason.as_deref(),
+    ) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(db::Error::InvalidClaim(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(db::Error::ClaimStatus(status)) => {
+            Err(Custom(Status::Conflict, format!("claim is {:?}", status)))
+        }
+        Err(e) => panic!("{:?}", e),
+    }
 }
 
-#[get("/doctors")]
-pub fn doctors() -> Json<Vec<i32>> {
+#[get("/consent_documents")]
+pub fn consent_documents() -> Json<Vec<db::ConsentDocument>> {
     let db = DB::default().unwrap();
-    Json::from(db.doctors().unwrap())
+    Json::from(db.consent_documents().unwrap())
 }
 
-#[get("/doctors/<doctor_id>")]
-pub fn doctor_info(doctor_id: i32) -> Json<db::DoctorInfo> {
+#[get("/consent_documents/<document_id>")]
+pub fn consent_document(document_id: i32) -> Option<Json<db::ConsentDocument>> {
+    let db = DB::default().unwrap();
+    match db.get_consent_document(document_id) {
+        Ok(document) => Some(Json::from(document)),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => None,
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Publishes a consent document, or the next version of an existing code.
+#[post("/consent_documents", format = "json", data = "<input>")]
+pub fn publish_consent_document(
+    input: Json<db::ConsentDocumentData>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
+    auth.require(rbac::CONSENT_DOCUMENTS_MANAGE)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let db = DB::default().unwrap();
+    match db.publish_consent_document(&input, auth.claims.sub) {
+        Ok(document_id) => Ok(Json::from(document_id)),
+        Err(db::Error::InvalidConsent(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Reads an upload body, refusing ones over the configured size.
+async fn read_upload(
+    data: Data<'_>,
+    settings: &AttachmentSettings,
+) -> Result<Vec<u8>, Custom<String>> {
+    let body = data
+        .open(settings.max_attachment_bytes.bytes())
+        .into_bytes()
+        .await
+        .map_err(|e| Custom(Status::BadRequest, e.to_string()))?;
+    if !body.is_complete() {
+        return Err(Custom(
+            Status::PayloadTooLarge,
+            format!(
+                "attachments are limited to {} bytes",
+                settings.max_attachment_bytes
+            ),
+        ));
+    }
+    Ok(body.into_inner())
+}
+
+fn storage_error(e: std::io::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        format!("attachment storage failed: {}", e),
+    )
+}
+
+fn store_upload(
+    upload: &db::AttachmentUpload,
+    storage: &dyn Storage,
+) -> Result<Json<db::Attachment>, Custom<String>> {
+    let db = DB::default().unwrap();
+    match db.create_attachment(upload, storage) {
+        Ok(attachment) => Ok(Json::from(attachment)),
+        Err(db::Error::InvalidAttachment(reason)) => {
+            Err(Custom(Status::UnsupportedMediaType, reason))
+        }
+        Err(db::Error::ChecksumMismatch) => Err(Custom(
+            Status::BadRequest,
+            "sha256 doesn't match the uploaded contents".to_string(),
+        )),
+        Err(db::Error::Storage(e)) => Err(storage_error(e)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+fn media_type(content_type: &ContentType) -> String {
+    format!("{}/{}", content_type.top(), content_type.sub())
+}
+
+/// Uploads the request body as an attachment on the appointment.
+#[allow(clippy::too_many_arguments)]
+#[post(
+    "/appointments/<appointment_id>/attachments?<filename>&<sha256>",
+    data = "<data>"
+)]
+pub async fn upload_appointment_attachment(
+    appointment_id: i32,
+    filename: &str,
+    sha256: Option<&str>,
+    content_type: &ContentType,
+    data: Data<'_>,
+    auth: AccountGuard,
+    settings: &State<AttachmentSettings>,
+    storage: &State<Box<dyn Storage>>,
+) -> Result<Json<db::Attachment>, Custom<String>> {
+    let patient_id = {
+        let db = DB::default().unwrap();
+        let ap = db.get_appointment(appointment_id).unwrap();
+        auth.authorize(rbac::ATTACHMENT_WRITE, &db.appointment_owners(&ap).unwrap())
+            .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+        ap.patient_id
+    };
+
+    let body = read_upload(data, settings).await?;
+    store_upload(
+        &db::AttachmentUpload {
+            patient_id,
+            appointment_id: Some(appointment_id),
+            uploaded_by: auth.claims.sub,
+            filename,
+            content_type: &media_type(content_type),
+            data: &body,
+            sha256,
+        },
+        storage.as_ref(),
+    )
+}
+
+/// Uploads the request body as an attachment on the patient, outside of any visit.
+#[allow(clippy::too_many_arguments)]
+#[post(
+    "/patients/<patient_id>/attachments?<filename>&<sha256>",
+    data = "<data>"
+)]
+pub async fn upload_patient_attachment(
+    patient_id: i32,
+    filename: &str,
+    sha256: Option<&str>,
+    content_type: &ContentType,
+    data: Data<'_>,
+    auth: AccountGuard,
+    settings: &State<AttachmentSettings>,
+    storage: &State<Box<dyn Storage>>,
+) -> Result<Json<db::Attachment>, Custom<String>> {
+    {
+        let db = DB::default().unwrap();
+        auth.authorize(
+            rbac::ATTACHMENT_WRITE,
+            &db.patient_owners(patient_id).unwrap(),
+        )
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+    }
+
+    let body = read_upload(data, settings).await?;
+    store_upload(
+        &db::AttachmentUpload {
+            patient_id,
+            appointment_id: None,
+            uploaded_by: auth.claims.sub,
+            filename,
+            content_type: &media_type(content_type),
+            data: &body,
+            sha256,
+        },
+        storage.as_ref(),
+    )
+}
+
+#[get("/appointments/<appointment_id>/attachments")]
+pub fn appointment_attachments(
+    appointment_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Attachment>>, Forbidden<String>> {
     let db = DB::default().unwrap();
-    Json::from(db.get_doctor_info(doctor_id).unwrap())
+    let ap = db.get_appointment(appointment_id).unwrap();
+    auth.authorize(rbac::APPOINTMENT_READ, &db.appointment_owners(&ap).unwrap())?;
+    if !clinical_access(&db, &ap, &auth) {
+        return Err(Forbidden(Some("Not Authorized".to_string())));
+    }
+
+    Ok(Json::from(
+        db.appointment_attachments(appointment_id).unwrap(),
+    ))
+}
+
+#[get("/patients/<patient_id>/attachments")]
+pub fn patient_attachments(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Attachment>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    let owners = db.patient_owners(patient_id).unwrap();
+    auth.authorize(rbac::PATIENT_HISTORY_READ, &owners)?;
+
+    let mut attachments = db.patient_attachments(patient_id).unwrap();
+    if !shares_records(&db, patient_id, &owners, &auth) {
+        attachments.retain(|attachment| {
+            attachment.uploaded_by == auth.claims.sub
+                || attachment.appointment_id.is_some_and(|appointment_id| {
+                    db.get_appointment(appointment_id).unwrap().doctor_id == auth.claims.sub
+                })
+        });
+    }
+
+    Ok(Json::from(attachments))
+}
+
+#[derive(Responder)]
+pub struct AttachmentDownload {
+    body: Vec<u8>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+#[get("/attachments/<attachment_id>")]
+pub fn download_attachment(
+    attachment_id: i32,
+    auth: AccountGuard,
+    storage: &State<Box<dyn Storage>>,
+) -> Result<Option<AttachmentDownload>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let attachment = match db.get_attachment(attachment_id) {
+        Ok(attachment) => attachment,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    // Visit attachments are readable like the appointment, the rest like
+    // the patient's history.
+    let authorized = match attachment.appointment_id {
+        Some(appointment_id) => {
+            let ap = db.get_appointment(appointment_id).unwrap();
+            auth.authorize(rbac::APPOINTMENT_READ, &db.appointment_owners(&ap).unwrap())
+                .and_then(|()| {
+                    if clinical_access(&db, &ap, &auth) {
+                        Ok(())
+                    } else {
+                        Err(Forbidden(Some("Not Authorized".to_string())))
+                    }
+                })
+        }
+        None => {
+            let owners = db.patient_owners(attachment.patient_id).unwrap();
+            auth.authorize(rbac::PATIENT_HISTORY_READ, &owners)
+                .and_then(|()| {
+                    if attachment.uploaded_by == auth.claims.sub
+                        || shares_records(&db, attachment.patient_id, &owners, &auth)
+                    {
+                        Ok(())
+                    } else {
+                        Err(Forbidden(Some("Not Authorized".to_string())))
+                    }
+                })
+        }
+    };
+    authorized.map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let body = match db.read_attachment(&attachment, storage.as_ref()) {
+        Ok(body) => body,
+        Err(db::Error::ChecksumMismatch) => {
+            return Err(Custom(
+                Status::InternalServerError,
+                "attachment failed its integrity check".to_string(),
+            ))
+        }
+        Err(db::Error::Storage(e)) => return Err(storage_error(e)),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    let ascii_name: String = attachment
+        .filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    Ok(Some(AttachmentDownload {
+        body,
+        content_type: ContentType::parse_flexible(&attachment.content_type)
+            .unwrap_or(ContentType::Binary),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                ascii_name,
+                RawStr::new(&attachment.filename).percent_encode()
+            ),
+        ),
+    }))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct BookedTimeslotsView {
+pub struct ThreadInput {
+    /// Threads about an appointment take its doctor and patient.
+    appointment_id: Option<i32>,
+    doctor_id: Option<i32>,
     patient_id: Option<i32>,
-    start_date: DateTime<FixedOffset>,
-    duration: i32,
+    subject: String,
+    message: db::MessageData,
 }
 
-#[get("/doctors/<doctor_id>/slots", format = "json", data = "<input>")]
-pub fn doctor_booked_slots(
-    doctor_id: i32,
-    input: Json<DateInput>,
-    auth: AccountGuard<ALL>,
-) -> Json<Vec<BookedTimeslotsView>> {
+#[post("/threads", format = "json", data = "<input>")]
+pub fn create_thread(
+    input: Json<ThreadInput>,
+    auth: AccountGuard,
+) -> Result<Json<i32>, Custom<String>> {
     let db = DB::default().unwrap();
-    let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
-    let appointments = db.get_doctor_appointments(doctor_id, date).unwrap();
+    let (doctor_id, patient_id) = match input.appointment_id {
+        Some(appointment_id) => {
+            let ap = db.get_appointment(appointment_id).unwrap();
+            (ap.doctor_id, ap.patient_id)
+        }
+        None => match (input.doctor_id, input.patient_id) {
+            (Some(doctor_id), Some(patient_id)) => (doctor_id, patient_id),
+            _ => {
+                return Err(Custom(
+                    Status::BadRequest,
+                    "set appointment_id, or doctor_id and patient_id".to_string(),
+                ))
+            }
+        },
+    };
 
-    Json::from(
-        appointments
-            .into_iter()
-            .map(|a| BookedTimeslotsView {
-                patient_id: match auth.claims.account_type {
-                    db::AccountType::Patient => None,
-                    _ => Some(a.patient_id),
-                },
-                start_date: a.start_date,
-                duration: a.duration,
-            })
-            .collect::<Vec<BookedTimeslotsView>>(),
+    auth.authorize(
+        rbac::MESSAGE_WRITE,
+        &db.care_owners(doctor_id, patient_id).unwrap(),
     )
+    .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    let thread = db::ThreadData {
+        patient_id,
+        doctor_id,
+        appointment_id: input.appointment_id,
+        subject: input.subject.clone(),
+    };
+    match db.create_thread(auth.claims.sub, &thread, &input.message) {
+        Ok(thread_id) => Ok(Json::from(thread_id)),
+        Err(db::Error::InvalidMessage(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct BookInput {
-    start_date: DateTime<FixedOffset>,
-    duration: i32,
+/// The caller's threads with their unread counts, most recently active first.
+#[get("/threads")]
+pub fn threads(auth: AccountGuard) -> Result<Json<Vec<db::Thread>>, Forbidden<String>> {
+    auth.require(rbac::MESSAGE_READ)?;
+
+    let db = DB::default().unwrap();
+    Ok(Json::from(db.account_threads(auth.claims.sub).unwrap()))
 }
 
-#[post("/doctors/<doctor_id>/book", format = "json", data = "<input>")]
-pub fn book_doctor(
-    doctor_id: i32,
-    input: Json<BookInput>,
-    auth: AccountGuard<PATIENT>,
-) -> Result<Json<i32>, BadRequest<String>> {
+#[get("/threads/unread")]
+pub fn unread_messages(auth: AccountGuard) -> Result<Json<i64>, Forbidden<String>> {
+    auth.require(rbac::MESSAGE_READ)?;
+
     let db = DB::default().unwrap();
-    let valid_request = db
-        .is_valid_appointment_request(doctor_id, &input.start_date, input.duration)
-        .unwrap();
+    Ok(Json::from(
+        db.unread_message_count(auth.claims.sub).unwrap(),
+    ))
+}
 
-    if valid_request {
-        let appointment_id = db
-            .book_appointment(db::AppointmentRequest {
-                doctor_id: doctor_id,
-                patient_id: auth.claims.sub,
-                start_date: input.start_date,
-                duration: input.duration,
-            })
-            .unwrap();
-        Ok(Json::from(appointment_id))
-    } else {
-        Err(BadRequest(Some("doctor unavailable".to_string())))
+/// Loads the thread if `auth` may use it for `action`.
+fn accessible_thread(
+    db: &DB,
+    thread_id: i32,
+    action: &str,
+    auth: &AccountGuard,
+) -> Result<Option<db::Thread>, Forbidden<String>> {
+    let thread = match db.get_thread(thread_id, auth.claims.sub) {
+        Ok(thread) => thread,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+        Err(e) => panic!("{:?}", e),
+    };
+
+    auth.authorize(action, &db.thread_owners(&thread).unwrap())?;
+    Ok(Some(thread))
+}
+
+#[get("/threads/<thread_id>/messages")]
+pub fn thread_messages(
+    thread_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<Json<Vec<db::Message>>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    if accessible_thread(&db, thread_id, rbac::MESSAGE_READ, &auth)?.is_none() {
+        return Ok(None);
     }
+
+    Ok(Some(Json::from(db.thread_messages(thread_id).unwrap())))
 }
 
-#[post("/appointments/<appointment_id>/cancel")]
-pub fn cancel_appointment(
-    appointment_id: i32,
-    auth: AccountGuard<DOCTOR_ADMIN>,
-) -> Result<(), Forbidden<String>> {
+#[post("/threads/<thread_id>/messages", format = "json", data = "<input>")]
+pub fn send_message(
+    thread_id: i32,
+    input: Json<db::MessageData>,
+    auth: AccountGuard,
+) -> Result<Option<Json<i32>>, Custom<String>> {
     let db = DB::default().unwrap();
-    let appointment = db.get_appointment(appointment_id).unwrap();
+    let thread = accessible_thread(&db, thread_id, rbac::MESSAGE_WRITE, &auth)
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+    if thread.is_none() {
+        return Ok(None);
+    }
 
-    match auth.claims.account_type {
-        db::AccountType::Admin => db
-            .set_appointment_status(appointment_id, db::AppointmentStatus::Cancelled)
-            .unwrap(),
-        db::AccountType::Doctor if auth.claims.sub == appointment.doctor_id => db
-            .set_appointment_status(appointment_id, db::AppointmentStatus::Cancelled)
-            .unwrap(),
-        _ => return Err(Forbidden::<String>(Some("Not Authorized".to_string()))),
+    match db.add_message(thread_id, auth.claims.sub, &input) {
+        Ok(message_id) => Ok(Some(Json::from(message_id))),
+        Err(db::Error::InvalidMessage(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
     }
+}
 
-    Ok(())
+/// Records a read receipt for every message in the thread.
+#[post("/threads/<thread_id>/read")]
+pub fn mark_thread_read(
+    thread_id: i32,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    if accessible_thread(&db, thread_id, rbac::MESSAGE_READ, &auth)?.is_none() {
+        return Ok(None);
+    }
+
+    db.mark_thread_read(thread_id, auth.claims.sub).unwrap();
+    Ok(Some(()))
 }
 
-#[get("/doctors/available", format = "json", data = "<input>")]
-pub fn available_doctors(input: Json<DateInput>, _auth: AccountGuard<ADMIN>) -> Json<Vec<i32>> {
+/// Active prescriptions only, unless `all` is set.
+#[get("/patients/<patient_id>/prescriptions?<all>")]
+pub fn patient_prescriptions(
+    patient_id: i32,
+    all: Option<bool>,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Prescription>>, Forbidden<String>> {
     let db = DB::default().unwrap();
-    let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
-    Json::from(
-        db.doctors_stats(date.date())
+    auth.authorize(
+        rbac::PRESCRIPTION_READ,
+        &db.patient_owners(patient_id).unwrap(),
+    )?;
+
+    Ok(Json::from(
+        db.patient_prescriptions(patient_id, !all.unwrap_or(false))
+            .unwrap(),
+    ))
+}
+
+#[get("/patients/<patient_id>/lab_orders")]
+pub fn patient_lab_orders(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::LabOrder>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(rbac::LAB_READ, &db.patient_owners(patient_id).unwrap())?;
+
+    Ok(Json::from(
+        db.patient_lab_orders(patient_id)
             .unwrap()
             .into_iter()
-            .filter(|stats| stats.booked_mins < 8 * 60 && stats.appointments_count < 12)
-            .map(|s| s.doctor_id)
-            .collect::<Vec<i32>>(),
-    )
+            .map(|order| visible_lab_order(&db, order, &auth))
+            .collect::<Vec<_>>(),
+    ))
 }
 
-#[get("/appointments/<appointment_id>")]
-pub fn appointment_details(
-    appointment_id: i32,
-    auth: AccountGuard<ALL>,
-) -> Result<Json<Appointment>, Forbidden<String>> {
+#[get("/patients/<patient_id>/invoices")]
+pub fn patient_invoices(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Invoice>>, Forbidden<String>> {
     let db = DB::default().unwrap();
-    let ap = db.get_appointment(appointment_id).unwrap();
+    auth.authorize(rbac::INVOICE_READ, &db.patient_owners(patient_id).unwrap())?;
+
+    Ok(Json::from(db.patient_invoices(patient_id).unwrap()))
+}
+
+#[get("/patients/<patient_id>/claims")]
+pub fn patient_claims(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::InsuranceClaim>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(rbac::CLAIM_READ, &db.patient_owners(patient_id).unwrap())?;
+
+    Ok(Json::from(db.claims(None, Some(patient_id)).unwrap()))
+}
+
+#[get("/patients/<patient_id>/consents")]
+pub fn patient_consents(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::Consent>>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(
+        rbac::CONSENT_MANAGE,
+        &db.patient_owners(patient_id).unwrap(),
+    )?;
+
+    Ok(Json::from(db.patient_consents(patient_id).unwrap()))
+}
 
-    match auth.claims.account_type {
-        db::AccountType::Admin => Ok(Json::from(ap)),
-        db::AccountType::Doctor if auth.claims.sub == ap.doctor_id => Ok(Json::from(ap)),
-        db::AccountType::Patient if auth.claims.sub == ap.patient_id => Ok(Json::from(ap)),
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsentInput {
+    document_id: i32,
+}
+
+#[post("/patients/<patient_id>/consents", format = "json", data = "<input>")]
+pub fn accept_consent(
+    patient_id: i32,
+    input: Json<ConsentInput>,
+    auth: AccountGuard,
+) -> Result<Option<Json<i32>>, Custom<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(rbac::CONSENT_GIVE, &db.patient_owners(patient_id).unwrap())
+        .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+
+    match db.accept_consent(patient_id, input.document_id, auth.claims.sub) {
+        Ok(consent_id) => Ok(Some(Json::from(consent_id))),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(db::Error::InvalidConsent(reason)) => Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+}
 
-        _ => return Err(Forbidden::<String>(Some("Not Authorized".to_string()))),
+/// Withdraws the patient's acceptance of every version of the document.
+#[post("/patients/<patient_id>/consents/<code>/withdraw")]
+pub fn withdraw_consent(
+    patient_id: i32,
+    code: &str,
+    auth: AccountGuard,
+) -> Result<Option<()>, Forbidden<String>> {
+    let db = DB::default().unwrap();
+    auth.authorize(
+        rbac::CONSENT_MANAGE,
+        &db.patient_owners(patient_id).unwrap(),
+    )?;
+
+    match db.withdraw_consent(patient_id, code, auth.claims.sub) {
+        Ok(()) => Ok(Some(())),
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => Ok(None),
+        Err(e) => panic!("{:?}", e),
     }
 }
 
 #[get("/patients/<patient_id>/history")]
 pub fn patient_history(
     patient_id: i32,
-    auth: AccountGuard<ALL>,
+    auth: AccountGuard,
 ) -> Result<Json<Vec<Appointment>>, Forbidden<String>> {
-    if auth.claims.account_type == AccountType::Patient && auth.claims.sub != patient_id {
-        return Err(Forbidden::<String>(Some("Not Authorized".to_string())));
+    let db = DB::default().unwrap();
+    let owners = db.patient_owners(patient_id).unwrap();
+    auth.authorize(rbac::PATIENT_HISTORY_READ, &owners)?;
+
+    let mut patient_appointments = db.get_patient_appointments_history(patient_id).unwrap();
+
+    if !shares_records(&db, patient_id, &owners, &auth) {
+        patient_appointments.retain(|appointment| appointment.doctor_id == auth.claims.sub);
+    }
+
+    Ok(Json::from(patient_appointments))
+}
+
+/// Whether `auth` may read the patient's records from other doctors.
+/// Doctors, i.e. callers who write visit notes, need the patient to have
+/// accepted the record sharing consents unless they are on the patient's side.
+fn shares_records(db: &DB, patient_id: i32, owners: &[i32], auth: &AccountGuard) -> bool {
+    !auth.can(&format!("{}.{}", rbac::VISIT_NOTE_WRITE, rbac::OWN))
+        || owners.contains(&auth.claims.sub)
+        || db
+            .missing_consents(patient_id, db::ConsentPurpose::RecordSharing)
+            .unwrap()
+            .is_empty()
+}
+
+/// Returns the patient profile with only the field groups `auth` may see.
+/// Callers that can't see any group are refused.
+fn visible_patient_profile(
+    db: &DB,
+    patient_id: i32,
+    auth: &AccountGuard,
+) -> Result<db::PatientProfile, Custom<String>> {
+    let owners = db.patient_owners(patient_id).unwrap();
+    let visible = |group: &str| auth.authorize(group, &owners).is_ok();
+
+    let groups = [
+        rbac::PATIENT_PROFILE_DEMOGRAPHICS,
+        rbac::PATIENT_PROFILE_CONTACT,
+        rbac::PATIENT_PROFILE_INSURANCE,
+        rbac::PATIENT_PROFILE_CLINICAL,
+    ];
+    if !groups.iter().any(|group| visible(group)) {
+        return Err(Custom(Status::Forbidden, "Not Authorized".to_string()));
     }
 
+    let mut profile = match db.get_patient_profile(patient_id) {
+        Ok(profile) => profile,
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+            return Err(Custom(Status::NotFound, "Patient not found".to_string()))
+        }
+        Err(e) => panic!("{:?}", e),
+    };
+
+    if !visible(rbac::PATIENT_PROFILE_DEMOGRAPHICS) {
+        profile.demographics = None;
+    }
+    if !visible(rbac::PATIENT_PROFILE_CONTACT) {
+        profile.contact = None;
+    }
+    if !visible(rbac::PATIENT_PROFILE_INSURANCE) {
+        profile.insurance = None;
+    }
+    if !visible(rbac::PATIENT_PROFILE_CLINICAL) {
+        profile.clinical = None;
+    }
+    Ok(profile)
+}
+
+#[get("/patients/<patient_id>/profile")]
+pub fn patient_profile(
+    patient_id: i32,
+    auth: AccountGuard,
+) -> Result<Json<db::PatientProfile>, Custom<String>> {
     let db = DB::default().unwrap();
-    let patient_appointments = db.get_patient_appointments_history(patient_id).unwrap();
+    visible_patient_profile(&db, patient_id, &auth).map(Json::from)
+}
 
-    Ok(Json::from(patient_appointments))
+#[post("/patients/<patient_id>/profile", format = "json", data = "<input>")]
+pub fn update_patient_profile(
+    patient_id: i32,
+    input: Json<db::PatientProfileUpdate>,
+    auth: AccountGuard,
+) -> Result<Json<db::PatientProfile>, Custom<String>> {
+    let db = DB::default().unwrap();
+    let owners = db.patient_owners(patient_id).unwrap();
+
+    let groups = [
+        (
+            input.demographics.is_some(),
+            rbac::PATIENT_PROFILE_DEMOGRAPHICS,
+        ),
+        (input.contact.is_some(), rbac::PATIENT_PROFILE_CONTACT),
+        (input.insurance.is_some(), rbac::PATIENT_PROFILE_INSURANCE),
+        (input.clinical.is_some(), rbac::PATIENT_PROFILE_CLINICAL),
+    ];
+    for (_, group) in groups.iter().filter(|(present, _)| *present) {
+        auth.authorize(group, &owners)
+            .map_err(|_| Custom(Status::Forbidden, "Not Authorized".to_string()))?;
+    }
+
+    match db.update_patient_profile(patient_id, &input) {
+        Ok(()) => {}
+        Err(db::Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+            return Err(Custom(Status::NotFound, "Patient not found".to_string()))
+        }
+        Err(db::Error::InvalidProfile(reason)) => return Err(Custom(Status::BadRequest, reason)),
+        Err(e) => panic!("{:?}", e),
+    }
+
+    visible_patient_profile(&db, patient_id, &auth).map(Json::from)
 }
 
 #[get("/doctors/by_top_appointments", format = "json", data = "<input>")]
 pub fn stats_top_appointments(
     input: Json<DateInput>,
-    _auth: AccountGuard<ADMIN>,
-) -> Json<Vec<db::DoctorAppointmentStats>> {
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::DoctorAppointmentStats>>, Forbidden<String>> {
+    auth.require(rbac::STATS_READ)?;
+
     let db = DB::default().unwrap();
     let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
     let mut stats = db.doctors_stats(date.date()).unwrap();
 
     stats.sort_by(|a, b| b.appointments_count.cmp(&a.appointments_count));
     let max = stats[0].appointments_count;
-    Json::from(
+    Ok(Json::from(
         stats
             .into_iter()
             .filter(|a| a.appointments_count == max)
             .collect::<Vec<db::DoctorAppointmentStats>>(),
-    )
+    ))
 }
 
 #[get("/doctors/with_six_hours_plus", format = "json", data = "<input>")]
 pub fn stats_greaterthan_hours(
     input: Json<DateInput>,
-    _auth: AccountGuard<ADMIN>,
-) -> Json<Vec<db::DoctorAppointmentStats>> {
+    auth: AccountGuard,
+) -> Result<Json<Vec<db::DoctorAppointmentStats>>, Forbidden<String>> {
+    auth.require(rbac::STATS_READ)?;
+
     let db = DB::default().unwrap();
     let date = chrono::DateTime::parse_from_str(&input.date, "%Y-%m-%d").unwrap();
     let stats = db.doctors_stats(date.date()).unwrap();
 
-    Json::from(
+    Ok(Json::from(
         stats
             .into_iter()
             .filter(|a| a.booked_mins >= 6 * 60)
             .collect::<Vec<db::DoctorAppointmentStats>>(),
-    )
+    ))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -208,48 +2648,97 @@ pub struct DateInput {
     date: String,
 }
 
-pub struct ADMIN {}
-impl db::ValidClaimsChecker for ADMIN {
-    fn is_valid(claims: &db::Claims) -> bool {
-        claims.account_type == db::AccountType::Admin
-    }
+/// Any authenticated account, together with the permissions granted by its
+/// roles. Services can authenticate with an `X-Api-Key` header instead, in
+/// which case only the key's own permissions apply. Handlers check the
+/// permission they need with `require`, or with `authorize` when the
+/// resource has owners.
+pub struct AccountGuard {
+    claims: Claims,
+    permissions: HashSet<String>,
 }
 
-pub struct DOCTOR {}
-impl db::ValidClaimsChecker for DOCTOR {
-    fn is_valid(claims: &db::Claims) -> bool {
-        claims.account_type == db::AccountType::Doctor
+impl AccountGuard {
+    pub fn can(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
     }
-}
 
-pub struct PATIENT {}
-impl db::ValidClaimsChecker for PATIENT {
-    fn is_valid(claims: &db::Claims) -> bool {
-        claims.account_type == db::AccountType::Patient
+    pub fn require(&self, permission: &str) -> Result<(), Forbidden<String>> {
+        match self.can(permission) {
+            true => Ok(()),
+            false => Err(Forbidden::<String>(Some("Not Authorized".to_string()))),
+        }
+    }
+
+    /// Passes with `<action>.any`, or with `<action>.own` when the caller is
+    /// one of the resource's `owners`.
+    pub fn authorize(&self, action: &str, owners: &[i32]) -> Result<(), Forbidden<String>> {
+        if self.can(&format!("{}.{}", action, rbac::ANY))
+            || (self.can(&format!("{}.{}", action, rbac::OWN)) && owners.contains(&self.claims.sub))
+        {
+            Ok(())
+        } else {
+            Err(Forbidden::<String>(Some("Not Authorized".to_string())))
+        }
     }
 }
 
-pub struct ALL {}
-impl db::ValidClaimsChecker for ALL {
-    fn is_valid(claims: &db::Claims) -> bool {
-        true
+/// Decodes a JWT, accepting it only while its session is still active.
+fn session_claims(jwt: &str) -> Option<Claims> {
+    let claims = db::decode_jwt(jwt).ok()?;
+    let db = DB::default().unwrap();
+
+    match db.touch_session(claims.sid?, db::unix_now()).unwrap()
+        && db.account_active(claims.sub).unwrap()
+    {
+        true => Some(claims),
+        false => None,
     }
 }
 
-pub struct DOCTOR_ADMIN {}
-impl db::ValidClaimsChecker for DOCTOR_ADMIN {
-    fn is_valid(claims: &db::Claims) -> bool {
-        claims.account_type == db::AccountType::Doctor
-            || claims.account_type == db::AccountType::Admin
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for db::ClientInfo {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(db::ClientInfo {
+            ip: req.client_ip().map(|ip| ip.to_string()),
+            user_agent: req.headers().get_one("User-Agent").map(String::from),
+        })
     }
 }
 
-pub struct AccountGuard<T>
-where
-    T: db::ValidClaimsChecker,
-{
-    phantom: PhantomData<T>,
-    claims: Claims,
+/// Accepts a regular token, or the challenge token `login` hands out when the
+/// account's role requires 2FA but it hasn't been set up yet.
+pub struct TotpEnrollmentGuard {
+    account_id: i32,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TotpEnrollmentGuard {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let jwt = match req.headers().get_one("Authorization") {
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+            Some(auth) => auth.rsplit(' ').next().unwrap(),
+        };
+
+        if let Some(claims) = session_claims(jwt) {
+            return Outcome::Success(Self {
+                account_id: claims.sub,
+            });
+        }
+
+        match db::decode_challenge(jwt) {
+            Ok(challenge) if challenge.purpose == db::ChallengePurpose::TotpEnrollment => {
+                Outcome::Success(Self {
+                    account_id: challenge.sub,
+                })
+            }
+            _ => Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -259,28 +2748,39 @@ pub enum AuthError {
 }
 
 #[rocket::async_trait]
-impl<'r, T> FromRequest<'r> for AccountGuard<T>
-where
-    T: db::ValidClaimsChecker,
-{
+impl<'r> FromRequest<'r> for AccountGuard {
     type Error = AuthError;
 
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        match req.headers().get_one("Authorization") {
-            None => Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
-            Some(auth) => {
-                let jwt = auth.split(" ").last().unwrap();
-                let claims = db::decode_jwt(jwt).unwrap();
-
-                if !claims.is_valid::<T>() {
-                    Outcome::Failure((Status::Unauthorized, AuthError::Invalid))
-                } else {
-                    Outcome::Success(Self {
-                        claims: claims,
-                        phantom: PhantomData,
-                    })
-                }
-            }
+        if let Some(key) = req.headers().get_one("X-Api-Key") {
+            let db = DB::default().unwrap();
+            return match db.authenticate_api_key(key, db::unix_now()).unwrap() {
+                Some((claims, permissions)) => Outcome::Success(Self {
+                    claims,
+                    permissions,
+                }),
+                None => Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+            };
         }
+
+        let jwt = match req.headers().get_one("Authorization") {
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+            Some(auth) => auth.rsplit(' ').next().unwrap(),
+        };
+
+        let claims = match session_claims(jwt) {
+            Some(claims) => claims,
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Invalid)),
+        };
+
+        let db = DB::default().unwrap();
+        let permissions = db
+            .account_permissions(claims.sub, claims.account_type)
+            .unwrap();
+
+        Outcome::Success(Self {
+            claims,
+            permissions,
+        })
     }
 }
@@ -1,10 +1,13 @@
-use chrono::{Date, DateTime, Duration, FixedOffset, NaiveDate};
-use rusqlite::{params, Connection, Row, Transaction, TransactionBehavior};
+use chrono::{Date, DateTime, Duration, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use rusqlite::{
+    params, params_from_iter, Connection, Row, ToSql, Transaction, TransactionBehavior,
+};
 
 use derive_more::From;
 use serde::{Deserialize, Serialize};
 use std::{
     cell::{Ref, RefCell},
+    collections::{BTreeMap, HashSet},
     ops::Deref,
     rc::Rc,
     result::Result,
@@ -14,6 +17,10 @@ use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header,
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use argon2::{self, Config};
+use ring::digest;
+
+use crate::storage::Storage;
+use crate::{rbac, totp, x12};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
@@ -23,6 +30,81 @@ pub enum Error {
     HashingError(argon2::Error),
     JWTError(jsonwebtoken::errors::Error),
     InvalidPassword,
+    #[from(ignore)]
+    LoginThrottled(i64),
+    #[from(ignore)]
+    AccountLocked(i64),
+    InvalidTotpCode,
+    TotpAlreadyEnabled,
+    TotpNotEnrolled,
+    SsoAccountNotFound,
+    PasswordLoginDisabled,
+    AccountDeactivated,
+    EmailTaken,
+    #[from(ignore)]
+    InvalidProfile(String),
+    InvalidCursor,
+    #[from(ignore)]
+    InvalidReview(String),
+    ReviewExists,
+    #[from(ignore)]
+    InvalidReferral(String),
+    ReferralRequired,
+    UnknownAppointmentType,
+    /// Bookings need an active patient account.
+    UnknownPatient,
+    #[from(ignore)]
+    InvalidVisitNote(String),
+    #[from(ignore)]
+    InvalidPrescription(String),
+    /// The drug matches these recorded allergies and no override was given.
+    #[from(ignore)]
+    AllergyConflict(Vec<String>),
+    Storage(std::io::Error),
+    #[from(ignore)]
+    InvalidAttachment(String),
+    /// The contents don't match the expected SHA-256.
+    ChecksumMismatch,
+    #[from(ignore)]
+    InvalidQuestionnaire(String),
+    #[from(ignore)]
+    InvalidAnswers(String),
+    /// Required questionnaires must be answered before check-in.
+    QuestionnairesPending,
+    #[from(ignore)]
+    InvalidMessage(String),
+    #[from(ignore)]
+    InvalidLabOrder(String),
+    #[from(ignore)]
+    InvalidLabResult(String),
+    /// The order isn't in a status that allows the change.
+    #[from(ignore)]
+    LabOrderStatus(LabOrderStatus),
+    #[from(ignore)]
+    InvalidAppointmentType(String),
+    #[from(ignore)]
+    InvalidInvoice(String),
+    /// The invoice isn't in a status that allows the change.
+    #[from(ignore)]
+    InvoiceStatus(InvoiceStatus),
+    #[from(ignore)]
+    InvalidInsurancePlan(String),
+    #[from(ignore)]
+    InvalidClaim(String),
+    /// The claim isn't in a status that allows the change.
+    #[from(ignore)]
+    ClaimStatus(ClaimStatus),
+    #[from(ignore)]
+    InvalidConsent(String),
+    /// Codes of the consent documents the patient still has to accept.
+    #[from(ignore)]
+    ConsentRequired(Vec<String>),
+    /// The appointment isn't in a status that allows the change.
+    #[from(ignore)]
+    AppointmentStatus(AppointmentStatus),
+    /// Permissions asked for that the account doesn't hold itself.
+    #[from(ignore)]
+    PermissionsNotHeld(Vec<String>),
 }
 
 pub fn password_hash(password: &[u8]) -> Result<String, Error> {
@@ -35,6 +117,32 @@ pub fn verify_password(hash: &str, password: &[u8]) -> Result<bool, Error> {
     Ok(argon2::verify_encoded(&hash, password)?)
 }
 
+/// SHA-256 hex digest for high-entropy secrets such as recovery codes, which
+/// don't need a slow password hash.
+pub fn token_hash(token: &str) -> String {
+    sha256_hex(token.as_bytes())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Random hex string with `bytes` bytes of entropy.
+pub fn random_token(bytes: usize) -> String {
+    totp::random_bytes(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn unix_now() -> i64 {
+    Utc::now().timestamp()
+}
+
 #[derive(Debug)]
 pub struct DB<'a> {
     con: Connection,
@@ -61,400 +169,8242 @@ impl DB<'_> {
 
     pub fn init_schema(&self) -> Result<(), Error> {
         self.con().execute_batch(include_str!("./schema.sql"))?;
+        self.migrate()?;
+        self.seed_roles()?;
         Ok(())
     }
 
-    pub fn con(&self) -> &Connection {
-        return &self.con;
-    }
-
-    pub fn doctors(&self) -> Result<Vec<i32>, Error> {
-        let mut stmt = self
-            .con()
-            .prepare("SELECT id FROM account where account_type=?1")?;
-        let q = stmt.query_map(params![AccountType::Doctor as i32], |row| {
-            row.get::<usize, i32>(0)
-        })?;
-        Ok(q.collect::<Result<_, _>>()?)
-    }
-
-    pub fn doctors_stats(
-        &self,
-        day: Date<FixedOffset>,
-    ) -> Result<Vec<DoctorAppointmentStats>, Error> {
-        let mut stmt = self.con().prepare(
-            "
-            SELECT account.id, sum(appointment.duration_mins) as s, count(appointment.doctor) as c 
-            FROM account 
-            LEFT JOIN appointment
-            ON appointment.doctor = account.id 
-            GROUP BY account.id
-            HAVING account_type=?1 
-            AND date(starting_date) = ?2
-            AND appointment_status != ?3
-            ",
-        )?;
-        let q = stmt.query_map(
-            params![
-                AccountType::Doctor as i32,
-                day.format("%Y-%m-%d").to_string(),
-                AppointmentStatus::Cancelled as i32
-            ],
-            |row| {
-                Ok(DoctorAppointmentStats {
-                    doctor_id: row.get(0)?,
-                    booked_mins: row.get(1)?,
-                    appointments_count: row.get(2)?,
-                })
-            },
-        )?;
-        Ok(q.collect::<Result<_, _>>()?)
+    /// Brings databases created before `ADDED_COLUMNS` up to date. Fresh
+    /// databases already get these columns from `schema.sql`.
+    fn migrate(&self) -> Result<(), Error> {
+        for (table, column, definition) in ADDED_COLUMNS {
+            let exists: bool = self.con().query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                params![table, column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                self.con().execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    table, column, definition
+                ))?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn register(&self, data: RegisterData) -> Result<(), Error> {
-        let id = {
-            let mut stmnt = self.con().prepare(
-                "INSERT INTO account (fullname, email, passhash, account_type) VALUES (?1, ?2, ?3, ?4) RETURNING id",
+    fn seed_roles(&self) -> Result<(), Error> {
+        for (role, permissions) in rbac::DEFAULT_ROLES {
+            self.con().execute(
+                "INSERT OR IGNORE INTO role (name) VALUES (?1)",
+                params![role],
             )?;
 
-            let passhash = password_hash(data.password.as_bytes())?;
-
-            stmnt.query_row(
-                params![data.name, data.email, passhash, data.account_type as i32],
-                |r| r.get(0),
-            )?
-        };
+            // Each default is granted once, so permissions added in later
+            // releases reach existing roles while admin removals survive restarts.
+            for permission in permissions.iter() {
+                let seeded = self.con().execute(
+                    "INSERT OR IGNORE INTO seeded_permission (role, permission) VALUES (?1, ?2)",
+                    params![role, permission],
+                )?;
+                if seeded > 0 {
+                    self.con().execute(
+                        "INSERT OR IGNORE INTO role_permission (role, permission) VALUES (?1, ?2)",
+                        params![role, permission],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        self.register_account_type(data.account_type, id)?;
+    pub fn con(&self) -> &Connection {
+        return &self.con;
+    }
 
-        Ok(())
+    /// Starts a transaction that the other methods run in until it's
+    /// committed. Dropping it rolls everything back.
+    pub fn transaction(&self) -> Result<Transaction<'_>, Error> {
+        Ok(self.con().unchecked_transaction()?)
     }
 
-    fn register_account_type(&self, account_type: AccountType, id: i32) -> Result<(), Error> {
-        let sql = match account_type {
-            AccountType::Doctor => "INSERT INTO doctor(id) values (?1)",
-            AccountType::Patient => "INSERT INTO patient(id) values (?1)",
-            AccountType::Admin => "INSERT INTO admin(id) values (?1)",
-        };
+    /// Like `transaction`, but takes the write lock up front so concurrent
+    /// writers wait instead of reading rows this one is about to change.
+    pub fn immediate_transaction(&self) -> Result<Transaction<'_>, Error> {
+        Ok(Transaction::new_unchecked(
+            self.con(),
+            TransactionBehavior::Immediate,
+        )?)
+    }
 
-        self.con().execute(sql, params![id])?;
-        Ok(())
+    /// Runs `f` in a savepoint, so its writes land together or not at all.
+    /// Unlike `transaction` this also works inside an open transaction.
+    fn atomically<T>(&self, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        self.con().execute_batch("SAVEPOINT atomically")?;
+        match f() {
+            Ok(value) => {
+                self.con().execute_batch("RELEASE atomically")?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.con()
+                    .execute_batch("ROLLBACK TO atomically; RELEASE atomically")?;
+                Err(e)
+            }
+        }
     }
 
-    pub fn login(&self, data: LoginData) -> Result<JWT, Error> {
-        let mut stmt = self.con().prepare(
-            "SELECT id, account_type, passhash 
-        FROM account 
-        WHERE account.email=?1
-        ",
-        )?;
+    /// One page of active doctors matching `query`, using keyset pagination
+    /// on the sort key and id.
+    pub fn search_doctors(&self, query: &DoctorQuery) -> Result<DoctorPage, Error> {
+        let mut values: Vec<Box<dyn ToSql>> = vec![];
+        let mut bind = |value: Box<dyn ToSql>| {
+            values.push(value);
+            values.len()
+        };
+        let mut conditions = vec!["account.deactivated_at IS NULL".to_string()];
 
-        let claims: Claims = stmt.query_row(params![data.email], |row: &Row| {
-            let password_hash: String = row.get(2)?;
+        if let Some(name) = &query.name {
+            let pattern = name
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            conditions.push(format!(
+                "account.fullname LIKE '%' || ?{} || '%' ESCAPE '\\'",
+                bind(Box::new(pattern))
+            ));
+        }
+        if let Some(specialty) = &query.specialty {
+            conditions.push(format!(
+                "lower(doctor.specialty) = lower(?{})",
+                bind(Box::new(specialty.trim().to_string()))
+            ));
+        }
+        if let Some(clinic) = &query.clinic {
+            conditions.push(format!(
+                "lower(doctor.clinic) = lower(?{})",
+                bind(Box::new(clinic.trim().to_string()))
+            ));
+        }
+        if let Some(language) = &query.language {
+            conditions.push(format!(
+                "EXISTS(SELECT 1 FROM json_each(doctor.languages) WHERE lower(value) = lower(?{}))",
+                bind(Box::new(language.trim().to_string()))
+            ));
+        }
+        if let Some(day) = query.available_on {
+            // Same limits as `is_valid_appointment_request`.
+            conditions.push(format!(
+                "(SELECT count(*) < 12 AND coalesce(sum(duration_mins), 0) < 8 * 60
+                  FROM appointment WHERE appointment.doctor = account.id
+                  AND date(starting_date) = ?{} AND appointment_status != {})",
+                bind(Box::new(day.format("%Y-%m-%d").to_string())),
+                AppointmentStatus::Cancelled as i32
+            ));
+        }
 
-            match verify_password(&password_hash, data.password.as_bytes()) {
-                Ok(false) => return Ok(Err(Error::InvalidPassword)),
-                Err(e) => return Ok(Err(e)),
-                Ok(true) => {}
-            }
+        let key = query.sort.key();
+        let (order, after) = match query.sort.descending() {
+            true => ("DESC", "<"),
+            false => ("ASC", ">"),
+        };
+        if let Some(cursor) = &query.cursor {
+            let cursor = match DoctorCursor::decode(cursor) {
+                Some(cursor) if cursor.sort == query.sort => cursor,
+                _ => return Err(Error::InvalidCursor),
+            };
+            let cursor_key = bind(match cursor.key {
+                CursorKey::Int(key) => Box::new(key),
+                CursorKey::Float(key) => Box::new(key),
+                CursorKey::Text(key) => Box::new(key),
+            });
+            let cursor_id = bind(Box::new(cursor.id));
+            conditions.push(format!(
+                "({0} {1} ?{2} OR ({0} = ?{2} AND account.id > ?{3}))",
+                key, after, cursor_key, cursor_id
+            ));
+        }
 
-            Ok(Ok(Claims {
-                sub: row.get(0)?,
-                account_type: AccountType::try_from(row.get::<_, i32>(1)?).unwrap(),
-                iat: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            }))
-        })??;
+        let limit = query.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {}, {} FROM account JOIN doctor ON account.id = doctor.id
+             WHERE {} ORDER BY {} {}, account.id ASC LIMIT {}",
+            doctor_info_columns(),
+            key,
+            conditions.join(" AND "),
+            key,
+            order,
+            limit + 1
+        ))?;
 
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret("secret".as_ref()),
-        )?;
+        let mut rows = stmnt
+            .query_map(params_from_iter(values.iter()), |row| {
+                let key = match query.sort {
+                    DoctorSort::Name => CursorKey::Text(row.get(11)?),
+                    DoctorSort::FeeAsc | DoctorSort::FeeDesc => CursorKey::Int(row.get(11)?),
+                    DoctorSort::Rating => CursorKey::Float(row.get(11)?),
+                };
+                Ok((doctor_info_from_row(row)?, key))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(token)
-    }
+        let mut next_cursor = None;
+        if rows.len() > limit {
+            rows.truncate(limit);
+            let (doctor, key) = rows.last().unwrap();
+            next_cursor = Some(
+                DoctorCursor {
+                    sort: query.sort,
+                    key: key.clone(),
+                    id: doctor.id,
+                }
+                .encode(),
+            );
+        }
 
-    pub fn get_doctor_info(&self, doctor_id: i32) -> Result<DoctorInfo, Error> {
-        let mut stmnt = self.con().prepare("SELECT fullname, specialty, details FROM account LEFT JOIN doctor ON account.id = doctor.id WHERE account.id = ?1")?;
-        let info = stmnt.query_row(params![doctor_id], |row| {
-            Ok(DoctorInfo {
-                name: row.get(0)?,
-                specialty: row.get(1)?,
-                details: row.get(2)?,
-            })
-        })?;
-        Ok(info)
+        Ok(DoctorPage {
+            doctors: rows.into_iter().map(|(doctor, _)| doctor).collect(),
+            next_cursor,
+        })
     }
 
-    pub fn get_doctor_appointments(
+    /// Ranks active doctors by how well their specialty and details match
+    /// `text`. Every word has to match, as a prefix of an indexed word.
+    pub fn full_text_search_doctors(
         &self,
-        doctor_id: i32,
-        day: DateTime<FixedOffset>,
-    ) -> Result<Vec<Appointment>, Error> {
-        let mut stmnt = self
-            .con()
-            .prepare("SELECT * FROM appointment WHERE doctor = ?1 and date(starting_date) = ?2")?;
+        text: &str,
+        limit: u32,
+    ) -> Result<Vec<DoctorMatch>, Error> {
+        let query = fts_query(text);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
 
-        let mut q = stmnt.query_map(
-            params![doctor_id, day.format("%Y-%m-%d").to_string()],
-            appointment_from_row,
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {}, bm25(doctor_fts, 2.0, 1.0),
+                highlight(doctor_fts, 0, ?3, ?4),
+                snippet(doctor_fts, 1, ?3, ?4, '…', 16)
+             FROM doctor_fts
+             JOIN doctor ON doctor.id = doctor_fts.rowid
+             JOIN account ON account.id = doctor.id
+             WHERE doctor_fts MATCH ?1 AND account.deactivated_at IS NULL
+             ORDER BY bm25(doctor_fts, 2.0, 1.0), account.id
+             LIMIT ?2",
+            doctor_info_columns()
+        ))?;
+
+        let q = stmnt.query_map(
+            params![
+                query,
+                limit.clamp(1, MAX_PAGE_SIZE),
+                MARK_START.to_string(),
+                MARK_END.to_string()
+            ],
+            |row| {
+                Ok(DoctorMatch {
+                    doctor: doctor_info_from_row(row)?,
+                    // bm25 is lower for better matches.
+                    score: -row.get::<_, f64>(11)?,
+                    specialty: mark_html(&row.get::<_, String>(12)?),
+                    snippet: mark_html(&row.get::<_, String>(13)?),
+                })
+            },
         )?;
 
         Ok(q.collect::<Result<_, _>>()?)
     }
 
-    pub fn get_patient_appointments_history(
+    /// Reviews a `Done` appointment. Each appointment gets one review.
+    pub fn create_review(
         &self,
-        patient_id: i32,
-    ) -> Result<Vec<Appointment>, Error> {
-        let mut stmnt = self
-            .con()
-            .prepare("SELECT * FROM appointment WHERE patient = ?1")?;
-
-        let mut q = stmnt.query_map(params![patient_id], appointment_from_row)?;
+        appointment_id: i32,
+        author: i32,
+        data: &ReviewData,
+    ) -> Result<i32, Error> {
+        if !(1..=5).contains(&data.rating) {
+            return Err(Error::InvalidReview(
+                "rating must be between 1 and 5".to_string(),
+            ));
+        }
+        if data.text.chars().count() > MAX_REVIEW_CHARS {
+            return Err(Error::InvalidReview(format!(
+                "review is longer than {} characters",
+                MAX_REVIEW_CHARS
+            )));
+        }
 
-        Ok(q.collect::<Result<_, _>>()?)
-    }
+        let appointment = self.get_appointment(appointment_id)?;
+        if appointment.status != AppointmentStatus::Done {
+            return Err(Error::InvalidReview(
+                "only completed appointments can be reviewed".to_string(),
+            ));
+        }
 
-    pub fn book_appointment(&self, request: AppointmentRequest) -> Result<i32, Error> {
-        let mut stmnt = self
-            .con()
-            .prepare(
-                "INSERT INTO appointment(doctor, patient, appointment_status, start_date, duration_mins)
-             VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
-            )
-            .unwrap();
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM review WHERE appointment = ?1)",
+            params![appointment_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(Error::ReviewExists);
+        }
 
-        let q: i32 = stmnt.query_row(
+        Ok(self.con().query_row(
+            "INSERT INTO review (appointment, doctor, author, rating, body, review_status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
             params![
-                request.doctor_id,
-                request.patient_id,
-                AppointmentStatus::Booked as i32,
-                request.start_date.timestamp() as i32,
-                request.duration
+                appointment_id,
+                appointment.doctor_id,
+                author,
+                data.rating,
+                data.text.trim(),
+                ReviewStatus::Visible as i32,
+                unix_now()
             ],
             |row| row.get(0),
+        )?)
+    }
+
+    /// A doctor's reviews, newest first, leaving out hidden ones.
+    pub fn doctor_reviews(&self, doctor_id: i32) -> Result<Vec<Review>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT id, appointment, doctor, rating, body, review_status, created_at FROM review
+             WHERE doctor = ?1 AND review_status != ?2 ORDER BY created_at DESC, id DESC",
+        )?;
+        let q = stmnt.query_map(
+            params![doctor_id, ReviewStatus::Hidden as i32],
+            review_from_row,
         )?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
 
-        Ok(q)
+    pub fn reviews_with_status(&self, status: ReviewStatus) -> Result<Vec<Review>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT id, appointment, doctor, rating, body, review_status, created_at FROM review
+             WHERE review_status = ?1 ORDER BY created_at DESC, id DESC",
+        )?;
+        let q = stmnt.query_map(params![status as i32], review_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
     }
 
-    pub fn is_valid_appointment_request(
+    pub fn moderate_review(
         &self,
-        doctor_id: i32,
-        start_date: &DateTime<FixedOffset>,
-        duration: i32,
-    ) -> Result<bool, Error> {
-        if duration < 15 || duration > 120 {
-            return Ok(false);
+        review_id: i32,
+        status: ReviewStatus,
+        moderator: i32,
+    ) -> Result<(), Error> {
+        let updated = self.con().execute(
+            "UPDATE review SET review_status = ?1, moderated_by = ?2, moderated_at = ?3
+             WHERE id = ?4",
+            params![status as i32, moderator, unix_now(), review_id],
+        )?;
+        match updated {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            _ => Ok(()),
         }
+    }
 
-        let stats = self.get_doctor_stats(doctor_id, start_date.date())?;
-        if stats.appointments_count >= 12 || stats.booked_mins > 8 * 60 {
-            return Ok(false);
+    /// Appends a version of the appointment's visit note. The first version
+    /// is the note itself, later ones are amendments and need a reason.
+    pub fn add_visit_note(
+        &self,
+        appointment_id: i32,
+        author: i32,
+        data: &VisitNoteData,
+    ) -> Result<i32, Error> {
+        if data.chief_complaint.trim().is_empty() {
+            return Err(Error::InvalidVisitNote(
+                "chief complaint is required".to_string(),
+            ));
+        }
+        let mut diagnosis_codes = Vec::new();
+        for code in &data.diagnosis_codes {
+            let code = code.trim().to_uppercase();
+            if !is_diagnosis_code(&code) {
+                return Err(Error::InvalidVisitNote(format!(
+                    "{} is not an ICD-10 code",
+                    code
+                )));
+            }
+            if !diagnosis_codes.contains(&code) {
+                diagnosis_codes.push(code);
+            }
         }
 
-        return Ok(true);
-    }
+        let appointment = self.get_appointment(appointment_id)?;
+        if !matches!(
+            appointment.status,
+            AppointmentStatus::CheckedIn | AppointmentStatus::Done
+        ) {
+            return Err(Error::InvalidVisitNote(
+                "notes can only be written once the patient has checked in".to_string(),
+            ));
+        }
 
-    pub fn get_doctor_stats(
-        &self,
-        doctor_id: i32,
-        day: Date<FixedOffset>,
-    ) -> Result<DoctorAppointmentStats, Error> {
-        let mut stmnt = self.con().prepare(
-            "SELECT doctor, count(*), sum(duration) FROM appointments
-                 GROUP BY doctor
-                 HAVING doctor = ?1 and date(starting_date) = ?2 and appointment_status != ?3",
+        let amendment_reason = data
+            .amendment_reason
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty());
+        let amending: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM visit_note WHERE appointment = ?1)",
+            params![appointment_id],
+            |row| row.get(0),
         )?;
+        if amending && amendment_reason.is_none() {
+            return Err(Error::InvalidVisitNote(
+                "amendments need a reason".to_string(),
+            ));
+        }
 
-        let q = stmnt.query_row(
+        Ok(self.con().query_row(
+            "INSERT INTO visit_note (appointment, version, author, chief_complaint, findings,
+                diagnosis_codes, plan, patient_summary, amendment_reason, created_at)
+             SELECT ?1, coalesce(max(version), 0) + 1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9
+             FROM visit_note WHERE appointment = ?1
+             RETURNING version",
             params![
-                doctor_id,
-                day.format("%Y-%m-%d").to_string(),
-                AppointmentStatus::Cancelled as i32
+                appointment_id,
+                author,
+                data.chief_complaint.trim(),
+                data.findings.trim(),
+                serde_json::to_string(&diagnosis_codes).unwrap(),
+                data.plan.trim(),
+                data.patient_summary.trim(),
+                amendment_reason.filter(|_| amending),
+                unix_now()
             ],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Every version of the appointment's visit note, oldest first.
+    pub fn visit_notes(&self, appointment_id: i32) -> Result<Vec<VisitNote>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM visit_note WHERE appointment = ?1 ORDER BY version",
+            VISIT_NOTE_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![appointment_id], visit_note_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// The patient-facing part of the latest note version.
+    pub fn visit_summary(&self, appointment_id: i32) -> Result<Option<VisitSummary>, Error> {
+        let q = self.con().query_row(
+            "SELECT patient_summary, version, created_at FROM visit_note
+             WHERE appointment = ?1 ORDER BY version DESC LIMIT 1",
+            params![appointment_id],
             |row| {
-                Ok(DoctorAppointmentStats {
-                    doctor_id: row.get(0)?,
-                    appointments_count: row.get(1)?,
-                    booked_mins: row.get(2)?,
+                Ok(VisitSummary {
+                    summary: row.get(0)?,
+                    version: row.get(1)?,
+                    updated_at: row.get(2)?,
                 })
             },
-        )?;
+        );
+        match q {
+            Ok(summary) => Ok(Some(summary)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        Ok(q)
+    /// Prescribes a drug after a visit. Drugs matching the patient's recorded
+    /// allergies are refused unless the doctor gives an override reason.
+    pub fn create_prescription(
+        &self,
+        appointment_id: i32,
+        data: &PrescriptionData,
+    ) -> Result<i32, Error> {
+        let drug = data.drug.trim();
+        if drug.is_empty() || data.dose.trim().is_empty() || data.frequency.trim().is_empty() {
+            return Err(Error::InvalidPrescription(
+                "drug, dose and frequency are required".to_string(),
+            ));
+        }
+        if !(1..=MAX_PRESCRIPTION_DAYS).contains(&data.duration_days) {
+            return Err(Error::InvalidPrescription(format!(
+                "duration must be between 1 and {} days",
+                MAX_PRESCRIPTION_DAYS
+            )));
+        }
+        if !(0..=MAX_REFILLS).contains(&data.refills) {
+            return Err(Error::InvalidPrescription(format!(
+                "refills must be between 0 and {}",
+                MAX_REFILLS
+            )));
+        }
+
+        let appointment = self.get_appointment(appointment_id)?;
+        if !matches!(
+            appointment.status,
+            AppointmentStatus::CheckedIn | AppointmentStatus::Done
+        ) {
+            return Err(Error::InvalidPrescription(
+                "prescriptions can only be issued once the patient has checked in".to_string(),
+            ));
+        }
+
+        let allergy_override = data
+            .allergy_override
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty());
+        let conflicts = self.allergy_conflicts(appointment.patient_id, drug)?;
+        if !conflicts.is_empty() && allergy_override.is_none() {
+            return Err(Error::AllergyConflict(conflicts));
+        }
+
+        let now = unix_now();
+        // Each refill covers another full course.
+        let expires_at = now + (data.duration_days * (data.refills + 1)) as i64 * 24 * 60 * 60;
+        Ok(self.con().query_row(
+            "INSERT INTO prescription (appointment, patient, doctor, drug, dose, frequency,
+                duration_days, refills, instructions, allergy_override, prescription_status,
+                created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13) RETURNING id",
+            params![
+                appointment_id,
+                appointment.patient_id,
+                appointment.doctor_id,
+                drug,
+                data.dose.trim(),
+                data.frequency.trim(),
+                data.duration_days,
+                data.refills,
+                data.instructions.trim(),
+                allergy_override.filter(|_| !conflicts.is_empty()),
+                PrescriptionStatus::Active as i32,
+                now,
+                expires_at
+            ],
+            |row| row.get(0),
+        )?)
     }
 
-    pub fn get_appointment(&self, appointment_id: i32) -> Result<Appointment, Error> {
-        let mut stmnt = self
-            .con()
-            .prepare("SELECT * FROM appointment where id = ?1")?;
+    /// Recorded allergies naming the drug or named by it, e.g. "penicillin"
+    /// for "Penicillin V".
+    pub fn allergy_conflicts(&self, patient_id: i32, drug: &str) -> Result<Vec<String>, Error> {
+        let allergies: String = match self.con().query_row(
+            "SELECT allergies FROM patient WHERE id = ?1",
+            params![patient_id],
+            |row| row.get(0),
+        ) {
+            Ok(allergies) => allergies,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let allergies: Vec<String> = serde_json::from_str(&allergies).unwrap_or_default();
 
-        let q = stmnt.query_row(params![appointment_id], appointment_from_row)?;
+        let drug = drug.to_lowercase();
+        Ok(allergies
+            .into_iter()
+            .filter(|allergy| {
+                let allergy = allergy.to_lowercase();
+                !allergy.is_empty() && (drug.contains(&allergy) || allergy.contains(&drug))
+            })
+            .collect())
+    }
 
-        Ok(q)
+    pub fn get_prescription(&self, prescription_id: i32) -> Result<Prescription, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM prescription WHERE id = ?1",
+                PRESCRIPTION_COLUMNS
+            ),
+            params![prescription_id],
+            prescription_from_row,
+        )?)
     }
 
-    pub fn set_appointment_status(
+    /// The patient's prescriptions, newest first. With `active_only`,
+    /// cancelled and expired ones are left out.
+    pub fn patient_prescriptions(
         &self,
-        appointment_id: i32,
-        status: AppointmentStatus,
-    ) -> Result<(), Error> {
-        let mut stmnt = self.con().prepare(
-            "
-            UPDATE appointment SET appointment_status = ?1 WHERE id = ?2
-        ",
+        patient_id: i32,
+        active_only: bool,
+    ) -> Result<Vec<Prescription>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM prescription
+             WHERE patient = ?1 AND (NOT ?2 OR (prescription_status = ?3 AND expires_at > ?4))
+             ORDER BY created_at DESC, id DESC",
+            PRESCRIPTION_COLUMNS
+        ))?;
+        let q = stmnt.query_map(
+            params![
+                patient_id,
+                active_only,
+                PrescriptionStatus::Active as i32,
+                unix_now()
+            ],
+            prescription_from_row,
         )?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
 
-        stmnt.execute(params![status as i32, appointment_id])?;
+    pub fn cancel_prescription(&self, prescription_id: i32) -> Result<(), Error> {
+        let updated = self.con().execute(
+            "UPDATE prescription SET prescription_status = ?1 WHERE id = ?2",
+            params![PrescriptionStatus::Cancelled as i32, prescription_id],
+        )?;
+        match updated {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            _ => Ok(()),
+        }
+    }
 
-        Ok(())
+    pub fn prescription_owners(&self, prescription: &Prescription) -> Result<Vec<i32>, Error> {
+        let mut owners = self.patient_owners(prescription.patient_id)?;
+        owners.push(prescription.doctor_id);
+        Ok(owners)
     }
-}
+
+    /// Validates and stores an upload, then records its metadata. The
+    /// contents must look like the declared content type.
+    pub fn create_attachment(
+        &self,
+        upload: &AttachmentUpload,
+        storage: &dyn Storage,
+    ) -> Result<Attachment, Error> {
+        let content_type = upload.content_type.to_lowercase();
+        if !ATTACHMENT_TYPES.contains(&content_type.as_str()) {
+            return Err(Error::InvalidAttachment(format!(
+                "{} files aren't accepted",
+                content_type
+            )));
+        }
+        if upload.data.is_empty() {
+            return Err(Error::InvalidAttachment("file is empty".to_string()));
+        }
+        if !content_matches_type(&content_type, upload.data) {
+            return Err(Error::InvalidAttachment(format!(
+                "contents aren't {}",
+                content_type
+            )));
+        }
+
+        let sha256 = sha256_hex(upload.data);
+        if let Some(expected) = upload.sha256 {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                return Err(Error::ChecksumMismatch);
+            }
+        }
+
+        let attachment = Attachment {
+            id: 0,
+            patient_id: upload.patient_id,
+            appointment_id: upload.appointment_id,
+            uploaded_by: upload.uploaded_by,
+            filename: attachment_filename(upload.filename),
+            content_type,
+            size_bytes: upload.data.len() as i64,
+            sha256,
+            created_at: unix_now(),
+            storage_key: random_token(16),
+        };
+        storage.put(&attachment.storage_key, upload.data)?;
+
+        let id = self.con().query_row(
+            "INSERT INTO attachment (patient, appointment, uploaded_by, filename, content_type,
+                size_bytes, sha256, storage_key, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) RETURNING id",
+            params![
+                attachment.patient_id,
+                attachment.appointment_id,
+                attachment.uploaded_by,
+                attachment.filename,
+                attachment.content_type,
+                attachment.size_bytes,
+                attachment.sha256,
+                attachment.storage_key,
+                attachment.created_at
+            ],
+            |row| row.get(0),
+        );
+        match id {
+            Ok(id) => Ok(Attachment { id, ..attachment }),
+            Err(e) => {
+                storage.delete(&attachment.storage_key)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn get_attachment(&self, attachment_id: i32) -> Result<Attachment, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM attachment WHERE id = ?1",
+                ATTACHMENT_COLUMNS
+            ),
+            params![attachment_id],
+            attachment_from_row,
+        )?)
+    }
+
+    pub fn appointment_attachments(&self, appointment_id: i32) -> Result<Vec<Attachment>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM attachment WHERE appointment = ?1 ORDER BY id",
+            ATTACHMENT_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![appointment_id], attachment_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// All of the patient's attachments, including those on appointments.
+    pub fn patient_attachments(&self, patient_id: i32) -> Result<Vec<Attachment>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM attachment WHERE patient = ?1 ORDER BY id",
+            ATTACHMENT_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![patient_id], attachment_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Reads the contents back, checking them against the stored checksum.
+    pub fn read_attachment(
+        &self,
+        attachment: &Attachment,
+        storage: &dyn Storage,
+    ) -> Result<Vec<u8>, Error> {
+        let data = storage.get(&attachment.storage_key)?;
+        if sha256_hex(&data) != attachment.sha256 {
+            return Err(Error::ChecksumMismatch);
+        }
+        Ok(data)
+    }
+
+    pub fn create_questionnaire(
+        &self,
+        author: i32,
+        data: &QuestionnaireData,
+    ) -> Result<i32, Error> {
+        self.check_questionnaire(data)?;
+        Ok(self.con().query_row(
+            "INSERT INTO questionnaire (name, appointment_type, required, questions, created_by,
+                created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+            params![
+                data.name.trim(),
+                data.appointment_type_id,
+                data.required,
+                serde_json::to_string(&data.questions).unwrap(),
+                author,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Replaces the template for bookings from now on. Forms already
+    /// attached keep the questions they were sent out with.
+    pub fn update_questionnaire(
+        &self,
+        questionnaire_id: i32,
+        data: &QuestionnaireData,
+    ) -> Result<(), Error> {
+        self.check_questionnaire(data)?;
+        let updated = self.con().execute(
+            "UPDATE questionnaire SET name = ?1, appointment_type = ?2, required = ?3,
+                questions = ?4
+             WHERE id = ?5",
+            params![
+                data.name.trim(),
+                data.appointment_type_id,
+                data.required,
+                serde_json::to_string(&data.questions).unwrap(),
+                questionnaire_id
+            ],
+        )?;
+        match updated {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_questionnaire(&self, data: &QuestionnaireData) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidQuestionnaire(reason.to_string()));
+        if data.name.trim().is_empty() {
+            return invalid("name is required");
+        }
+        if data.questions.is_empty() {
+            return invalid("add at least one question");
+        }
+        let mut keys = HashSet::new();
+        for question in &data.questions {
+            if question.key.trim().is_empty() || question.text.trim().is_empty() {
+                return invalid("questions need a key and text");
+            }
+            if !keys.insert(question.key.as_str()) {
+                return Err(Error::InvalidQuestionnaire(format!(
+                    "duplicate question key {}",
+                    question.key
+                )));
+            }
+            let choice = matches!(
+                question.kind,
+                QuestionKind::SingleChoice | QuestionKind::MultipleChoice
+            );
+            if choice && question.options.len() < 2 {
+                return invalid("choice questions need at least two options");
+            }
+            if !choice && !question.options.is_empty() {
+                return invalid("only choice questions have options");
+            }
+        }
+
+        match self.get_appointment_type(data.appointment_type_id) {
+            Ok(_) => Ok(()),
+            Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                Err(Error::UnknownAppointmentType)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get_questionnaire(&self, questionnaire_id: i32) -> Result<Questionnaire, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM questionnaire WHERE id = ?1",
+                QUESTIONNAIRE_COLUMNS
+            ),
+            params![questionnaire_id],
+            questionnaire_from_row,
+        )?)
+    }
+
+    pub fn questionnaires(
+        &self,
+        appointment_type_id: Option<i32>,
+    ) -> Result<Vec<Questionnaire>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM questionnaire WHERE ?1 IS NULL OR appointment_type = ?1 ORDER BY id",
+            QUESTIONNAIRE_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![appointment_type_id], questionnaire_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Attaches a questionnaire to a booking on top of those that came with
+    /// its appointment type, or changes whether it's required.
+    pub fn attach_questionnaire(
+        &self,
+        appointment_id: i32,
+        questionnaire_id: i32,
+        required: bool,
+    ) -> Result<(), Error> {
+        let questionnaire = self.get_questionnaire(questionnaire_id)?;
+        self.con().execute(
+            "INSERT INTO appointment_questionnaire (appointment, questionnaire, required, name,
+                questions)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(appointment, questionnaire) DO UPDATE SET required = excluded.required",
+            params![
+                appointment_id,
+                questionnaire_id,
+                required,
+                questionnaire.name,
+                serde_json::to_string(&questionnaire.questions).unwrap()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn appointment_questionnaires(
+        &self,
+        appointment_id: i32,
+    ) -> Result<Vec<AppointmentQuestionnaire>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT questionnaire.id,
+                coalesce(appointment_questionnaire.name, questionnaire.name), appointment_type,
+                questionnaire.required,
+                coalesce(appointment_questionnaire.questions, questionnaire.questions),
+                created_by, created_at, appointment_questionnaire.required, answers, submitted_at
+             FROM appointment_questionnaire
+             JOIN questionnaire ON questionnaire.id = appointment_questionnaire.questionnaire
+             WHERE appointment = ?1 ORDER BY questionnaire.id",
+        )?;
+        let q = stmnt.query_map(params![appointment_id], |row| {
+            Ok(AppointmentQuestionnaire {
+                questionnaire: questionnaire_from_row(row)?,
+                required: row.get(7)?,
+                answers: row
+                    .get::<_, Option<String>>(8)?
+                    .map(|answers| serde_json::from_str(&answers).unwrap_or_default()),
+                submitted_at: row.get(9)?,
+            })
+        })?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Stores the patient's answers, replacing earlier ones. Answers can be
+    /// changed until the patient checks in.
+    pub fn submit_answers(
+        &self,
+        appointment_id: i32,
+        questionnaire_id: i32,
+        answers: &BTreeMap<String, serde_json::Value>,
+    ) -> Result<(), Error> {
+        let attached = self
+            .appointment_questionnaires(appointment_id)?
+            .into_iter()
+            .find(|a| a.questionnaire.id == questionnaire_id)
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        if self.get_appointment(appointment_id)?.status != AppointmentStatus::Booked {
+            return Err(Error::InvalidAnswers(
+                "answers can only be given before the visit".to_string(),
+            ));
+        }
+
+        let answers: BTreeMap<&String, &serde_json::Value> = answers
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .collect();
+        let questions = &attached.questionnaire.questions;
+        if let Some(key) = answers
+            .keys()
+            .find(|key| !questions.iter().any(|q| &&q.key == *key))
+        {
+            return Err(Error::InvalidAnswers(format!("unknown question {}", key)));
+        }
+        for question in questions {
+            match answers.get(&question.key) {
+                Some(value) if !question.accepts(value) => {
+                    return Err(Error::InvalidAnswers(format!(
+                        "invalid answer to {}",
+                        question.key
+                    )))
+                }
+                None if question.required => {
+                    return Err(Error::InvalidAnswers(format!(
+                        "{} is required",
+                        question.key
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        self.con().execute(
+            "UPDATE appointment_questionnaire SET answers = ?1, submitted_at = ?2
+             WHERE appointment = ?3 AND questionnaire = ?4",
+            params![
+                serde_json::to_string(&answers).unwrap(),
+                unix_now(),
+                appointment_id,
+                questionnaire_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Starts a thread with its first message. Threads without an
+    /// appointment need the doctor to have seen or booked the patient before.
+    pub fn create_thread(
+        &self,
+        author: i32,
+        data: &ThreadData,
+        message: &MessageData,
+    ) -> Result<i32, Error> {
+        let subject = data.subject.trim();
+        if subject.is_empty() || subject.chars().count() > MAX_SUBJECT_CHARS {
+            return Err(Error::InvalidMessage(format!(
+                "subject must be 1 to {} characters",
+                MAX_SUBJECT_CHARS
+            )));
+        }
+
+        let related: bool = match data.appointment_id {
+            Some(appointment_id) => {
+                let appointment = self.get_appointment(appointment_id)?;
+                appointment.doctor_id == data.doctor_id && appointment.patient_id == data.patient_id
+            }
+            None => self.con().query_row(
+                "SELECT EXISTS(SELECT 1 FROM appointment WHERE doctor = ?1 AND patient = ?2)",
+                params![data.doctor_id, data.patient_id],
+                |row| row.get(0),
+            )?,
+        };
+        if !related {
+            return Err(Error::InvalidMessage(
+                "the doctor has no appointments with this patient".to_string(),
+            ));
+        }
+        self.check_message(data.patient_id, message)?;
+
+        let thread_id = self.con().query_row(
+            "INSERT INTO message_thread (patient, doctor, appointment, subject, created_by,
+                created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+            params![
+                data.patient_id,
+                data.doctor_id,
+                data.appointment_id,
+                subject,
+                author,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?;
+        self.insert_message(thread_id, author, message)?;
+        Ok(thread_id)
+    }
+
+    pub fn add_message(
+        &self,
+        thread_id: i32,
+        author: i32,
+        message: &MessageData,
+    ) -> Result<i32, Error> {
+        let thread = self.get_thread(thread_id, author)?;
+        self.check_message(thread.patient_id, message)?;
+        self.insert_message(thread_id, author, message)
+    }
+
+    /// Messages can only carry attachments of the thread's patient.
+    fn check_message(&self, patient_id: i32, message: &MessageData) -> Result<(), Error> {
+        let length = message.body.trim().chars().count();
+        if length == 0 || length > MAX_MESSAGE_CHARS {
+            return Err(Error::InvalidMessage(format!(
+                "message must be 1 to {} characters",
+                MAX_MESSAGE_CHARS
+            )));
+        }
+        if let Some(attachment_id) = message.attachment_id {
+            match self.get_attachment(attachment_id) {
+                Ok(attachment) if attachment.patient_id == patient_id => {}
+                Ok(_) | Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                    return Err(Error::InvalidMessage("attachment not found".to_string()))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_message(
+        &self,
+        thread_id: i32,
+        author: i32,
+        message: &MessageData,
+    ) -> Result<i32, Error> {
+        let message_id = self.con().query_row(
+            "INSERT INTO message (thread, author, body, attachment, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+            params![
+                thread_id,
+                author,
+                message.body.trim(),
+                message.attachment_id,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?;
+        // Authors have read everything up to their own message.
+        self.mark_thread_read(thread_id, author)?;
+        Ok(message_id)
+    }
+
+    /// The thread as seen by `viewer`, whose unread messages are counted.
+    pub fn get_thread(&self, thread_id: i32, viewer: i32) -> Result<Thread, Error> {
+        Ok(self.con().query_row(
+            &format!("{} WHERE message_thread.id = ?2", THREAD_SELECT),
+            params![viewer, thread_id],
+            thread_from_row,
+        )?)
+    }
+
+    /// Threads the account takes part in, as doctor, patient or guardian,
+    /// with the most recently active first.
+    pub fn account_threads(&self, account_id: i32) -> Result<Vec<Thread>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "{} WHERE doctor = ?1 OR patient = ?1
+                OR patient IN (SELECT dependent FROM guardian WHERE guardian = ?1)
+             ORDER BY last_message_at DESC, message_thread.id DESC",
+            THREAD_SELECT
+        ))?;
+        let q = stmnt.query_map(params![account_id], thread_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn unread_message_count(&self, account_id: i32) -> Result<i64, Error> {
+        Ok(self
+            .account_threads(account_id)?
+            .iter()
+            .map(|thread| thread.unread)
+            .sum())
+    }
+
+    pub fn thread_owners(&self, thread: &Thread) -> Result<Vec<i32>, Error> {
+        self.care_owners(thread.doctor_id, thread.patient_id)
+    }
+
+    /// Messages oldest first, each with the accounts that have read it.
+    pub fn thread_messages(&self, thread_id: i32) -> Result<Vec<Message>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT account, last_read FROM message_read WHERE thread = ?1 ORDER BY account",
+        )?;
+        let reads = stmnt
+            .query_map(params![thread_id], |row| {
+                Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmnt = self.con().prepare(
+            "SELECT id, thread, author, body, attachment, created_at FROM message
+             WHERE thread = ?1 ORDER BY id",
+        )?;
+        let q = stmnt.query_map(params![thread_id], |row| {
+            let id = row.get(0)?;
+            Ok(Message {
+                id,
+                thread_id: row.get(1)?,
+                author_id: row.get(2)?,
+                body: row.get(3)?,
+                attachment_id: row.get(4)?,
+                created_at: row.get(5)?,
+                read_by: reads
+                    .iter()
+                    .filter(|(_, last_read)| *last_read >= id)
+                    .map(|(account, _)| *account)
+                    .collect(),
+            })
+        })?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Marks every message in the thread as read by the account.
+    pub fn mark_thread_read(&self, thread_id: i32, account_id: i32) -> Result<(), Error> {
+        self.con().execute(
+            "INSERT INTO message_read (thread, account, last_read)
+             SELECT ?1, ?2, last FROM (SELECT max(id) AS last FROM message WHERE thread = ?1)
+             WHERE last IS NOT NULL
+             ON CONFLICT(thread, account) DO UPDATE SET last_read = excluded.last_read",
+            params![thread_id, account_id],
+        )?;
+        Ok(())
+    }
+
+    /// Orders a lab test for a patient seen at the appointment.
+    pub fn create_lab_order(&self, appointment_id: i32, data: &LabOrderData) -> Result<i32, Error> {
+        let test_code = data.test_code.trim();
+        let test_name = data.test_name.trim();
+        if test_code.is_empty() || test_code.chars().count() > MAX_LAB_CODE_CHARS {
+            return Err(Error::InvalidLabOrder(format!(
+                "test code must be 1 to {} characters",
+                MAX_LAB_CODE_CHARS
+            )));
+        }
+        if test_name.is_empty() || test_name.chars().count() > MAX_LAB_NAME_CHARS {
+            return Err(Error::InvalidLabOrder(format!(
+                "test name must be 1 to {} characters",
+                MAX_LAB_NAME_CHARS
+            )));
+        }
+
+        let appointment = self.get_appointment(appointment_id)?;
+        if !matches!(
+            appointment.status,
+            AppointmentStatus::CheckedIn | AppointmentStatus::Done
+        ) {
+            return Err(Error::InvalidLabOrder(
+                "lab tests can only be ordered once the patient has checked in".to_string(),
+            ));
+        }
+
+        Ok(self.con().query_row(
+            "INSERT INTO lab_order (appointment, patient, doctor, test_code, test_name, notes,
+                lab_status, ordered_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) RETURNING id",
+            params![
+                appointment_id,
+                appointment.patient_id,
+                appointment.doctor_id,
+                test_code,
+                test_name,
+                data.notes.trim(),
+                LabOrderStatus::Ordered as i32,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn get_lab_order(&self, order_id: i32) -> Result<LabOrder, Error> {
+        let mut order = self.con().query_row(
+            &format!("SELECT {} FROM lab_order WHERE id = ?1", LAB_ORDER_COLUMNS),
+            params![order_id],
+            lab_order_from_row,
+        )?;
+        order.results = self.lab_results(order_id)?;
+        Ok(order)
+    }
+
+    /// The patient's lab orders with their results, newest first.
+    pub fn patient_lab_orders(&self, patient_id: i32) -> Result<Vec<LabOrder>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM lab_order WHERE patient = ?1 ORDER BY ordered_at DESC, id DESC",
+            LAB_ORDER_COLUMNS
+        ))?;
+        let mut orders = stmnt
+            .query_map(params![patient_id], lab_order_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        for order in orders.iter_mut() {
+            order.results = self.lab_results(order.id)?;
+        }
+        Ok(orders)
+    }
+
+    fn lab_results(&self, order_id: i32) -> Result<Vec<LabResult>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT code, name, value, units, reference_range, flag FROM lab_result
+             WHERE lab_order = ?1 ORDER BY id",
+        )?;
+        let q = stmnt.query_map(params![order_id], |row| {
+            Ok(LabResult {
+                code: row.get(0)?,
+                name: row.get(1)?,
+                value: row.get(2)?,
+                units: row.get(3)?,
+                reference_range: row.get(4)?,
+                flag: row
+                    .get::<_, Option<i32>>(5)?
+                    .map(|flag| LabFlag::try_from(flag).unwrap()),
+            })
+        })?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn lab_order_owners(&self, order: &LabOrder) -> Result<Vec<i32>, Error> {
+        let mut owners = self.patient_owners(order.patient_id)?;
+        owners.push(order.doctor_id);
+        Ok(owners)
+    }
+
+    pub fn mark_lab_collected(&self, order_id: i32) -> Result<(), Error> {
+        let order = self.get_lab_order(order_id)?;
+        if order.status != LabOrderStatus::Ordered {
+            return Err(Error::LabOrderStatus(order.status));
+        }
+        self.con().execute(
+            "UPDATE lab_order SET lab_status = ?1, collected_at = ?2 WHERE id = ?3",
+            params![LabOrderStatus::Collected as i32, unix_now(), order_id],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the order's results. Corrections are accepted until the
+    /// doctor has reviewed them. Flags left out are derived from numeric
+    /// reference ranges.
+    pub fn record_lab_results(
+        &self,
+        order_id: i32,
+        results: &[LabResultData],
+    ) -> Result<(), Error> {
+        self.atomically(|| {
+            let order = self.get_lab_order(order_id)?;
+            if order.status == LabOrderStatus::Reviewed {
+                return Err(Error::LabOrderStatus(order.status));
+            }
+            if results.is_empty() {
+                return Err(Error::InvalidLabResult("no results given".to_string()));
+            }
+            for result in results {
+                if result.code.trim().is_empty() || result.value.trim().is_empty() {
+                    return Err(Error::InvalidLabResult(
+                        "every result needs a code and a value".to_string(),
+                    ));
+                }
+            }
+
+            self.con().execute(
+                "DELETE FROM lab_result WHERE lab_order = ?1",
+                params![order_id],
+            )?;
+            for result in results {
+                let flag = result
+                    .flag
+                    .or_else(|| reference_flag(&result.value, &result.reference_range));
+                self.con().execute(
+                    "INSERT INTO lab_result (lab_order, code, name, value, units, reference_range,
+                        flag)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        order_id,
+                        result.code.trim(),
+                        result.name.trim(),
+                        result.value.trim(),
+                        result.units.trim(),
+                        result.reference_range.trim(),
+                        flag.map(|flag| flag as i32)
+                    ],
+                )?;
+            }
+
+            let now = unix_now();
+            self.con().execute(
+                "UPDATE lab_order SET lab_status = ?1, collected_at = coalesce(collected_at, ?2),
+                    resulted_at = ?2
+                 WHERE id = ?3",
+                params![LabOrderStatus::Resulted as i32, now, order_id],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Marks the results reviewed, which releases them to the patient, and
+    /// lets the patient know with a message from the ordering doctor.
+    pub fn review_lab_order(&self, order_id: i32) -> Result<(), Error> {
+        let order = self.get_lab_order(order_id)?;
+        if order.status != LabOrderStatus::Resulted {
+            return Err(Error::LabOrderStatus(order.status));
+        }
+        self.con().execute(
+            "UPDATE lab_order SET lab_status = ?1, reviewed_at = ?2 WHERE id = ?3",
+            params![LabOrderStatus::Reviewed as i32, unix_now(), order_id],
+        )?;
+
+        let message = MessageData {
+            body: format!("Your {} results are ready to view.", order.test_name),
+            attachment_id: None,
+        };
+        let thread_id: Option<i32> = match self.con().query_row(
+            "SELECT id FROM message_thread WHERE appointment = ?1 AND doctor = ?2
+             ORDER BY id DESC LIMIT 1",
+            params![order.appointment_id, order.doctor_id],
+            |row| row.get(0),
+        ) {
+            Ok(thread_id) => Some(thread_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        match thread_id {
+            Some(thread_id) => self.add_message(thread_id, order.doctor_id, &message)?,
+            None => self.create_thread(
+                order.doctor_id,
+                &ThreadData {
+                    patient_id: order.patient_id,
+                    doctor_id: order.doctor_id,
+                    appointment_id: Some(order.appointment_id),
+                    subject: "Lab results".to_string(),
+                },
+                &message,
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Drafts the invoice for a completed appointment: the doctor's
+    /// consultation fee plus the appointment type's fee, taxed at the type's
+    /// rate. Appointments keep their first invoice.
+    fn generate_invoice(&self, appointment_id: i32) -> Result<(), Error> {
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM invoice WHERE appointment = ?1)",
+            params![appointment_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(());
+        }
+
+        let appointment = self.get_appointment(appointment_id)?;
+        let doctor = self.get_doctor_info(appointment.doctor_id)?;
+        let appointment_type = match appointment.appointment_type_id {
+            Some(type_id) => Some(self.get_appointment_type(type_id)?),
+            None => None,
+        };
+        let tax_rate_bps = appointment_type.as_ref().map_or(0, |t| t.tax_rate_bps);
+
+        let mut lines = Vec::new();
+        if let Some(fee) = doctor.consultation_fee {
+            lines.push(InvoiceLineData {
+                description: format!("Consultation with {}", doctor.name),
+                quantity: 1,
+                unit_price: fee,
+                discount: 0,
+                tax_rate_bps,
+            });
+        }
+        if let Some(AppointmentType {
+            name,
+            fee: Some(fee),
+            ..
+        }) = appointment_type
+        {
+            lines.push(InvoiceLineData {
+                description: name,
+                quantity: 1,
+                unit_price: fee,
+                discount: 0,
+                tax_rate_bps,
+            });
+        }
+
+        let invoice_id = self.con().query_row(
+            "INSERT INTO invoice (appointment, patient, doctor, invoice_status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+            params![
+                appointment_id,
+                appointment.patient_id,
+                appointment.doctor_id,
+                InvoiceStatus::Draft as i32,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?;
+        self.insert_invoice_lines(invoice_id, &lines)
+    }
+
+    fn insert_invoice_lines(
+        &self,
+        invoice_id: i32,
+        lines: &[InvoiceLineData],
+    ) -> Result<(), Error> {
+        for line in lines {
+            self.con().execute(
+                "INSERT INTO invoice_line (invoice, description, quantity, unit_price, discount,
+                    tax_rate_bps)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    invoice_id,
+                    line.description.trim(),
+                    line.quantity,
+                    line.unit_price,
+                    line.discount,
+                    line.tax_rate_bps
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_invoice(&self, invoice_id: i32) -> Result<Invoice, Error> {
+        let invoice = self.con().query_row(
+            &format!("SELECT {} FROM invoice WHERE id = ?1", INVOICE_COLUMNS),
+            params![invoice_id],
+            invoice_from_row,
+        )?;
+        self.with_invoice_lines(invoice)
+    }
+
+    pub fn appointment_invoice(&self, appointment_id: i32) -> Result<Invoice, Error> {
+        let invoice = self.con().query_row(
+            &format!(
+                "SELECT {} FROM invoice WHERE appointment = ?1",
+                INVOICE_COLUMNS
+            ),
+            params![appointment_id],
+            invoice_from_row,
+        )?;
+        self.with_invoice_lines(invoice)
+    }
+
+    /// Invoices matching the filters, newest first.
+    pub fn invoices(
+        &self,
+        status: Option<InvoiceStatus>,
+        patient_id: Option<i32>,
+    ) -> Result<Vec<Invoice>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM invoice
+             WHERE (?1 IS NULL OR invoice_status = ?1) AND (?2 IS NULL OR patient = ?2)
+             ORDER BY created_at DESC, id DESC",
+            INVOICE_COLUMNS
+        ))?;
+        let invoices = stmnt
+            .query_map(
+                params![status.map(|status| status as i32), patient_id],
+                invoice_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        invoices
+            .into_iter()
+            .map(|invoice| self.with_invoice_lines(invoice))
+            .collect()
+    }
+
+    /// The patient's invoices other than drafts, newest first.
+    pub fn patient_invoices(&self, patient_id: i32) -> Result<Vec<Invoice>, Error> {
+        Ok(self
+            .invoices(None, Some(patient_id))?
+            .into_iter()
+            .filter(|invoice| invoice.status != InvoiceStatus::Draft)
+            .collect())
+    }
+
+    /// Loads the invoice's lines and adds up its totals.
+    fn with_invoice_lines(&self, mut invoice: Invoice) -> Result<Invoice, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT description, quantity, unit_price, discount, tax_rate_bps FROM invoice_line
+             WHERE invoice = ?1 ORDER BY id",
+        )?;
+        let lines = stmnt
+            .query_map(params![invoice.id], |row| {
+                Ok(InvoiceLineData {
+                    description: row.get(0)?,
+                    quantity: row.get(1)?,
+                    unit_price: row.get(2)?,
+                    discount: row.get(3)?,
+                    tax_rate_bps: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        invoice.lines = lines
+            .into_iter()
+            .map(InvoiceLine::try_from)
+            .collect::<Result<_, _>>()?;
+        invoice.subtotal = invoice
+            .lines
+            .iter()
+            .map(|l| l.quantity * l.unit_price)
+            .sum();
+        invoice.discount = invoice.lines.iter().map(|l| l.discount).sum();
+        invoice.tax = invoice.lines.iter().map(|l| l.tax).sum();
+        invoice.total = invoice.lines.iter().map(|l| l.total).sum();
+        Ok(invoice)
+    }
+
+    /// Replaces the lines of a draft invoice.
+    pub fn set_invoice_lines(
+        &self,
+        invoice_id: i32,
+        lines: &[InvoiceLineData],
+    ) -> Result<(), Error> {
+        let invoice = self.get_invoice(invoice_id)?;
+        if invoice.status != InvoiceStatus::Draft {
+            return Err(Error::InvoiceStatus(invoice.status));
+        }
+        for line in lines {
+            let description = line.description.trim();
+            if description.is_empty() || description.chars().count() > MAX_INVOICE_LINE_CHARS {
+                return Err(Error::InvalidInvoice(format!(
+                    "description must be 1 to {} characters",
+                    MAX_INVOICE_LINE_CHARS
+                )));
+            }
+            if line.quantity < 1 || line.unit_price < 0 {
+                return Err(Error::InvalidInvoice(
+                    "quantity must be positive and unit price can't be negative".to_string(),
+                ));
+            }
+            let amount = line
+                .quantity
+                .checked_mul(line.unit_price)
+                .ok_or_else(|| Error::InvalidInvoice("line amount is too large".to_string()))?;
+            if !(0..=amount).contains(&line.discount) {
+                return Err(Error::InvalidInvoice(
+                    "discount must be between 0 and the line amount".to_string(),
+                ));
+            }
+            if !(0..=MAX_TAX_RATE_BPS).contains(&line.tax_rate_bps) {
+                return Err(Error::InvalidInvoice(format!(
+                    "tax rate must be between 0 and {} basis points",
+                    MAX_TAX_RATE_BPS
+                )));
+            }
+        }
+        let priced = lines
+            .iter()
+            .cloned()
+            .map(InvoiceLine::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let subtotal = priced.iter().try_fold(0i64, |sum, line| {
+            sum.checked_add(line.quantity * line.unit_price)
+        });
+        let total = priced
+            .iter()
+            .try_fold(0i64, |sum, line| sum.checked_add(line.total));
+        if subtotal.is_none() || total.is_none() {
+            return Err(Error::InvalidInvoice(
+                "invoice total is too large".to_string(),
+            ));
+        }
+
+        self.atomically(|| {
+            self.con().execute(
+                "DELETE FROM invoice_line WHERE invoice = ?1",
+                params![invoice_id],
+            )?;
+            self.insert_invoice_lines(invoice_id, lines)?;
+
+            // Claims that haven't been sent yet bill the edited amount.
+            let total = self.get_invoice(invoice_id)?.total;
+            self.con().execute(
+                "UPDATE claim SET billed_amount = ?1 WHERE invoice = ?2 AND claim_status = ?3",
+                params![total, invoice_id, ClaimStatus::Pending as i32],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Drafts are issued to the patient, who then pays them. Unpaid invoices
+    /// can be voided with a reason.
+    pub fn set_invoice_status(
+        &self,
+        invoice_id: i32,
+        status: InvoiceStatus,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let invoice = self.get_invoice(invoice_id)?;
+        let column = match (invoice.status, status) {
+            (InvoiceStatus::Draft, InvoiceStatus::Issued) => "issued_at",
+            (InvoiceStatus::Issued, InvoiceStatus::Paid) => "paid_at",
+            (InvoiceStatus::Draft | InvoiceStatus::Issued, InvoiceStatus::Void) => "voided_at",
+            (current, _) => return Err(Error::InvoiceStatus(current)),
+        };
+        let reason = reason.map(str::trim).filter(|s| !s.is_empty());
+        if status == InvoiceStatus::Void && reason.is_none() {
+            return Err(Error::InvalidInvoice(
+                "a reason is required to void an invoice".to_string(),
+            ));
+        }
+
+        self.atomically(|| {
+            self.con().execute(
+                &format!(
+                    "UPDATE invoice SET invoice_status = ?1, {} = ?2,
+                        void_reason = coalesce(?3, void_reason)
+                     WHERE id = ?4",
+                    column
+                ),
+                params![
+                    status as i32,
+                    unix_now(),
+                    reason.filter(|_| status == InvoiceStatus::Void),
+                    invoice_id
+                ],
+            )?;
+
+            // A voided invoice has nothing left to bill.
+            if status == InvoiceStatus::Void {
+                self.con().execute(
+                    "UPDATE claim SET claim_status = ?1 WHERE invoice = ?2 AND claim_status = ?3",
+                    params![
+                        ClaimStatus::Cancelled as i32,
+                        invoice_id,
+                        ClaimStatus::Pending as i32
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Records a deposit captured by the payment provider under `reference`.
+    pub fn record_payment(
+        &self,
+        appointment_id: i32,
+        reference: &str,
+        amount: i64,
+    ) -> Result<i32, Error> {
+        Ok(self.con().query_row(
+            "INSERT INTO payment (appointment, reference, amount, refunded, created_at)
+             VALUES (?1, ?2, ?3, 0, ?4) RETURNING id",
+            params![appointment_id, reference, amount, unix_now()],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn appointment_payment(&self, appointment_id: i32) -> Result<Payment, Error> {
+        Ok(self.con().query_row(
+            "SELECT id, appointment, reference, amount, refunded, created_at, refunded_at
+             FROM payment WHERE appointment = ?1",
+            params![appointment_id],
+            |row| {
+                Ok(Payment {
+                    id: row.get(0)?,
+                    appointment_id: row.get(1)?,
+                    reference: row.get(2)?,
+                    amount: row.get(3)?,
+                    refunded: row.get(4)?,
+                    created_at: row.get(5)?,
+                    refunded_at: row.get(6)?,
+                })
+            },
+        )?)
+    }
+
+    pub fn record_refund(&self, payment_id: i32, amount: i64) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE payment SET refunded = refunded + ?1, refunded_at = ?2 WHERE id = ?3",
+            params![amount, unix_now(), payment_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn insurance_plans(&self) -> Result<Vec<InsurancePlan>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM insurance_plan ORDER BY payer_name, plan_name",
+            INSURANCE_PLAN_COLUMNS
+        ))?;
+        let q = stmnt.query_map([], insurance_plan_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_insurance_plan(&self, plan_id: i32) -> Result<InsurancePlan, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM insurance_plan WHERE id = ?1",
+                INSURANCE_PLAN_COLUMNS
+            ),
+            params![plan_id],
+            insurance_plan_from_row,
+        )?)
+    }
+
+    /// Creates a plan, or replaces plan `plan_id`. Fails with
+    /// `QueryReturnedNoRows` if that plan doesn't exist.
+    pub fn save_insurance_plan(
+        &self,
+        plan_id: Option<i32>,
+        data: &InsurancePlanData,
+    ) -> Result<i32, Error> {
+        for (field, value, max) in [
+            ("payer name", &data.payer_name, MAX_PLAN_NAME_CHARS),
+            ("payer id", &data.payer_id, MAX_PAYER_ID_CHARS),
+            ("plan name", &data.plan_name, MAX_PLAN_NAME_CHARS),
+        ] {
+            let value = value.trim();
+            if value.is_empty() || value.chars().count() > max {
+                return Err(Error::InvalidInsurancePlan(format!(
+                    "{} must be 1 to {} characters",
+                    field, max
+                )));
+            }
+        }
+        if data.copay < 0 {
+            return Err(Error::InvalidInsurancePlan(
+                "copay can't be negative".to_string(),
+            ));
+        }
+        for type_id in &data.covered_appointment_types {
+            match self.get_appointment_type(*type_id) {
+                Ok(_) => {}
+                Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                    return Err(Error::UnknownAppointmentType)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let specialties: Vec<&str> = data
+            .covered_specialties
+            .iter()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect();
+        let covered_types = serde_json::to_string(&data.covered_appointment_types).unwrap();
+        let covered_specialties = serde_json::to_string(&specialties).unwrap();
+
+        match plan_id {
+            Some(plan_id) => {
+                let updated = self.con().execute(
+                    "UPDATE insurance_plan SET payer_name = ?1, payer_id = ?2, plan_name = ?3,
+                        covered_types = ?4, covered_specialties = ?5, copay = ?6
+                     WHERE id = ?7",
+                    params![
+                        data.payer_name.trim(),
+                        data.payer_id.trim(),
+                        data.plan_name.trim(),
+                        covered_types,
+                        covered_specialties,
+                        data.copay,
+                        plan_id
+                    ],
+                )?;
+                match updated {
+                    0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+                    _ => Ok(plan_id),
+                }
+            }
+            None => Ok(self.con().query_row(
+                "INSERT INTO insurance_plan (payer_name, payer_id, plan_name, covered_types,
+                    covered_specialties, copay)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+                params![
+                    data.payer_name.trim(),
+                    data.payer_id.trim(),
+                    data.plan_name.trim(),
+                    covered_types,
+                    covered_specialties,
+                    data.copay
+                ],
+                |row| row.get(0),
+            )?),
+        }
+    }
+
+    /// Whether the patient's policy covers a visit with the doctor on `date`.
+    pub fn check_coverage(
+        &self,
+        patient_id: i32,
+        doctor_id: i32,
+        appointment_type_id: Option<i32>,
+        date: NaiveDate,
+    ) -> Result<Coverage, Error> {
+        let insurance = self
+            .get_patient_profile(patient_id)?
+            .insurance
+            .unwrap_or_default();
+        let not_covered = |plan: Option<InsurancePlan>, reason: &str| {
+            Ok(Coverage {
+                covered: false,
+                reason: Some(reason.to_string()),
+                plan,
+                copay: None,
+            })
+        };
+
+        let plan = match insurance.plan_id {
+            Some(plan_id) => self.get_insurance_plan(plan_id)?,
+            None => return not_covered(None, "no insurance plan on file"),
+        };
+        if insurance
+            .policy_number
+            .as_deref()
+            .map(str::trim)
+            .unwrap_or("")
+            .is_empty()
+        {
+            return not_covered(Some(plan), "no policy number on file");
+        }
+        if matches!(insurance.valid_until, Some(until) if until < date) {
+            return not_covered(Some(plan), "the policy has expired");
+        }
+        if !plan.covered_appointment_types.is_empty()
+            && !appointment_type_id.is_some_and(|t| plan.covered_appointment_types.contains(&t))
+        {
+            return not_covered(Some(plan), "the appointment type isn't covered");
+        }
+        let specialty = self.get_doctor_info(doctor_id)?.specialty;
+        if !plan.covered_specialties.is_empty()
+            && !plan
+                .covered_specialties
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(specialty.trim()))
+        {
+            return not_covered(Some(plan), "the doctor's specialty isn't covered");
+        }
+
+        Ok(Coverage {
+            covered: true,
+            reason: None,
+            copay: Some(plan.copay),
+            plan: Some(plan),
+        })
+    }
+
+    /// Opens a claim for a completed appointment the patient's plan covers,
+    /// billing the appointment's invoice. Appointments keep their first claim.
+    fn generate_claim(&self, appointment_id: i32) -> Result<(), Error> {
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM claim WHERE appointment = ?1)",
+            params![appointment_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(());
+        }
+
+        let appointment = self.get_appointment(appointment_id)?;
+        let coverage = self.check_coverage(
+            appointment.patient_id,
+            appointment.doctor_id,
+            appointment.appointment_type_id,
+            appointment.start_date.naive_local().date(),
+        )?;
+        let plan = match coverage.plan {
+            Some(plan) if coverage.covered => plan,
+            _ => return Ok(()),
+        };
+        let insurance = self
+            .get_patient_profile(appointment.patient_id)?
+            .insurance
+            .unwrap_or_default();
+        let invoice = self.appointment_invoice(appointment_id)?;
+
+        self.con().execute(
+            "INSERT INTO claim (appointment, invoice, patient, plan, member_id, group_number,
+                billed_amount, copay, claim_status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                appointment_id,
+                invoice.id,
+                appointment.patient_id,
+                plan.id,
+                insurance.policy_number.as_deref().map(str::trim),
+                insurance.group_number,
+                invoice.total,
+                plan.copay,
+                ClaimStatus::Pending as i32,
+                unix_now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_claim(&self, claim_id: i32) -> Result<InsuranceClaim, Error> {
+        Ok(self.con().query_row(
+            &format!("SELECT {} FROM claim WHERE id = ?1", CLAIM_COLUMNS),
+            params![claim_id],
+            claim_from_row,
+        )?)
+    }
+
+    /// Claims matching the filters, newest first.
+    pub fn claims(
+        &self,
+        status: Option<ClaimStatus>,
+        patient_id: Option<i32>,
+    ) -> Result<Vec<InsuranceClaim>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM claim
+             WHERE (?1 IS NULL OR claim_status = ?1) AND (?2 IS NULL OR patient = ?2)
+             ORDER BY created_at DESC, id DESC",
+            CLAIM_COLUMNS
+        ))?;
+        let q = stmnt.query_map(
+            params![status.map(|status| status as i32), patient_id],
+            claim_from_row,
+        )?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Writes every pending claim to a new batch file and marks them
+    /// submitted. `None` when nothing is pending. Concurrent exports wait for
+    /// each other, so a claim is only ever sent in one batch.
+    pub fn export_claims(&self) -> Result<Option<ClaimBatch>, Error> {
+        let tx = self.immediate_transaction()?;
+        let mut pending = self.claims(Some(ClaimStatus::Pending), None)?;
+        if pending.is_empty() {
+            return Ok(None);
+        }
+        pending.reverse();
+        let records = pending
+            .iter()
+            .map(|claim| self.claim_record(claim))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let created_at = unix_now();
+        let batch_id: i32 = self.con().query_row(
+            "INSERT INTO claim_batch (created_at, claim_count, contents)
+             VALUES (?1, ?2, '') RETURNING id",
+            params![created_at, pending.len()],
+            |row| row.get(0),
+        )?;
+        let contents = x12::claim_batch(
+            batch_id,
+            NaiveDateTime::from_timestamp(created_at, 0),
+            &records,
+        );
+        self.con().execute(
+            "UPDATE claim_batch SET contents = ?1 WHERE id = ?2",
+            params![contents, batch_id],
+        )?;
+        for claim in &pending {
+            let submitted = self.con().execute(
+                "UPDATE claim SET claim_status = ?1, batch = ?2, submitted_at = ?3
+                 WHERE id = ?4 AND claim_status = ?5",
+                params![
+                    ClaimStatus::Submitted as i32,
+                    batch_id,
+                    created_at,
+                    claim.id,
+                    ClaimStatus::Pending as i32
+                ],
+            )?;
+            if submitted != 1 {
+                return Err(Error::ClaimStatus(self.get_claim(claim.id)?.status));
+            }
+        }
+
+        let batch = self.get_claim_batch(batch_id)?;
+        tx.commit()?;
+        Ok(Some(batch))
+    }
+
+    /// What the batch file needs to know about a claim.
+    fn claim_record(&self, claim: &InsuranceClaim) -> Result<x12::ClaimRecord, Error> {
+        let appointment = self.get_appointment(claim.appointment_id)?;
+        let plan = self.get_insurance_plan(claim.plan_id)?;
+        let diagnosis_codes = self
+            .visit_notes(claim.appointment_id)?
+            .pop()
+            .map(|note| note.diagnosis_codes)
+            .unwrap_or_default();
+        Ok(x12::ClaimRecord {
+            claim_id: claim.id,
+            patient_name: self.get_patient_profile(claim.patient_id)?.name,
+            member_id: claim.member_id.clone(),
+            group_number: claim.group_number.clone(),
+            payer_name: plan.payer_name,
+            payer_id: plan.payer_id,
+            doctor_name: self.get_doctor_info(appointment.doctor_id)?.name,
+            service_date: appointment.start_date.naive_local().date(),
+            billed_amount: claim.billed_amount,
+            diagnosis_codes,
+        })
+    }
+
+    pub fn get_claim_batch(&self, batch_id: i32) -> Result<ClaimBatch, Error> {
+        Ok(self.con().query_row(
+            "SELECT id, created_at, claim_count, contents FROM claim_batch WHERE id = ?1",
+            params![batch_id],
+            |row| {
+                Ok(ClaimBatch {
+                    id: row.get(0)?,
+                    created_at: row.get(1)?,
+                    claim_count: row.get(2)?,
+                    contents: row.get(3)?,
+                })
+            },
+        )?)
+    }
+
+    /// Records the payer's answer to a submitted claim: approved claims are
+    /// later paid, and denials need a reason.
+    pub fn set_claim_status(
+        &self,
+        claim_id: i32,
+        status: ClaimStatus,
+        paid_amount: Option<i64>,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let claim = self.get_claim(claim_id)?;
+        let column = match (claim.status, status) {
+            (ClaimStatus::Submitted, ClaimStatus::Approved)
+            | (ClaimStatus::Submitted | ClaimStatus::Approved, ClaimStatus::Denied) => "decided_at",
+            (ClaimStatus::Approved, ClaimStatus::Paid) => "paid_at",
+            (current, _) => return Err(Error::ClaimStatus(current)),
+        };
+        let reason = reason.map(str::trim).filter(|s| !s.is_empty());
+        if status == ClaimStatus::Denied && reason.is_none() {
+            return Err(Error::InvalidClaim(
+                "a reason is required to deny a claim".to_string(),
+            ));
+        }
+        if status == ClaimStatus::Paid
+            && !matches!(paid_amount, Some(amount) if (0..=claim.billed_amount).contains(&amount))
+        {
+            return Err(Error::InvalidClaim(
+                "the paid amount must be between 0 and the billed amount".to_string(),
+            ));
+        }
+
+        self.con().execute(
+            &format!(
+                "UPDATE claim SET claim_status = ?1, {} = ?2,
+                    denial_reason = coalesce(?3, denial_reason),
+                    paid_amount = coalesce(?4, paid_amount)
+                 WHERE id = ?5",
+                column
+            ),
+            params![
+                status as i32,
+                unix_now(),
+                reason.filter(|_| status == ClaimStatus::Denied),
+                paid_amount.filter(|_| status == ClaimStatus::Paid),
+                claim_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Publishes the next version of the document with `data.code`. Patients
+    /// have to accept the new version for it to count.
+    pub fn publish_consent_document(
+        &self,
+        data: &ConsentDocumentData,
+        author: i32,
+    ) -> Result<i32, Error> {
+        let code = data.code.trim();
+        if code.is_empty()
+            || code.len() > MAX_CONSENT_CODE_CHARS
+            || !code
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+        {
+            return Err(Error::InvalidConsent(format!(
+                "code must be 1 to {} lowercase letters, digits or underscores",
+                MAX_CONSENT_CODE_CHARS
+            )));
+        }
+        let title = data.title.trim();
+        if title.is_empty() || title.chars().count() > MAX_SUBJECT_CHARS {
+            return Err(Error::InvalidConsent(format!(
+                "title must be 1 to {} characters",
+                MAX_SUBJECT_CHARS
+            )));
+        }
+        if data.body.trim().is_empty() {
+            return Err(Error::InvalidConsent("body is required".to_string()));
+        }
+
+        let previous: Option<(i32, i32)> = match self.con().query_row(
+            "SELECT version, purpose FROM consent_document WHERE code = ?1
+             ORDER BY version DESC LIMIT 1",
+            params![code],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(previous) => Some(previous),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if matches!(previous, Some((_, purpose)) if purpose != data.purpose as i32) {
+            return Err(Error::InvalidConsent(
+                "a document keeps its purpose across versions".to_string(),
+            ));
+        }
+
+        Ok(self.con().query_row(
+            "INSERT INTO consent_document (code, version, title, body, purpose, created_by,
+                created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
+            params![
+                code,
+                previous.map_or(1, |(version, _)| version + 1),
+                title,
+                data.body.trim(),
+                data.purpose as i32,
+                author,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// The latest version of every consent document.
+    pub fn consent_documents(&self) -> Result<Vec<ConsentDocument>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM consent_document
+             WHERE version = (SELECT max(version) FROM consent_document AS latest
+                WHERE latest.code = consent_document.code)
+             ORDER BY code",
+            CONSENT_DOCUMENT_COLUMNS
+        ))?;
+        let q = stmnt.query_map([], consent_document_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_consent_document(&self, document_id: i32) -> Result<ConsentDocument, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM consent_document WHERE id = ?1",
+                CONSENT_DOCUMENT_COLUMNS
+            ),
+            params![document_id],
+            consent_document_from_row,
+        )?)
+    }
+
+    /// Records the patient's acceptance of the latest version of a document,
+    /// given by the patient or their guardian. Accepting again keeps the
+    /// existing acceptance.
+    pub fn accept_consent(
+        &self,
+        patient_id: i32,
+        document_id: i32,
+        actor: i32,
+    ) -> Result<i32, Error> {
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM patient WHERE id = ?1)",
+            params![patient_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        let document = match self.get_consent_document(document_id) {
+            Ok(document) => document,
+            Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                return Err(Error::InvalidConsent(
+                    "consent document not found".to_string(),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        if !self
+            .consent_documents()?
+            .iter()
+            .any(|current| current.id == document.id)
+        {
+            return Err(Error::InvalidConsent(format!(
+                "a newer version of {} has been published",
+                document.code
+            )));
+        }
+
+        match self.con().query_row(
+            "SELECT id FROM consent
+             WHERE patient = ?1 AND document = ?2 AND withdrawn_at IS NULL",
+            params![patient_id, document_id],
+            |row| row.get(0),
+        ) {
+            Ok(consent_id) => return Ok(consent_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(self.con().query_row(
+            "INSERT INTO consent (patient, document, accepted_by, accepted_at)
+             VALUES (?1, ?2, ?3, ?4) RETURNING id",
+            params![patient_id, document_id, actor, unix_now()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Withdraws every standing acceptance of any version of the document.
+    /// Fails with `QueryReturnedNoRows` if there is none.
+    pub fn withdraw_consent(&self, patient_id: i32, code: &str, actor: i32) -> Result<(), Error> {
+        let withdrawn = self.con().execute(
+            "UPDATE consent SET withdrawn_by = ?1, withdrawn_at = ?2
+             WHERE patient = ?3 AND withdrawn_at IS NULL
+                AND document IN (SELECT id FROM consent_document WHERE code = ?4)",
+            params![actor, unix_now(), patient_id, code],
+        )?;
+        match withdrawn {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every acceptance the patient has given, withdrawn ones included,
+    /// newest first.
+    pub fn patient_consents(&self, patient_id: i32) -> Result<Vec<Consent>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT consent.id, consent.patient, consent.document, consent_document.code,
+                consent_document.version, consent_document.title, consent_document.purpose,
+                consent_document.version = (SELECT max(version) FROM consent_document AS latest
+                    WHERE latest.code = consent_document.code),
+                consent.accepted_by, consent.accepted_at, consent.withdrawn_by,
+                consent.withdrawn_at
+             FROM consent JOIN consent_document ON consent_document.id = consent.document
+             WHERE consent.patient = ?1
+             ORDER BY consent.accepted_at DESC, consent.id DESC",
+        )?;
+        let q = stmnt.query_map(params![patient_id], |row| {
+            Ok(Consent {
+                id: row.get(0)?,
+                patient_id: row.get(1)?,
+                document_id: row.get(2)?,
+                code: row.get(3)?,
+                version: row.get(4)?,
+                title: row.get(5)?,
+                purpose: ConsentPurpose::try_from(row.get::<_, i32>(6)?).unwrap(),
+                current: row.get(7)?,
+                accepted_by: row.get(8)?,
+                accepted_at: row.get(9)?,
+                withdrawn_by: row.get(10)?,
+                withdrawn_at: row.get(11)?,
+            })
+        })?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Latest document versions for `purpose` the patient hasn't accepted,
+    /// or has withdrawn from.
+    pub fn missing_consents(
+        &self,
+        patient_id: i32,
+        purpose: ConsentPurpose,
+    ) -> Result<Vec<ConsentDocument>, Error> {
+        let accepted: HashSet<i32> = self
+            .patient_consents(patient_id)?
+            .into_iter()
+            .filter(|consent| consent.withdrawn_at.is_none())
+            .map(|consent| consent.document_id)
+            .collect();
+        Ok(self
+            .consent_documents()?
+            .into_iter()
+            .filter(|document| document.purpose == purpose && !accepted.contains(&document.id))
+            .collect())
+    }
+
+    pub fn doctors_stats(
+        &self,
+        day: Date<FixedOffset>,
+    ) -> Result<Vec<DoctorAppointmentStats>, Error> {
+        let mut stmt = self.con().prepare(
+            "
+            SELECT account.id, sum(appointment.duration_mins) as s, count(appointment.doctor) as c 
+            FROM account 
+            LEFT JOIN appointment
+            ON appointment.doctor = account.id 
+            GROUP BY account.id
+            HAVING account_type=?1 
+            AND account.deactivated_at IS NULL
+            AND date(starting_date) = ?2
+            AND appointment_status != ?3
+            ",
+        )?;
+        let q = stmt.query_map(
+            params![
+                AccountType::Doctor as i32,
+                day.format("%Y-%m-%d").to_string(),
+                AppointmentStatus::Cancelled as i32
+            ],
+            |row| {
+                Ok(DoctorAppointmentStats {
+                    doctor_id: row.get(0)?,
+                    booked_mins: row.get(1)?,
+                    appointments_count: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn register(&self, data: RegisterData) -> Result<(), Error> {
+        let id = {
+            let mut stmnt = self.con().prepare(
+                "INSERT INTO account (fullname, email, passhash, account_type) VALUES (?1, ?2, ?3, ?4) RETURNING id",
+            )?;
+
+            let passhash = password_hash(data.password.as_bytes())?;
+
+            stmnt.query_row(
+                params![data.name, data.email, passhash, data.account_type as i32],
+                |r| r.get(0),
+            )?
+        };
+
+        self.register_account_type(data.account_type, id)?;
+
+        Ok(())
+    }
+
+    fn register_account_type(&self, account_type: AccountType, id: i32) -> Result<(), Error> {
+        let sql = match account_type {
+            AccountType::Doctor => "INSERT INTO doctor(id) values (?1)",
+            AccountType::Patient => "INSERT INTO patient(id) values (?1)",
+            AccountType::Admin => "INSERT INTO admin(id) values (?1)",
+            AccountType::Receptionist => "INSERT INTO receptionist(id) values (?1)",
+        };
+
+        self.con().execute(sql, params![id])?;
+        Ok(())
+    }
+
+    /// Creates a patient profile without a login, managed by `guardian`.
+    pub fn create_dependent(&self, guardian: i32, data: DependentData) -> Result<i32, Error> {
+        // `account.email` is required and unique, so dependents get a
+        // placeholder address that can never receive mail or log in.
+        let email = format!("dependent-{}@dependents.invalid", random_token(8));
+
+        let id: i32 = self.con().query_row(
+            "INSERT INTO account (fullname, email, passhash, account_type)
+             VALUES (?1, ?2, '', ?3) RETURNING id",
+            params![data.name, email, AccountType::Patient as i32],
+            |row| row.get(0),
+        )?;
+
+        self.register_account_type(AccountType::Patient, id)?;
+        self.con().execute(
+            "INSERT INTO guardian (guardian, dependent, relationship) VALUES (?1, ?2, ?3)",
+            params![guardian, id, data.relationship],
+        )?;
+
+        Ok(id)
+    }
+
+    pub fn dependents(&self, guardian: i32) -> Result<Vec<Dependent>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT account.id, account.fullname, guardian.relationship FROM guardian
+             JOIN account ON account.id = guardian.dependent
+             WHERE guardian.guardian = ?1",
+        )?;
+
+        let q = stmnt.query_map(params![guardian], |row| {
+            Ok(Dependent {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                relationship: row.get(2)?,
+            })
+        })?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn guardians(&self, patient_id: i32) -> Result<Vec<i32>, Error> {
+        let mut stmnt = self
+            .con()
+            .prepare("SELECT guardian FROM guardian WHERE dependent = ?1")?;
+
+        let q = stmnt.query_map(params![patient_id], |row| row.get(0))?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Dependents have no login of their own, so once the last guardian lets
+    /// go the profile is deactivated; its records are kept.
+    pub fn remove_dependent(&self, guardian: i32, dependent: i32) -> Result<(), Error> {
+        let removed = self.con().execute(
+            "DELETE FROM guardian WHERE guardian = ?1 AND dependent = ?2",
+            params![guardian, dependent],
+        )?;
+        if removed == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+
+        if self.guardians(dependent)?.is_empty() {
+            self.deactivate_account(dependent, guardian)?;
+        }
+        Ok(())
+    }
+
+    /// Accounts that act for the patient: the patient and their guardians.
+    pub fn patient_owners(&self, patient_id: i32) -> Result<Vec<i32>, Error> {
+        let mut owners = vec![patient_id];
+        owners.extend(self.guardians(patient_id)?);
+        Ok(owners)
+    }
+
+    /// Accounts that own something between a doctor and a patient: the
+    /// doctor, the patient and the patient's guardians.
+    pub fn care_owners(&self, doctor_id: i32, patient_id: i32) -> Result<Vec<i32>, Error> {
+        let mut owners = vec![doctor_id];
+        owners.extend(self.patient_owners(patient_id)?);
+        Ok(owners)
+    }
+
+    pub fn appointment_owners(&self, appointment: &Appointment) -> Result<Vec<i32>, Error> {
+        self.care_owners(appointment.doctor_id, appointment.patient_id)
+    }
+
+    pub fn login(
+        &self,
+        data: LoginData,
+        client: &ClientInfo,
+        policy: &LoginPolicy,
+    ) -> Result<LoginOutcome, Error> {
+        let now = unix_now();
+        let ip = client.ip.as_deref();
+        self.check_login_throttle(&data.email, ip, now, policy)?;
+
+        let claims = match self.verify_login(&data) {
+            Ok(claims) => claims,
+            Err(Error::InvalidPassword)
+            | Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                self.record_login_failure(ThrottleScope::Email, &data.email, now, policy)?;
+                if let Some(ip) = ip {
+                    self.record_login_failure(ThrottleScope::Ip, ip, now, policy)?;
+                }
+                return Err(Error::InvalidPassword);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if policy.disable_sso_password_login && self.sso_linked(claims.sub)? {
+            return Err(Error::PasswordLoginDisabled);
+        }
+
+        self.clear_login_throttle(ThrottleScope::Email, &data.email)?;
+        if let Some(ip) = ip {
+            self.clear_login_throttle(ThrottleScope::Ip, ip)?;
+        }
+
+        let purpose = if self.totp_enabled(claims.sub)? {
+            ChallengePurpose::Totp
+        } else if policy.totp_required_roles.contains(&claims.account_type) {
+            ChallengePurpose::TotpEnrollment
+        } else {
+            return Ok(LoginOutcome::Authenticated(
+                self.issue_token(claims, client)?,
+            ));
+        };
+
+        let challenge = encode_challenge(&ChallengeClaims {
+            sub: claims.sub,
+            account_type: claims.account_type,
+            purpose,
+            exp: now + policy.totp_challenge_secs,
+        })?;
+
+        Ok(match purpose {
+            ChallengePurpose::Totp => LoginOutcome::TotpRequired(challenge),
+            ChallengePurpose::TotpEnrollment => LoginOutcome::TotpEnrollmentRequired(challenge),
+        })
+    }
+
+    /// Second login step: exchanges a `TotpRequired` challenge and a TOTP or
+    /// recovery code for the real JWT.
+    pub fn login_totp(
+        &self,
+        challenge: &str,
+        code: &str,
+        client: &ClientInfo,
+        policy: &LoginPolicy,
+    ) -> Result<JWT, Error> {
+        let challenge = decode_challenge(challenge)?;
+        if challenge.purpose != ChallengePurpose::Totp {
+            return Err(Error::InvalidTotpCode);
+        }
+
+        if !self.account_active(challenge.sub)? {
+            return Err(Error::AccountDeactivated);
+        }
+
+        let now = unix_now();
+        let email = self.account_email(challenge.sub)?;
+        let ip = client.ip.as_deref();
+        self.check_login_throttle(&email, ip, now, policy)?;
+
+        if !self.verify_totp(challenge.sub, code, now)? {
+            self.record_login_failure(ThrottleScope::Email, &email, now, policy)?;
+            if let Some(ip) = ip {
+                self.record_login_failure(ThrottleScope::Ip, ip, now, policy)?;
+            }
+            return Err(Error::InvalidTotpCode);
+        }
+        self.clear_login_throttle(ThrottleScope::Email, &email)?;
+        if let Some(ip) = ip {
+            self.clear_login_throttle(ThrottleScope::Ip, ip)?;
+        }
+
+        self.issue_token(Claims::new(challenge.sub, challenge.account_type), client)
+    }
+
+    /// Records a session for the login and returns its JWT. Requests are
+    /// only accepted while the session hasn't been revoked.
+    fn issue_token(&self, mut claims: Claims, client: &ClientInfo) -> Result<JWT, Error> {
+        let now = unix_now();
+        let session_id: i32 = self.con().query_row(
+            "INSERT INTO session (account, user_agent, ip, created_at, last_seen_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?4, ?5) RETURNING id",
+            params![
+                claims.sub,
+                client.user_agent,
+                client.ip,
+                now,
+                claims.exp as i64
+            ],
+            |row| row.get(0),
+        )?;
+
+        claims.sid = Some(session_id);
+        encode_jwt(&claims)
+    }
+
+    /// Starts an SSO login, returning the `state` and `nonce` to send to the
+    /// identity provider.
+    pub fn begin_oidc_login(&self) -> Result<(String, String), Error> {
+        let state = random_token(32);
+        let nonce = random_token(32);
+        self.con().execute(
+            "INSERT INTO oidc_state (state, nonce, created_at) VALUES (?1, ?2, ?3)",
+            params![state, nonce, unix_now()],
+        )?;
+        Ok((state, nonce))
+    }
+
+    /// Consumes a `state` from `begin_oidc_login` and returns its nonce, or
+    /// `None` if it's unknown or older than `OIDC_STATE_SECS`.
+    pub fn take_oidc_state(&self, state: &str) -> Result<Option<String>, Error> {
+        self.con().execute(
+            "DELETE FROM oidc_state WHERE created_at <= ?1",
+            params![unix_now() - OIDC_STATE_SECS],
+        )?;
+        match self.con().query_row(
+            "DELETE FROM oidc_state WHERE state = ?1 RETURNING nonce",
+            params![state],
+            |row| row.get(0),
+        ) {
+            Ok(nonce) => Ok(Some(nonce)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Logs a staff member in with a verified IdP identity. On first use the
+    /// identity is linked to the doctor or admin account with the same email,
+    /// so `email` must only be given when the provider verified it.
+    pub fn login_sso(
+        &self,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+        client: &ClientInfo,
+    ) -> Result<JWT, Error> {
+        let linked = self.con().query_row(
+            "SELECT account.id, account.account_type
+             FROM oidc_identity JOIN account ON account.id = oidc_identity.account
+             WHERE oidc_identity.issuer = ?1 AND oidc_identity.subject = ?2",
+            params![issuer, subject],
+            |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?)),
+        );
+
+        let (account_id, account_type) = match linked {
+            Ok(account) => account,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let email = email.ok_or(Error::SsoAccountNotFound)?;
+                let (account_id, account_type) = match self.con().query_row(
+                    "SELECT id, account_type FROM account WHERE email = ?1",
+                    params![email],
+                    |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?)),
+                ) {
+                    Ok(account) => account,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        return Err(Error::SsoAccountNotFound)
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                match AccountType::try_from(account_type) {
+                    Ok(AccountType::Doctor) | Ok(AccountType::Admin) => {}
+                    _ => return Err(Error::SsoAccountNotFound),
+                }
+
+                self.con().execute(
+                    "INSERT INTO oidc_identity (issuer, subject, account, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![issuer, subject, account_id, unix_now()],
+                )?;
+                (account_id, account_type)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if !self.account_active(account_id)? {
+            return Err(Error::AccountDeactivated);
+        }
+
+        self.issue_token(
+            Claims::new(account_id, AccountType::try_from(account_type).unwrap()),
+            client,
+        )
+    }
+
+    pub fn sso_linked(&self, account_id: i32) -> Result<bool, Error> {
+        Ok(self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM oidc_identity WHERE account = ?1)",
+            params![account_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Marks the session as seen. Returns false if it was revoked or expired.
+    pub fn touch_session(&self, session_id: i32, now: i64) -> Result<bool, Error> {
+        let updated = self.con().execute(
+            "UPDATE session SET last_seen_at = ?1
+             WHERE id = ?2 AND revoked_at IS NULL AND expires_at > ?1",
+            params![now, session_id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub fn sessions(&self, account_id: i32) -> Result<Vec<Session>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT id, user_agent, ip, created_at, last_seen_at FROM session
+             WHERE account = ?1 AND revoked_at IS NULL AND expires_at > ?2
+             ORDER BY last_seen_at DESC",
+        )?;
+
+        let q = stmnt.query_map(params![account_id, unix_now()], |row| {
+            Ok(Session {
+                id: row.get(0)?,
+                user_agent: row.get(1)?,
+                ip: row.get(2)?,
+                created_at: row.get(3)?,
+                last_seen_at: row.get(4)?,
+            })
+        })?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Fails with `QueryReturnedNoRows` unless the session is the account's
+    /// and still active.
+    pub fn revoke_session(&self, account_id: i32, session_id: i32) -> Result<(), Error> {
+        let revoked = self.con().execute(
+            "UPDATE session SET revoked_at = ?1
+             WHERE id = ?2 AND account = ?3 AND revoked_at IS NULL",
+            params![unix_now(), session_id, account_id],
+        )?;
+        if revoked == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        Ok(())
+    }
+
+    /// Signs the account out everywhere.
+    pub fn revoke_sessions(&self, account_id: i32) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE session SET revoked_at = ?1 WHERE account = ?2 AND revoked_at IS NULL",
+            params![unix_now(), account_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn account_active(&self, account_id: i32) -> Result<bool, Error> {
+        Ok(self.con().query_row(
+            "SELECT deactivated_at IS NULL FROM account WHERE id = ?1",
+            params![account_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Whether the account is a patient that hasn't been deactivated.
+    fn patient_active(&self, patient_id: i32) -> Result<bool, Error> {
+        Ok(self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM patient JOIN account ON account.id = patient.id
+             WHERE patient.id = ?1 AND account.deactivated_at IS NULL)",
+            params![patient_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn account_profile(&self, account_id: i32) -> Result<AccountProfile, Error> {
+        Ok(self.con().query_row(
+            "SELECT id, fullname, email, account_type, deactivated_at FROM account WHERE id = ?1",
+            params![account_id],
+            |row| {
+                Ok(AccountProfile {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    email: row.get(2)?,
+                    account_type: AccountType::try_from(row.get::<_, i32>(3)?).unwrap(),
+                    deactivated_at: row.get(4)?,
+                })
+            },
+        )?)
+    }
+
+    /// Applies a self-service profile update. Changing the email or password
+    /// needs the current password, and a password change signs out every
+    /// session except `current_session`.
+    pub fn update_profile(
+        &self,
+        account_id: i32,
+        update: &ProfileUpdate,
+        current_session: Option<i32>,
+    ) -> Result<(), Error> {
+        if update.email.is_some() || update.password.is_some() {
+            let passhash: String = self.con().query_row(
+                "SELECT passhash FROM account WHERE id = ?1",
+                params![account_id],
+                |row| row.get(0),
+            )?;
+            let current = update.current_password.as_deref().unwrap_or("");
+            if passhash.is_empty() || !verify_password(&passhash, current.as_bytes())? {
+                return Err(Error::InvalidPassword);
+            }
+        }
+
+        let name = update.name.as_deref().map(str::trim);
+        let email = update.email.as_deref().map(str::trim);
+        if name == Some("") {
+            return Err(Error::InvalidProfile("name can't be empty".to_string()));
+        }
+        if matches!(email, Some(email) if !email.contains('@')) {
+            return Err(Error::InvalidProfile("invalid email".to_string()));
+        }
+        if update.password.as_deref() == Some("") {
+            return Err(Error::InvalidProfile("password can't be empty".to_string()));
+        }
+
+        if let Some(email) = email {
+            let taken: bool = self.con().query_row(
+                "SELECT EXISTS(SELECT 1 FROM account WHERE email = ?1 AND id != ?2)",
+                params![email, account_id],
+                |row| row.get(0),
+            )?;
+            if taken {
+                return Err(Error::EmailTaken);
+            }
+            self.con().execute(
+                "UPDATE account SET email = ?1 WHERE id = ?2",
+                params![email, account_id],
+            )?;
+        }
+
+        if let Some(name) = name {
+            self.con().execute(
+                "UPDATE account SET fullname = ?1 WHERE id = ?2",
+                params![name, account_id],
+            )?;
+        }
+
+        if let Some(password) = &update.password {
+            self.con().execute(
+                "UPDATE account SET passhash = ?1 WHERE id = ?2",
+                params![password_hash(password.as_bytes())?, account_id],
+            )?;
+            self.con().execute(
+                "UPDATE session SET revoked_at = ?1
+                 WHERE account = ?2 AND revoked_at IS NULL AND id IS NOT ?3",
+                params![unix_now(), account_id, current_session],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Disables the account and signs it out everywhere. A doctor's upcoming
+    /// appointments are marked `NeedsRebooking`; their ids are returned.
+    pub fn deactivate_account(&self, account_id: i32, actor: i32) -> Result<Vec<i32>, Error> {
+        self.con().execute(
+            "UPDATE account SET deactivated_at = ?1 WHERE id = ?2 AND deactivated_at IS NULL",
+            params![unix_now(), account_id],
+        )?;
+        self.revoke_sessions(account_id)?;
+
+        let mut stmnt = self.con().prepare(
+            "UPDATE appointment SET appointment_status = ?1
+             WHERE doctor = ?2 AND appointment_status IN (?3, ?4)
+             AND datetime(starting_date) > datetime('now')
+             RETURNING id",
+        )?;
+        let flagged = stmnt
+            .query_map(
+                params![
+                    AppointmentStatus::NeedsRebooking as i32,
+                    account_id,
+                    AppointmentStatus::Booked as i32,
+                    AppointmentStatus::CheckedIn as i32
+                ],
+                |row| row.get(0),
+            )?
+            .collect::<Result<Vec<i32>, _>>()?;
+
+        for appointment_id in &flagged {
+            self.reopen_referral(*appointment_id)?;
+            self.record_appointment_action(
+                *appointment_id,
+                actor,
+                AppointmentAction::FlaggedForRebooking,
+            )?;
+        }
+
+        Ok(flagged)
+    }
+
+    /// Re-enables the account. Appointments flagged for rebooking stay flagged.
+    pub fn reactivate_account(&self, account_id: i32) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE account SET deactivated_at = NULL WHERE id = ?1",
+            params![account_id],
+        )?;
+        Ok(())
+    }
+
+    fn account_email(&self, account_id: i32) -> Result<String, Error> {
+        Ok(self.con().query_row(
+            "SELECT email FROM account WHERE id = ?1",
+            params![account_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn totp_enabled(&self, account_id: i32) -> Result<bool, Error> {
+        Ok(self.con().query_row(
+            "SELECT count(*) FROM totp WHERE account = ?1 AND enabled = 1",
+            params![account_id],
+            |row| row.get::<_, i32>(0),
+        )? > 0)
+    }
+
+    /// Stores a fresh, not yet enabled secret. 2FA only takes effect once
+    /// `confirm_totp_enrollment` has seen a valid code for it.
+    pub fn begin_totp_enrollment(&self, account_id: i32) -> Result<TotpEnrollment, Error> {
+        if self.totp_enabled(account_id)? {
+            return Err(Error::TotpAlreadyEnabled);
+        }
+
+        let secret = totp::generate_secret();
+        self.con().execute(
+            "INSERT OR REPLACE INTO totp (account, secret, enabled) VALUES (?1, ?2, 0)",
+            params![account_id, secret],
+        )?;
+
+        let email = self.account_email(account_id)?;
+        Ok(TotpEnrollment {
+            provisioning_uri: totp::provisioning_uri(&secret, &email, TOTP_ISSUER),
+            secret,
+        })
+    }
+
+    pub fn confirm_totp_enrollment(
+        &self,
+        account_id: i32,
+        code: &str,
+    ) -> Result<Vec<String>, Error> {
+        if self.totp_enabled(account_id)? {
+            return Err(Error::TotpAlreadyEnabled);
+        }
+
+        let secret: String = match self.con().query_row(
+            "SELECT secret FROM totp WHERE account = ?1",
+            params![account_id],
+            |row| row.get(0),
+        ) {
+            Ok(secret) => secret,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::TotpNotEnrolled),
+            Err(e) => return Err(e.into()),
+        };
+
+        let step = totp::verify(&secret, code, unix_now()).ok_or(Error::InvalidTotpCode)?;
+        self.con().execute(
+            "UPDATE totp SET enabled = 1, last_used_step = ?1 WHERE account = ?2",
+            params![step, account_id],
+        )?;
+
+        self.reset_recovery_codes(account_id)
+    }
+
+    /// Replaces all recovery codes, returning the new plaintext codes. Only
+    /// their hashes are stored.
+    pub fn reset_recovery_codes(&self, account_id: i32) -> Result<Vec<String>, Error> {
+        self.con().execute(
+            "DELETE FROM recovery_code WHERE account = ?1",
+            params![account_id],
+        )?;
+
+        let codes = totp::generate_recovery_codes();
+        let mut stmnt = self
+            .con()
+            .prepare("INSERT INTO recovery_code (account, code_hash) VALUES (?1, ?2)")?;
+        for code in codes.iter() {
+            stmnt.execute(params![account_id, token_hash(code)])?;
+        }
+
+        Ok(codes)
+    }
+
+    /// Accepts either a current TOTP code or an unused recovery code. TOTP
+    /// codes can't be replayed within their validity window.
+    pub fn verify_totp(&self, account_id: i32, code: &str, now: i64) -> Result<bool, Error> {
+        let (secret, last_used_step): (String, i64) = match self.con().query_row(
+            "SELECT secret, last_used_step FROM totp WHERE account = ?1 AND enabled = 1",
+            params![account_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(r) => r,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::TotpNotEnrolled),
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(step) = totp::verify(&secret, code, now) {
+            if step <= last_used_step {
+                return Ok(false);
+            }
+            self.con().execute(
+                "UPDATE totp SET last_used_step = ?1 WHERE account = ?2",
+                params![step, account_id],
+            )?;
+            return Ok(true);
+        }
+
+        let used = self.con().execute(
+            "UPDATE recovery_code SET used_at = ?1
+             WHERE account = ?2 AND code_hash = ?3 AND used_at IS NULL",
+            params![now, account_id, token_hash(code.trim())],
+        )?;
+
+        Ok(used > 0)
+    }
+
+    /// Turns 2FA off, e.g. after losing the authenticator. Needs the account
+    /// password or a current TOTP or unused recovery code.
+    pub fn disable_totp(
+        &self,
+        account_id: i32,
+        password: Option<&str>,
+        code: Option<&str>,
+    ) -> Result<(), Error> {
+        if !self.totp_enabled(account_id)? {
+            return Err(Error::TotpNotEnrolled);
+        }
+        match (password, code) {
+            (Some(password), _) => {
+                let passhash: String = self.con().query_row(
+                    "SELECT passhash FROM account WHERE id = ?1",
+                    params![account_id],
+                    |row| row.get(0),
+                )?;
+                if passhash.is_empty() || !verify_password(&passhash, password.as_bytes())? {
+                    return Err(Error::InvalidPassword);
+                }
+            }
+            (None, Some(code)) => {
+                if !self.verify_totp(account_id, code, unix_now())? {
+                    return Err(Error::InvalidTotpCode);
+                }
+            }
+            (None, None) => return Err(Error::InvalidPassword),
+        }
+
+        self.con().execute(
+            "DELETE FROM recovery_code WHERE account = ?1",
+            params![account_id],
+        )?;
+        self.con()
+            .execute("DELETE FROM totp WHERE account = ?1", params![account_id])?;
+        Ok(())
+    }
+
+    fn verify_login(&self, data: &LoginData) -> Result<Claims, Error> {
+        let mut stmt = self.con().prepare(
+            "SELECT id, account_type, passhash, deactivated_at
+        FROM account 
+        WHERE account.email=?1
+        ",
+        )?;
+
+        let claims: Claims = stmt.query_row(params![data.email], |row: &Row| {
+            let password_hash: String = row.get(2)?;
+
+            // Dependent profiles have no password and can't log in.
+            if password_hash.is_empty() {
+                return Ok(Err(Error::InvalidPassword));
+            }
+
+            match verify_password(&password_hash, data.password.as_bytes()) {
+                Ok(false) => return Ok(Err(Error::InvalidPassword)),
+                Err(e) => return Ok(Err(e)),
+                Ok(true) => {}
+            }
+
+            if row.get::<_, Option<i64>>(3)?.is_some() {
+                return Ok(Err(Error::AccountDeactivated));
+            }
+
+            Ok(Ok(Claims::new(
+                row.get(0)?,
+                AccountType::try_from(row.get::<_, i32>(1)?).unwrap(),
+            )))
+        })??;
+
+        Ok(claims)
+    }
+
+    pub fn check_login_throttle(
+        &self,
+        email: &str,
+        ip: Option<&str>,
+        now: i64,
+        policy: &LoginPolicy,
+    ) -> Result<(), Error> {
+        let keys = std::iter::once((ThrottleScope::Email, email))
+            .chain(ip.map(|ip| (ThrottleScope::Ip, ip)));
+
+        for (scope, value) in keys {
+            let throttle = match self.get_login_throttle(scope, value)? {
+                Some(t) => t,
+                None => continue,
+            };
+
+            if let Some(locked_until) = throttle.locked_until {
+                if locked_until > now {
+                    return Err(Error::AccountLocked(locked_until - now));
+                }
+            }
+
+            let retry_at = throttle.last_failure + policy.backoff_secs(throttle.failed_attempts);
+            if retry_at > now {
+                return Err(Error::LoginThrottled(retry_at - now));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record_login_failure(
+        &self,
+        scope: ThrottleScope,
+        value: &str,
+        now: i64,
+        policy: &LoginPolicy,
+    ) -> Result<(), Error> {
+        let failed_attempts: i32 = self.con().query_row(
+            "INSERT INTO login_throttle (scope, value, failed_attempts, last_failure)
+             VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT(scope, value) DO UPDATE
+             SET failed_attempts = failed_attempts + 1, last_failure = ?3
+             RETURNING failed_attempts",
+            params![scope as i32, value, now],
+            |row| row.get(0),
+        )?;
+
+        // Only accounts get locked; client IPs are slowed down by the backoff alone.
+        if scope == ThrottleScope::Email && failed_attempts >= policy.max_failed_logins {
+            self.con().execute(
+                "UPDATE login_throttle SET locked_until = ?1, failed_attempts = 0
+                 WHERE scope = ?2 AND value = ?3",
+                params![now + policy.lockout_mins * 60, scope as i32, value],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_login_throttle(
+        &self,
+        scope: ThrottleScope,
+        value: &str,
+    ) -> Result<Option<LoginThrottle>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT scope, value, failed_attempts, last_failure, locked_until
+             FROM login_throttle WHERE scope = ?1 AND value = ?2",
+        )?;
+
+        let mut q = stmnt.query_map(params![scope as i32, value], login_throttle_from_row)?;
+
+        Ok(q.next().transpose()?)
+    }
+
+    pub fn login_throttles(&self, now: i64) -> Result<Vec<LoginThrottle>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT scope, value, failed_attempts, last_failure, locked_until
+             FROM login_throttle WHERE failed_attempts > 0 OR locked_until > ?1",
+        )?;
+
+        let q = stmnt.query_map(params![now], login_throttle_from_row)?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn clear_login_throttle(&self, scope: ThrottleScope, value: &str) -> Result<(), Error> {
+        self.con().execute(
+            "DELETE FROM login_throttle WHERE scope = ?1 AND value = ?2",
+            params![scope as i32, value],
+        )?;
+        Ok(())
+    }
+
+    /// Permissions granted by the account's base role (named after its
+    /// `AccountType`) and any extra roles assigned to it.
+    pub fn account_permissions(
+        &self,
+        account_id: i32,
+        account_type: AccountType,
+    ) -> Result<HashSet<String>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT DISTINCT permission FROM role_permission
+             WHERE role = ?2 OR role IN (SELECT role FROM account_role WHERE account = ?1)",
+        )?;
+
+        let q = stmnt.query_map(params![account_id, account_type.role()], |row| row.get(0))?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn roles(&self) -> Result<Vec<Role>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT role.name, role_permission.permission FROM role
+             LEFT JOIN role_permission ON role_permission.role = role.name
+             ORDER BY role.name, role_permission.permission",
+        )?;
+
+        let rows = stmnt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })?;
+
+        let mut roles: Vec<Role> = vec![];
+        for row in rows {
+            let (name, permission) = row?;
+            if roles.last().map(|r| r.name != name).unwrap_or(true) {
+                roles.push(Role {
+                    name,
+                    permissions: vec![],
+                });
+            }
+            if let Some(permission) = permission {
+                roles.last_mut().unwrap().permissions.push(permission);
+            }
+        }
+
+        Ok(roles)
+    }
+
+    /// Creates the role if needed and replaces its permissions.
+    pub fn save_role(&self, role: &Role) -> Result<(), Error> {
+        self.con().execute(
+            "INSERT OR IGNORE INTO role (name) VALUES (?1)",
+            params![role.name],
+        )?;
+        self.set_role_permissions(&role.name, &role.permissions)
+    }
+
+    fn set_role_permissions<S: AsRef<str>>(
+        &self,
+        role: &str,
+        permissions: &[S],
+    ) -> Result<(), Error> {
+        self.con()
+            .execute("DELETE FROM role_permission WHERE role = ?1", params![role])?;
+
+        let mut stmnt = self
+            .con()
+            .prepare("INSERT OR IGNORE INTO role_permission (role, permission) VALUES (?1, ?2)")?;
+        for permission in permissions {
+            stmnt.execute(params![role, permission.as_ref()])?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `QueryReturnedNoRows` if the account or role doesn't exist.
+    pub fn assign_role(&self, account_id: i32, role: &str) -> Result<(), Error> {
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM account WHERE id = ?1)
+                AND EXISTS(SELECT 1 FROM role WHERE name = ?2)",
+            params![account_id, role],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        self.con().execute(
+            "INSERT OR IGNORE INTO account_role (account, role) VALUES (?1, ?2)",
+            params![account_id, role],
+        )?;
+        Ok(())
+    }
+
+    pub fn revoke_role(&self, account_id: i32, role: &str) -> Result<(), Error> {
+        self.con().execute(
+            "DELETE FROM account_role WHERE account = ?1 AND role = ?2",
+            params![account_id, role],
+        )?;
+        Ok(())
+    }
+
+    /// Creates an API key acting for `created_by`, limited to `permissions`,
+    /// which must all be held by `created_by`. Returns the key's ID and the
+    /// plaintext key, which isn't stored.
+    pub fn create_api_key(
+        &self,
+        created_by: i32,
+        data: &ApiKeyData,
+    ) -> Result<(i32, String), Error> {
+        let account_type: i32 = self.con().query_row(
+            "SELECT account_type FROM account WHERE id = ?1",
+            params![created_by],
+            |row| row.get(0),
+        )?;
+        let held =
+            self.account_permissions(created_by, AccountType::try_from(account_type).unwrap())?;
+        let not_held: Vec<String> = data
+            .permissions
+            .iter()
+            .filter(|permission| !held.contains(*permission))
+            .cloned()
+            .collect();
+        if !not_held.is_empty() {
+            return Err(Error::PermissionsNotHeld(not_held));
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, random_token(24));
+
+        let id: i32 = self.con().query_row(
+            "INSERT INTO api_key (name, key_hash, created_by, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+            params![
+                data.name,
+                token_hash(&key),
+                created_by,
+                unix_now(),
+                data.expires_at.map(|d| d.timestamp())
+            ],
+            |row| row.get(0),
+        )?;
+
+        let mut stmnt = self.con().prepare(
+            "INSERT OR IGNORE INTO api_key_permission (api_key, permission) VALUES (?1, ?2)",
+        )?;
+        for permission in data.permissions.iter() {
+            stmnt.execute(params![id, permission])?;
+        }
+
+        Ok((id, key))
+    }
+
+    pub fn api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT id, name, created_by, created_at, expires_at, last_used_at, revoked_at
+             FROM api_key ORDER BY id",
+        )?;
+
+        let keys = stmnt.query_map([], |row| {
+            Ok(ApiKey {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                permissions: vec![],
+                created_by: row.get(2)?,
+                created_at: row.get(3)?,
+                expires_at: row.get(4)?,
+                last_used_at: row.get(5)?,
+                revoked_at: row.get(6)?,
+            })
+        })?;
+
+        keys.map(|key| {
+            let mut key = key?;
+            key.permissions = self.api_key_permissions(key.id)?.into_iter().collect();
+            key.permissions.sort();
+            Ok(key)
+        })
+        .collect()
+    }
+
+    fn api_key_permissions(&self, api_key: i32) -> Result<HashSet<String>, Error> {
+        let mut stmnt = self
+            .con()
+            .prepare("SELECT permission FROM api_key_permission WHERE api_key = ?1")?;
+
+        let q = stmnt.query_map(params![api_key], |row| row.get(0))?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Revokes every key created by the account.
+    pub fn revoke_api_keys(&self, created_by: i32) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE api_key SET revoked_at = ?1 WHERE created_by = ?2 AND revoked_at IS NULL",
+            params![unix_now(), created_by],
+        )?;
+        Ok(())
+    }
+
+    pub fn revoke_api_key(&self, api_key: i32) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE api_key SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL",
+            params![unix_now(), api_key],
+        )?;
+        Ok(())
+    }
+
+    /// Looks up a live key, recording its use. Returns the claims of the
+    /// account that created it together with the key's own permissions.
+    pub fn authenticate_api_key(
+        &self,
+        key: &str,
+        now: i64,
+    ) -> Result<Option<(Claims, HashSet<String>)>, Error> {
+        let found = self.con().query_row(
+            "SELECT api_key.id, account.id, account.account_type FROM api_key
+             JOIN account ON account.id = api_key.created_by
+             WHERE api_key.key_hash = ?1 AND api_key.revoked_at IS NULL
+             AND account.deactivated_at IS NULL
+             AND (api_key.expires_at IS NULL OR api_key.expires_at > ?2)",
+            params![token_hash(key), now],
+            |row| {
+                Ok((
+                    row.get::<_, i32>(0)?,
+                    row.get::<_, i32>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            },
+        );
+
+        let (id, account_id, account_type) = match found {
+            Ok(found) => found,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        self.con().execute(
+            "UPDATE api_key SET last_used_at = ?1 WHERE id = ?2",
+            params![now, id],
+        )?;
+
+        // Roles taken from the creator later also drop out of their keys.
+        let account_type = AccountType::try_from(account_type).unwrap();
+        let held = self.account_permissions(account_id, account_type)?;
+        let permissions = self
+            .api_key_permissions(id)?
+            .into_iter()
+            .filter(|permission| held.contains(permission))
+            .collect();
+
+        Ok(Some((Claims::new(account_id, account_type), permissions)))
+    }
+
+    pub fn get_doctor_info(&self, doctor_id: i32) -> Result<DoctorInfo, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM account JOIN doctor ON account.id = doctor.id WHERE account.id = ?1",
+            doctor_info_columns()
+        ))?;
+        let info = stmnt.query_row(params![doctor_id], doctor_info_from_row)?;
+        Ok(info)
+    }
+
+    /// Updates the fields set in `update`. Fails with `QueryReturnedNoRows`
+    /// if `doctor_id` isn't a doctor.
+    pub fn update_doctor_profile(
+        &self,
+        doctor_id: i32,
+        update: &DoctorProfileUpdate,
+    ) -> Result<(), Error> {
+        if matches!(update.consultation_fee, Some(fee) if fee < 0) {
+            return Err(Error::InvalidProfile(
+                "consultation fee can't be negative".to_string(),
+            ));
+        }
+        if let Some(url) = &update.photo_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(Error::InvalidProfile(
+                    "photo must be an http(s) URL".to_string(),
+                ));
+            }
+        }
+
+        let to_json = |list: &Option<Vec<String>>| {
+            list.as_ref()
+                .map(|list| serde_json::to_string(list).unwrap())
+        };
+        let updated = self.con().execute(
+            "UPDATE doctor SET
+                specialty = coalesce(?1, specialty),
+                details = coalesce(?2, details),
+                languages = coalesce(?3, languages),
+                qualifications = coalesce(?4, qualifications),
+                consultation_fee = coalesce(?5, consultation_fee),
+                photo_url = coalesce(?6, photo_url),
+                clinic = coalesce(?7, clinic)
+             WHERE id = ?8",
+            params![
+                update.specialty.as_deref().map(str::trim),
+                update.details,
+                to_json(&update.languages),
+                to_json(&update.qualifications),
+                update.consultation_fee,
+                update.photo_url,
+                update.clinic.as_deref().map(str::trim),
+                doctor_id
+            ],
+        )?;
+
+        match updated {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_patient_profile(&self, patient_id: i32) -> Result<PatientProfile, Error> {
+        Ok(self.con().query_row(
+            "SELECT account.id, fullname, date_of_birth, sex, phone, address,
+                emergency_contact_name, emergency_contact_phone, emergency_contact_relationship,
+                insurance_provider, insurance_policy_number, allergies, insurance_plan,
+                insurance_group_number, insurance_valid_until
+             FROM account JOIN patient ON account.id = patient.id WHERE account.id = ?1",
+            params![patient_id],
+            |row| {
+                let emergency_contact = match row.get::<_, Option<String>>(6)? {
+                    Some(name) => Some(EmergencyContact {
+                        name,
+                        phone: row.get(7)?,
+                        relationship: row.get(8)?,
+                    }),
+                    None => None,
+                };
+
+                Ok(PatientProfile {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    demographics: Some(PatientDemographics {
+                        date_of_birth: row
+                            .get::<_, Option<String>>(2)?
+                            .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok()),
+                        sex: row
+                            .get::<_, Option<i32>>(3)?
+                            .and_then(|sex| Sex::try_from(sex).ok()),
+                    }),
+                    contact: Some(PatientContact {
+                        phone: row.get(4)?,
+                        address: row.get(5)?,
+                        emergency_contact,
+                    }),
+                    insurance: Some(PatientInsurance {
+                        provider: row.get(9)?,
+                        policy_number: row.get(10)?,
+                        plan_id: row.get(12)?,
+                        group_number: row.get(13)?,
+                        valid_until: row
+                            .get::<_, Option<String>>(14)?
+                            .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok()),
+                    }),
+                    clinical: Some(PatientClinical {
+                        allergies: serde_json::from_str(&row.get::<_, String>(11)?)
+                            .unwrap_or_default(),
+                    }),
+                })
+            },
+        )?)
+    }
+
+    /// Replaces each field group present in `update`. Fails with
+    /// `QueryReturnedNoRows` if `patient_id` isn't a patient.
+    pub fn update_patient_profile(
+        &self,
+        patient_id: i32,
+        update: &PatientProfileUpdate,
+    ) -> Result<(), Error> {
+        let exists: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM patient WHERE id = ?1)",
+            params![patient_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+
+        if let Some(demographics) = &update.demographics {
+            if matches!(demographics.date_of_birth, Some(dob) if dob > Utc::now().naive_utc().date())
+            {
+                return Err(Error::InvalidProfile(
+                    "date of birth is in the future".to_string(),
+                ));
+            }
+            self.con().execute(
+                "UPDATE patient SET date_of_birth = ?1, sex = ?2 WHERE id = ?3",
+                params![
+                    demographics
+                        .date_of_birth
+                        .map(|d| d.format("%Y-%m-%d").to_string()),
+                    demographics.sex.map(|sex| sex as i32),
+                    patient_id
+                ],
+            )?;
+        }
+
+        if let Some(contact) = &update.contact {
+            let emergency = contact.emergency_contact.as_ref();
+            self.con().execute(
+                "UPDATE patient SET phone = ?1, address = ?2, emergency_contact_name = ?3,
+                    emergency_contact_phone = ?4, emergency_contact_relationship = ?5
+                 WHERE id = ?6",
+                params![
+                    contact.phone,
+                    contact.address,
+                    emergency.map(|c| &c.name),
+                    emergency.and_then(|c| c.phone.as_ref()),
+                    emergency.and_then(|c| c.relationship.as_ref()),
+                    patient_id
+                ],
+            )?;
+        }
+
+        if let Some(insurance) = &update.insurance {
+            if let Some(plan_id) = insurance.plan_id {
+                match self.get_insurance_plan(plan_id) {
+                    Ok(_) => {}
+                    Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                        return Err(Error::InvalidProfile("unknown insurance plan".to_string()))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            self.con().execute(
+                "UPDATE patient SET insurance_provider = ?1, insurance_policy_number = ?2,
+                    insurance_plan = ?3, insurance_group_number = ?4, insurance_valid_until = ?5
+                 WHERE id = ?6",
+                params![
+                    insurance.provider,
+                    insurance.policy_number,
+                    insurance.plan_id,
+                    insurance.group_number,
+                    insurance
+                        .valid_until
+                        .map(|d| d.format("%Y-%m-%d").to_string()),
+                    patient_id
+                ],
+            )?;
+        }
+
+        if let Some(clinical) = &update.clinical {
+            let allergies: Vec<&str> = clinical
+                .allergies
+                .iter()
+                .map(|a| a.trim())
+                .filter(|a| !a.is_empty())
+                .collect();
+            self.con().execute(
+                "UPDATE patient SET allergies = ?1 WHERE id = ?2",
+                params![serde_json::to_string(&allergies).unwrap(), patient_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_doctor_appointments(
+        &self,
+        doctor_id: i32,
+        day: DateTime<FixedOffset>,
+    ) -> Result<Vec<Appointment>, Error> {
+        let mut stmnt = self
+            .con()
+            .prepare("SELECT * FROM appointment WHERE doctor = ?1 and date(starting_date) = ?2")?;
+
+        let mut q = stmnt.query_map(
+            params![doctor_id, day.format("%Y-%m-%d").to_string()],
+            appointment_from_row,
+        )?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_patient_appointments_history(
+        &self,
+        patient_id: i32,
+    ) -> Result<Vec<Appointment>, Error> {
+        let mut stmnt = self
+            .con()
+            .prepare("SELECT * FROM appointment WHERE patient = ?1")?;
+
+        let mut q = stmnt.query_map(params![patient_id], appointment_from_row)?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Books the appointment on behalf of `actor`, who is the patient
+    /// themselves or a staff member booking for them. A referral is used up
+    /// by the booking, and appointment types can require one.
+    pub fn book_appointment(&self, request: AppointmentRequest, actor: i32) -> Result<i32, Error> {
+        if !self.patient_active(request.patient_id)? {
+            return Err(Error::UnknownPatient);
+        }
+        let appointment_type = match request.appointment_type_id {
+            Some(type_id) => match self.get_appointment_type(type_id) {
+                Ok(appointment_type) => Some(appointment_type),
+                Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                    return Err(Error::UnknownAppointmentType)
+                }
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let referral_required = appointment_type
+            .as_ref()
+            .is_some_and(|t| t.referral_required);
+        match request.referral_id {
+            Some(referral_id) => self.check_referral(referral_id, &request)?,
+            None if referral_required => return Err(Error::ReferralRequired),
+            None => {}
+        }
+        if appointment_type.as_ref().is_some_and(|t| t.telehealth) {
+            let missing = self.missing_consents(request.patient_id, ConsentPurpose::Telehealth)?;
+            if !missing.is_empty() {
+                return Err(Error::ConsentRequired(
+                    missing.into_iter().map(|document| document.code).collect(),
+                ));
+            }
+        }
+
+        let mut stmnt = self
+            .con()
+            .prepare(
+                "INSERT INTO appointment(doctor, patient, appointment_status, starting_date, duration_mins,
+                    appointment_type, referral)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
+            )
+            .unwrap();
+
+        let q: i32 = stmnt.query_row(
+            params![
+                request.doctor_id,
+                request.patient_id,
+                AppointmentStatus::Booked as i32,
+                request.start_date.to_rfc3339(),
+                request.duration,
+                request.appointment_type_id,
+                request.referral_id
+            ],
+            |row| row.get(0),
+        )?;
+
+        if let Some(referral_id) = request.referral_id {
+            self.con().execute(
+                "UPDATE referral SET referral_status = ?1, appointment = ?2 WHERE id = ?3",
+                params![ReferralStatus::Booked as i32, q, referral_id],
+            )?;
+        }
+        if let Some(type_id) = request.appointment_type_id {
+            self.con().execute(
+                "INSERT INTO appointment_questionnaire (appointment, questionnaire, required, name,
+                    questions)
+                 SELECT ?1, id, required, name, questions FROM questionnaire
+                 WHERE appointment_type = ?2",
+                params![q, type_id],
+            )?;
+        }
+
+        self.record_appointment_action(q, actor, AppointmentAction::Booked)?;
+
+        Ok(q)
+    }
+
+    /// Checks that the referral is open, for this patient, and to this
+    /// doctor or their specialty.
+    fn check_referral(&self, referral_id: i32, request: &AppointmentRequest) -> Result<(), Error> {
+        let referral = match self.get_referral(referral_id) {
+            Ok(referral) => referral,
+            Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                return Err(Error::InvalidReferral("referral not found".to_string()))
+            }
+            Err(e) => return Err(e),
+        };
+
+        if referral.patient_id != request.patient_id {
+            return Err(Error::InvalidReferral(
+                "referral is for another patient".to_string(),
+            ));
+        }
+        if referral.status != ReferralStatus::Open {
+            return Err(Error::InvalidReferral(
+                "referral has already been used".to_string(),
+            ));
+        }
+
+        let matches_target = match (&referral.target_doctor_id, &referral.target_specialty) {
+            (Some(doctor_id), _) => *doctor_id == request.doctor_id,
+            (None, Some(specialty)) => self.con().query_row(
+                "SELECT EXISTS(SELECT 1 FROM doctor WHERE id = ?1 AND lower(specialty) = lower(?2))",
+                params![request.doctor_id, specialty],
+                |row| row.get(0),
+            )?,
+            (None, None) => false,
+        };
+        if !matches_target {
+            return Err(Error::InvalidReferral(
+                "referral is for another doctor".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn appointment_types(&self) -> Result<Vec<AppointmentType>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM appointment_type ORDER BY name",
+            APPOINTMENT_TYPE_COLUMNS
+        ))?;
+        let q = stmnt.query_map([], appointment_type_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_appointment_type(&self, type_id: i32) -> Result<AppointmentType, Error> {
+        Ok(self.con().query_row(
+            &format!(
+                "SELECT {} FROM appointment_type WHERE id = ?1",
+                APPOINTMENT_TYPE_COLUMNS
+            ),
+            params![type_id],
+            appointment_type_from_row,
+        )?)
+    }
+
+    pub fn save_appointment_type(
+        &self,
+        type_id: Option<i32>,
+        data: &AppointmentTypeData,
+    ) -> Result<i32, Error> {
+        if matches!(data.fee, Some(fee) if fee < 0)
+            || matches!(data.deposit, Some(deposit) if deposit < 0)
+        {
+            return Err(Error::InvalidAppointmentType(
+                "fee and deposit can't be negative".to_string(),
+            ));
+        }
+        if !(0..=MAX_TAX_RATE_BPS).contains(&data.tax_rate_bps) {
+            return Err(Error::InvalidAppointmentType(format!(
+                "tax rate must be between 0 and {} basis points",
+                MAX_TAX_RATE_BPS
+            )));
+        }
+
+        Ok(self.con().query_row(
+            "INSERT INTO appointment_type (id, name, referral_required, fee, tax_rate_bps,
+                deposit, telehealth)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(id) DO UPDATE SET name = excluded.name,
+                referral_required = excluded.referral_required, fee = excluded.fee,
+                tax_rate_bps = excluded.tax_rate_bps, deposit = excluded.deposit,
+                telehealth = excluded.telehealth
+             RETURNING id",
+            params![
+                type_id,
+                data.name.trim(),
+                data.referral_required,
+                data.fee,
+                data.tax_rate_bps,
+                data.deposit.filter(|deposit| *deposit > 0),
+                data.telehealth
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Refers a patient the doctor has seen to another doctor or specialty.
+    pub fn create_referral(
+        &self,
+        referring_doctor: i32,
+        data: &ReferralData,
+    ) -> Result<i32, Error> {
+        let specialty = data
+            .target_specialty
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty());
+        match (data.target_doctor_id, specialty) {
+            (Some(_), Some(_)) | (None, None) => {
+                return Err(Error::InvalidReferral(
+                    "set either a target doctor or a specialty".to_string(),
+                ))
+            }
+            (Some(target), None) if target == referring_doctor => {
+                return Err(Error::InvalidReferral(
+                    "can't refer to yourself".to_string(),
+                ))
+            }
+            (Some(target), None) => {
+                let active_doctor: bool = self.con().query_row(
+                    "SELECT EXISTS(SELECT 1 FROM doctor JOIN account ON account.id = doctor.id
+                     WHERE doctor.id = ?1 AND account.deactivated_at IS NULL)",
+                    params![target],
+                    |row| row.get(0),
+                )?;
+                if !active_doctor {
+                    return Err(Error::InvalidReferral(
+                        "target doctor not found".to_string(),
+                    ));
+                }
+            }
+            (None, Some(_)) => {}
+        }
+
+        let seen: bool = self.con().query_row(
+            "SELECT EXISTS(SELECT 1 FROM appointment
+             WHERE doctor = ?1 AND patient = ?2 AND appointment_status IN (?3, ?4))",
+            params![
+                referring_doctor,
+                data.patient_id,
+                AppointmentStatus::CheckedIn as i32,
+                AppointmentStatus::Done as i32
+            ],
+            |row| row.get(0),
+        )?;
+        if !seen {
+            return Err(Error::InvalidReferral(
+                "doctors can only refer patients they have seen".to_string(),
+            ));
+        }
+
+        Ok(self.con().query_row(
+            "INSERT INTO referral (patient, referring_doctor, target_doctor, target_specialty,
+                reason, urgency, referral_status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) RETURNING id",
+            params![
+                data.patient_id,
+                referring_doctor,
+                data.target_doctor_id,
+                specialty,
+                data.reason.trim(),
+                data.urgency as i32,
+                ReferralStatus::Open as i32,
+                unix_now()
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn get_referral(&self, referral_id: i32) -> Result<Referral, Error> {
+        Ok(self.con().query_row(
+            &format!("SELECT {} FROM referral WHERE id = ?1", REFERRAL_COLUMNS),
+            params![referral_id],
+            referral_from_row,
+        )?)
+    }
+
+    pub fn patient_referrals(&self, patient_id: i32) -> Result<Vec<Referral>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM referral WHERE patient = ?1 ORDER BY created_at DESC, id DESC",
+            REFERRAL_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![patient_id], referral_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Referrals the doctor made, was named in, or has the booked
+    /// appointment for.
+    pub fn doctor_referrals(&self, doctor_id: i32) -> Result<Vec<Referral>, Error> {
+        let mut stmnt = self.con().prepare(&format!(
+            "SELECT {} FROM referral WHERE referring_doctor = ?1 OR target_doctor = ?1
+             OR appointment IN (SELECT id FROM appointment WHERE doctor = ?1)
+             ORDER BY created_at DESC, id DESC",
+            REFERRAL_COLUMNS
+        ))?;
+        let q = stmnt.query_map(params![doctor_id], referral_from_row)?;
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    /// Accounts that own the referral: the patient, the patient's guardians,
+    /// the referring and target doctors, and the doctor of the booked
+    /// appointment, who is the only receiving doctor of specialty referrals.
+    pub fn referral_owners(&self, referral: &Referral) -> Result<Vec<i32>, Error> {
+        let mut owners = self.patient_owners(referral.patient_id)?;
+        owners.push(referral.referring_doctor_id);
+        owners.extend(referral.target_doctor_id);
+        if let Some(appointment_id) = referral.appointment_id {
+            let doctor_id = self.get_appointment(appointment_id)?.doctor_id;
+            if !owners.contains(&doctor_id) {
+                owners.push(doctor_id);
+            }
+        }
+        Ok(owners)
+    }
+
+    pub fn reschedule_appointment(
+        &self,
+        appointment_id: i32,
+        start_date: &DateTime<FixedOffset>,
+        duration: i32,
+        actor: i32,
+    ) -> Result<(), Error> {
+        let current = self.get_appointment(appointment_id)?.status;
+        if current != AppointmentStatus::Booked {
+            return Err(Error::AppointmentStatus(current));
+        }
+        self.con().execute(
+            "UPDATE appointment SET starting_date = ?1, duration_mins = ?2 WHERE id = ?3",
+            params![start_date.to_rfc3339(), duration, appointment_id],
+        )?;
+
+        self.record_appointment_action(appointment_id, actor, AppointmentAction::Rescheduled)
+    }
+
+    fn reopen_referral(&self, appointment_id: i32) -> Result<(), Error> {
+        self.con().execute(
+            "UPDATE referral SET referral_status = ?1, appointment = NULL WHERE appointment = ?2",
+            params![ReferralStatus::Open as i32, appointment_id],
+        )?;
+        Ok(())
+    }
+
+    fn record_appointment_action(
+        &self,
+        appointment_id: i32,
+        actor: i32,
+        action: AppointmentAction,
+    ) -> Result<(), Error> {
+        self.con().execute(
+            "INSERT INTO appointment_action (appointment, actor, action, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![appointment_id, actor, action as i32, unix_now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_appointment_actions(
+        &self,
+        appointment_id: i32,
+    ) -> Result<Vec<AppointmentActionRecord>, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT actor, action, created_at FROM appointment_action
+             WHERE appointment = ?1 ORDER BY id",
+        )?;
+
+        let q = stmnt.query_map(params![appointment_id], |row| {
+            Ok(AppointmentActionRecord {
+                actor: row.get(0)?,
+                action: AppointmentAction::try_from(row.get::<_, i32>(1)?).unwrap(),
+                created_at: row.get(2)?,
+            })
+        })?;
+
+        Ok(q.collect::<Result<_, _>>()?)
+    }
+
+    pub fn is_valid_appointment_request(
+        &self,
+        doctor_id: i32,
+        start_date: &DateTime<FixedOffset>,
+        duration: i32,
+    ) -> Result<bool, Error> {
+        if duration < 15 || duration > 120 {
+            return Ok(false);
+        }
+
+        match self.account_active(doctor_id) {
+            Ok(true) => {}
+            Ok(false) | Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows)) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        }
+
+        let stats = self.get_doctor_stats(doctor_id, start_date.date())?;
+        if stats.appointments_count >= 12 || stats.booked_mins >= 8 * 60 {
+            return Ok(false);
+        }
+
+        return Ok(true);
+    }
+
+    pub fn get_doctor_stats(
+        &self,
+        doctor_id: i32,
+        day: Date<FixedOffset>,
+    ) -> Result<DoctorAppointmentStats, Error> {
+        let mut stmnt = self.con().prepare(
+            "SELECT ?1, count(*), coalesce(sum(duration_mins), 0) FROM appointment
+                 WHERE doctor = ?1 and date(starting_date) = ?2 and appointment_status != ?3",
+        )?;
+
+        let q = stmnt.query_row(
+            params![
+                doctor_id,
+                day.format("%Y-%m-%d").to_string(),
+                AppointmentStatus::Cancelled as i32
+            ],
+            |row| {
+                Ok(DoctorAppointmentStats {
+                    doctor_id: row.get(0)?,
+                    appointments_count: row.get(1)?,
+                    booked_mins: row.get(2)?,
+                })
+            },
+        )?;
+
+        Ok(q)
+    }
+
+    pub fn get_appointment(&self, appointment_id: i32) -> Result<Appointment, Error> {
+        let mut stmnt = self
+            .con()
+            .prepare("SELECT * FROM appointment where id = ?1")?;
+
+        let q = stmnt.query_row(params![appointment_id], appointment_from_row)?;
+
+        Ok(q)
+    }
+
+    pub fn set_appointment_status(
+        &self,
+        appointment_id: i32,
+        status: AppointmentStatus,
+        actor: i32,
+    ) -> Result<(), Error> {
+        let current = self.get_appointment(appointment_id)?.status;
+        let allowed = match status {
+            AppointmentStatus::CheckedIn => current == AppointmentStatus::Booked,
+            AppointmentStatus::Done => current == AppointmentStatus::CheckedIn,
+            AppointmentStatus::Cancelled => matches!(
+                current,
+                AppointmentStatus::Booked
+                    | AppointmentStatus::CheckedIn
+                    | AppointmentStatus::NeedsRebooking
+            ),
+            AppointmentStatus::NeedsRebooking => matches!(
+                current,
+                AppointmentStatus::Booked | AppointmentStatus::CheckedIn
+            ),
+            // Appointments only start out booked.
+            AppointmentStatus::Booked => false,
+        };
+        if !allowed {
+            return Err(Error::AppointmentStatus(current));
+        }
+
+        if status == AppointmentStatus::CheckedIn {
+            let pending: bool = self.con().query_row(
+                "SELECT EXISTS(SELECT 1 FROM appointment_questionnaire
+                 WHERE appointment = ?1 AND required AND submitted_at IS NULL)",
+                params![appointment_id],
+                |row| row.get(0),
+            )?;
+            if pending {
+                return Err(Error::QuestionnairesPending);
+            }
+        }
+
+        // The invoice and claim of a completed visit are created with it.
+        self.atomically(|| {
+            let mut stmnt = self.con().prepare(
+                "
+                UPDATE appointment SET appointment_status = ?1
+                WHERE id = ?2 AND appointment_status = ?3
+            ",
+            )?;
+
+            // Someone else changed it since it was read.
+            if stmnt.execute(params![status as i32, appointment_id, current as i32])? == 0 {
+                return Err(Error::AppointmentStatus(
+                    self.get_appointment(appointment_id)?.status,
+                ));
+            }
+
+            // The patient can book again with the referral.
+            if matches!(
+                status,
+                AppointmentStatus::Cancelled | AppointmentStatus::NeedsRebooking
+            ) {
+                self.reopen_referral(appointment_id)?;
+            }
+            if status == AppointmentStatus::Done {
+                self.generate_invoice(appointment_id)?;
+                self.generate_claim(appointment_id)?;
+            }
+
+            let action = match status {
+                AppointmentStatus::Booked => AppointmentAction::Booked,
+                AppointmentStatus::Cancelled => AppointmentAction::Cancelled,
+                AppointmentStatus::Done => AppointmentAction::Completed,
+                AppointmentStatus::CheckedIn => AppointmentAction::CheckedIn,
+                AppointmentStatus::NeedsRebooking => AppointmentAction::FlaggedForRebooking,
+            };
+            self.record_appointment_action(appointment_id, actor, action)
+        })
+    }
+}
 
 fn appointment_from_row(row: &Row) -> Result<Appointment, rusqlite::Error> {
     let appointment = Appointment {
         id: row.get(0)?,
-        doctor_id: row.get(1)?,
-        patient_id: row.get(2)?,
-        status: AppointmentStatus::try_from(row.get::<_, i32>(3)?).unwrap(),
-        start_date: DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?).unwrap(),
-        duration: row.get(5)?,
+        doctor_id: row.get(1)?,
+        patient_id: row.get(2)?,
+        status: AppointmentStatus::try_from(row.get::<_, i32>(3)?).unwrap(),
+        start_date: DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?).unwrap(),
+        duration: row.get(5)?,
+        appointment_type_id: row.get(6)?,
+        referral_id: row.get(7)?,
+    };
+
+    Ok(appointment)
+}
+
+fn visit_note_from_row(row: &Row) -> Result<VisitNote, rusqlite::Error> {
+    Ok(VisitNote {
+        appointment_id: row.get(0)?,
+        version: row.get(1)?,
+        author_id: row.get(2)?,
+        chief_complaint: row.get(3)?,
+        findings: row.get(4)?,
+        diagnosis_codes: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+        plan: row.get(6)?,
+        patient_summary: row.get(7)?,
+        amendment_reason: row.get(8)?,
+        created_at: row.get(9)?,
+    })
+}
+
+/// Checks the ICD-10 shape: a letter, two characters and an optional
+/// subcategory, e.g. `J06.9`.
+fn is_diagnosis_code(code: &str) -> bool {
+    let (category, subcategory) = match code.split_once('.') {
+        Some((category, subcategory)) => (category.as_bytes(), Some(subcategory)),
+        None => (code.as_bytes(), None),
+    };
+    let alphanumeric = |c: u8| c.is_ascii_digit() || c.is_ascii_uppercase();
+
+    category.len() == 3
+        && category[0].is_ascii_uppercase()
+        && category[1].is_ascii_digit()
+        && alphanumeric(category[2])
+        && subcategory.is_none_or(|s| (1..=4).contains(&s.len()) && s.bytes().all(alphanumeric))
+}
+
+fn prescription_from_row(row: &Row) -> Result<Prescription, rusqlite::Error> {
+    Ok(Prescription {
+        id: row.get(0)?,
+        appointment_id: row.get(1)?,
+        patient_id: row.get(2)?,
+        doctor_id: row.get(3)?,
+        drug: row.get(4)?,
+        dose: row.get(5)?,
+        frequency: row.get(6)?,
+        duration_days: row.get(7)?,
+        refills: row.get(8)?,
+        instructions: row.get(9)?,
+        allergy_override: row.get(10)?,
+        status: PrescriptionStatus::try_from(row.get::<_, i32>(11)?).unwrap(),
+        created_at: row.get(12)?,
+        expires_at: row.get(13)?,
+    })
+}
+
+fn attachment_from_row(row: &Row) -> Result<Attachment, rusqlite::Error> {
+    Ok(Attachment {
+        id: row.get(0)?,
+        patient_id: row.get(1)?,
+        appointment_id: row.get(2)?,
+        uploaded_by: row.get(3)?,
+        filename: row.get(4)?,
+        content_type: row.get(5)?,
+        size_bytes: row.get(6)?,
+        sha256: row.get(7)?,
+        storage_key: row.get(8)?,
+        created_at: row.get(9)?,
+    })
+}
+
+/// Sniffs the leading bytes, so a renamed executable can't pass as a PDF.
+fn content_matches_type(content_type: &str, data: &[u8]) -> bool {
+    match content_type {
+        "application/pdf" => data.starts_with(b"%PDF-"),
+        "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => data.starts_with(b"\xff\xd8\xff"),
+        // DICOM files start with a 128 byte preamble.
+        "application/dicom" => data.len() > 132 && &data[128..132] == b"DICM",
+        "text/plain" => std::str::from_utf8(data).is_ok(),
+        _ => false,
+    }
+}
+
+/// Keeps the last path component and drops characters that would break the
+/// download's Content-Disposition header.
+fn attachment_filename(filename: &str) -> String {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILENAME_CHARS)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn questionnaire_from_row(row: &Row) -> Result<Questionnaire, rusqlite::Error> {
+    Ok(Questionnaire {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        appointment_type_id: row.get(2)?,
+        required: row.get(3)?,
+        questions: serde_json::from_str(&row.get::<_, String>(4)?).unwrap_or_default(),
+        created_by: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn thread_from_row(row: &Row) -> Result<Thread, rusqlite::Error> {
+    Ok(Thread {
+        id: row.get(0)?,
+        patient_id: row.get(1)?,
+        doctor_id: row.get(2)?,
+        appointment_id: row.get(3)?,
+        subject: row.get(4)?,
+        created_by: row.get(5)?,
+        created_at: row.get(6)?,
+        last_message_at: row.get(7)?,
+        unread: row.get(8)?,
+    })
+}
+
+fn lab_order_from_row(row: &Row) -> Result<LabOrder, rusqlite::Error> {
+    Ok(LabOrder {
+        id: row.get(0)?,
+        appointment_id: row.get(1)?,
+        patient_id: row.get(2)?,
+        doctor_id: row.get(3)?,
+        test_code: row.get(4)?,
+        test_name: row.get(5)?,
+        notes: row.get(6)?,
+        status: LabOrderStatus::try_from(row.get::<_, i32>(7)?).unwrap(),
+        ordered_at: row.get(8)?,
+        collected_at: row.get(9)?,
+        resulted_at: row.get(10)?,
+        reviewed_at: row.get(11)?,
+        results: Vec::new(),
+    })
+}
+
+/// Low or High when `value` falls outside a numeric range such as "3.5-5.0",
+/// "<200" or ">=60", Normal inside it, and `None` when either isn't numeric.
+fn reference_flag(value: &str, range: &str) -> Option<LabFlag> {
+    let value: f64 = value.trim().parse().ok()?;
+    let range = range.trim();
+    let bound = |s: &str| s.trim().parse::<f64>().ok();
+    let (low, high) = if let Some(high) = range.strip_prefix("<=") {
+        (None, Some(bound(high)?))
+    } else if let Some(high) = range.strip_prefix('<') {
+        (None, Some(bound(high)?))
+    } else if let Some(low) = range.strip_prefix(">=") {
+        (Some(bound(low)?), None)
+    } else if let Some(low) = range.strip_prefix('>') {
+        (Some(bound(low)?), None)
+    } else {
+        let (low, high) = range.split_once('-')?;
+        (Some(bound(low)?), Some(bound(high)?))
+    };
+
+    if low.is_some_and(|low| value < low) {
+        Some(LabFlag::Low)
+    } else if high.is_some_and(|high| value > high) {
+        Some(LabFlag::High)
+    } else {
+        Some(LabFlag::Normal)
+    }
+}
+
+fn invoice_from_row(row: &Row) -> Result<Invoice, rusqlite::Error> {
+    Ok(Invoice {
+        id: row.get(0)?,
+        appointment_id: row.get(1)?,
+        patient_id: row.get(2)?,
+        doctor_id: row.get(3)?,
+        status: InvoiceStatus::try_from(row.get::<_, i32>(4)?).unwrap(),
+        lines: Vec::new(),
+        subtotal: 0,
+        discount: 0,
+        tax: 0,
+        total: 0,
+        created_at: row.get(5)?,
+        issued_at: row.get(6)?,
+        paid_at: row.get(7)?,
+        voided_at: row.get(8)?,
+        void_reason: row.get(9)?,
+    })
+}
+
+fn insurance_plan_from_row(row: &Row) -> Result<InsurancePlan, rusqlite::Error> {
+    Ok(InsurancePlan {
+        id: row.get(0)?,
+        payer_name: row.get(1)?,
+        payer_id: row.get(2)?,
+        plan_name: row.get(3)?,
+        covered_appointment_types: serde_json::from_str(&row.get::<_, String>(4)?)
+            .unwrap_or_default(),
+        covered_specialties: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+        copay: row.get(6)?,
+    })
+}
+
+fn claim_from_row(row: &Row) -> Result<InsuranceClaim, rusqlite::Error> {
+    Ok(InsuranceClaim {
+        id: row.get(0)?,
+        appointment_id: row.get(1)?,
+        invoice_id: row.get(2)?,
+        patient_id: row.get(3)?,
+        plan_id: row.get(4)?,
+        member_id: row.get(5)?,
+        group_number: row.get(6)?,
+        billed_amount: row.get(7)?,
+        copay: row.get(8)?,
+        status: ClaimStatus::try_from(row.get::<_, i32>(9)?).unwrap(),
+        batch_id: row.get(10)?,
+        created_at: row.get(11)?,
+        submitted_at: row.get(12)?,
+        decided_at: row.get(13)?,
+        paid_at: row.get(14)?,
+        paid_amount: row.get(15)?,
+        denial_reason: row.get(16)?,
+    })
+}
+
+fn consent_document_from_row(row: &Row) -> Result<ConsentDocument, rusqlite::Error> {
+    Ok(ConsentDocument {
+        id: row.get(0)?,
+        code: row.get(1)?,
+        version: row.get(2)?,
+        title: row.get(3)?,
+        body: row.get(4)?,
+        purpose: ConsentPurpose::try_from(row.get::<_, i32>(5)?).unwrap(),
+        created_by: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+fn appointment_type_from_row(row: &Row) -> Result<AppointmentType, rusqlite::Error> {
+    Ok(AppointmentType {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        referral_required: row.get(2)?,
+        fee: row.get(3)?,
+        tax_rate_bps: row.get(4)?,
+        deposit: row.get(5)?,
+        telehealth: row.get(6)?,
+    })
+}
+
+fn referral_from_row(row: &Row) -> Result<Referral, rusqlite::Error> {
+    Ok(Referral {
+        id: row.get(0)?,
+        patient_id: row.get(1)?,
+        referring_doctor_id: row.get(2)?,
+        target_doctor_id: row.get(3)?,
+        target_specialty: row.get(4)?,
+        reason: row.get(5)?,
+        urgency: ReferralUrgency::try_from(row.get::<_, i32>(6)?).unwrap(),
+        status: ReferralStatus::try_from(row.get::<_, i32>(7)?).unwrap(),
+        appointment_id: row.get(8)?,
+        created_at: row.get(9)?,
+    })
+}
+
+/// Turns free text into an FTS5 query matching every word as a prefix, so
+/// user input can't use (or break) the query syntax.
+fn fts_query(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// HTML-escapes FTS5 output and turns the match markers into `<mark>` tags.
+fn mark_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            MARK_START => out.push_str("<mark>"),
+            MARK_END => out.push_str("</mark>"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn review_from_row(row: &Row) -> Result<Review, rusqlite::Error> {
+    Ok(Review {
+        id: row.get(0)?,
+        appointment_id: row.get(1)?,
+        doctor_id: row.get(2)?,
+        rating: row.get(3)?,
+        text: row.get(4)?,
+        status: ReviewStatus::try_from(row.get::<_, i32>(5)?).unwrap(),
+        created_at: row.get(6)?,
+    })
+}
+
+fn doctor_info_from_row(row: &Row) -> Result<DoctorInfo, rusqlite::Error> {
+    let list = |json: String| serde_json::from_str(&json).unwrap_or_default();
+
+    Ok(DoctorInfo {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        specialty: row.get(2)?,
+        details: row.get(3)?,
+        languages: list(row.get(4)?),
+        qualifications: list(row.get(5)?),
+        consultation_fee: row.get(6)?,
+        photo_url: row.get(7)?,
+        clinic: row.get(8)?,
+        rating: row.get(9)?,
+        review_count: row.get(10)?,
+    })
+}
+
+fn login_throttle_from_row(row: &Row) -> Result<LoginThrottle, rusqlite::Error> {
+    Ok(LoginThrottle {
+        scope: ThrottleScope::try_from(row.get::<_, i32>(0)?).unwrap(),
+        value: row.get(1)?,
+        failed_attempts: row.get(2)?,
+        last_failure: row.get(3)?,
+        locked_until: row.get(4)?,
+    })
+}
+
+fn encode_jwt(claims: &Claims) -> Result<JWT, Error> {
+    Ok(encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret("secret".as_ref()),
+    )?)
+}
+
+pub fn decode_jwt(jwt: &str) -> Result<Claims, Error> {
+    let token = decode::<Claims>(
+        jwt,
+        &DecodingKey::from_secret("secret".as_ref()),
+        &Validation::new(Algorithm::HS256),
+    )?;
+
+    Ok(token.claims)
+}
+
+fn encode_challenge(claims: &ChallengeClaims) -> Result<JWT, Error> {
+    Ok(encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret("secret".as_ref()),
+    )?)
+}
+
+pub fn decode_challenge(jwt: &str) -> Result<ChallengeClaims, Error> {
+    let token = decode::<ChallengeClaims>(
+        jwt,
+        &DecodingKey::from_secret("secret".as_ref()),
+        &Validation::new(Algorithm::HS256),
+    )?;
+
+    Ok(token.claims)
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum AccountType {
+    Patient = 0,
+    Doctor = 1,
+    Admin = 2,
+    Receptionist = 3,
+}
+
+impl AccountType {
+    /// Name of the base role every account of this type has.
+    pub fn role(&self) -> &'static str {
+        match self {
+            AccountType::Patient => "patient",
+            AccountType::Doctor => "doctor",
+            AccountType::Admin => "admin",
+            AccountType::Receptionist => "receptionist",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegisterData {
+    name: String,
+    email: String,
+    password: String,
+    account_type: AccountType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountProfile {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub account_type: AccountType,
+    pub deactivated_at: Option<i64>,
+}
+
+/// Fields left out are unchanged. `current_password` is required when
+/// changing `email` or `password`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileUpdate {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    pub current_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DependentData {
+    name: String,
+    relationship: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dependent {
+    pub id: i32,
+    pub name: String,
+    pub relationship: String,
+}
+
+/// Where a request came from, recorded against login attempts and sessions.
+#[derive(Debug, Default, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginData {
+    email: String,
+    password: String,
+}
+
+pub type JWT = String;
+
+/// Brute-force protection settings, read from the Rocket config
+/// (e.g. `ROCKET_MAX_FAILED_LOGINS=10`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginPolicy {
+    /// Consecutive failures on an email before the account is locked.
+    pub max_failed_logins: i32,
+    pub lockout_mins: i64,
+    pub backoff_base_secs: i64,
+    pub backoff_max_secs: i64,
+    /// Roles that must pass a TOTP check to log in.
+    pub totp_required_roles: Vec<AccountType>,
+    pub totp_challenge_secs: i64,
+    /// Reject password logins for accounts linked to an SSO identity.
+    pub disable_sso_password_login: bool,
+}
+
+impl Default for LoginPolicy {
+    fn default() -> Self {
+        Self {
+            max_failed_logins: 5,
+            lockout_mins: 15,
+            backoff_base_secs: 1,
+            backoff_max_secs: 60,
+            totp_required_roles: vec![],
+            totp_challenge_secs: 5 * 60,
+            disable_sso_password_login: false,
+        }
+    }
+}
+
+impl LoginPolicy {
+    /// Seconds to wait after the last failure, doubling with every failed attempt.
+    pub fn backoff_secs(&self, failed_attempts: i32) -> i64 {
+        if failed_attempts <= 0 {
+            return 0;
+        }
+        // Saturates rather than wrapping, so a large base can't turn negative.
+        let exp = (failed_attempts - 1).min(62) as u32;
+        self.backoff_base_secs
+            .saturating_mul(1 << exp)
+            .min(self.backoff_max_secs)
+            .max(0)
+    }
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ThrottleScope {
+    Email,
+    Ip,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginThrottle {
+    pub scope: ThrottleScope,
+    pub value: String,
+    pub failed_attempts: i32,
+    pub last_failure: i64,
+    pub locked_until: Option<i64>,
+}
+
+const TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+const TOTP_ISSUER: &str = "Backend Assessment";
+const API_KEY_PREFIX: &str = "bk_";
+/// How long a user has to complete the identity provider login.
+const OIDC_STATE_SECS: i64 = 10 * 60;
+/// `(table, column, definition)` for columns added to existing tables.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("account", "deactivated_at", "INTEGER"),
+    ("doctor", "languages", "TEXT NOT NULL default \"[]\""),
+    ("doctor", "qualifications", "TEXT NOT NULL default \"[]\""),
+    ("doctor", "consultation_fee", "INTEGER"),
+    ("doctor", "photo_url", "TEXT"),
+    ("doctor", "clinic", "VARCHAR(100) NOT NULL default \"\""),
+    (
+        "appointment",
+        "appointment_type",
+        "INTEGER REFERENCES appointment_type(id)",
+    ),
+    ("appointment", "referral", "INTEGER REFERENCES referral(id)"),
+    ("appointment_type", "fee", "INTEGER"),
+    (
+        "appointment_type",
+        "tax_rate_bps",
+        "INTEGER NOT NULL default 0",
+    ),
+    ("appointment_type", "deposit", "INTEGER"),
+    (
+        "patient",
+        "insurance_plan",
+        "INTEGER REFERENCES insurance_plan(id)",
+    ),
+    ("patient", "insurance_group_number", "VARCHAR(50)"),
+    ("patient", "insurance_valid_until", "CHAR(10)"),
+    (
+        "appointment_type",
+        "telehealth",
+        "INTEGER NOT NULL default 0",
+    ),
+    ("appointment_questionnaire", "name", "VARCHAR(100)"),
+    ("appointment_questionnaire", "questions", "TEXT"),
+];
+/// Columns read by `doctor_info_from_row`.
+/// Hidden reviews don't count towards the rating.
+fn doctor_info_columns() -> String {
+    format!(
+        "account.id, account.fullname, doctor.specialty, doctor.details,
+    doctor.languages, doctor.qualifications, doctor.consultation_fee, doctor.photo_url, doctor.clinic,
+    (SELECT avg(rating) FROM review WHERE review.doctor = account.id AND review_status != {0}),
+    (SELECT count(*) FROM review WHERE review.doctor = account.id AND review_status != {0})",
+        ReviewStatus::Hidden as i32
+    )
+}
+const MAX_PAGE_SIZE: u32 = 100;
+const MAX_REVIEW_CHARS: usize = 2000;
+/// Columns read by `referral_from_row`.
+const REFERRAL_COLUMNS: &str = "id, patient, referring_doctor, target_doctor, target_specialty,
+    reason, urgency, referral_status, appointment, created_at";
+/// Columns read by `visit_note_from_row`.
+const VISIT_NOTE_COLUMNS: &str = "appointment, version, author, chief_complaint, findings,
+    diagnosis_codes, plan, patient_summary, amendment_reason, created_at";
+/// Columns read by `prescription_from_row`.
+const PRESCRIPTION_COLUMNS: &str = "id, appointment, patient, doctor, drug, dose, frequency,
+    duration_days, refills, instructions, allergy_override, prescription_status, created_at,
+    expires_at";
+const MAX_PRESCRIPTION_DAYS: i32 = 365;
+const MAX_REFILLS: i32 = 12;
+/// Columns read by `attachment_from_row`.
+const ATTACHMENT_COLUMNS: &str = "id, patient, appointment, uploaded_by, filename, content_type,
+    size_bytes, sha256, storage_key, created_at";
+/// Content types accepted for attachments, checked by `content_matches_type`.
+const ATTACHMENT_TYPES: &[&str] = &[
+    "application/pdf",
+    "image/png",
+    "image/jpeg",
+    "application/dicom",
+    "text/plain",
+];
+const MAX_FILENAME_CHARS: usize = 200;
+/// Columns read by `questionnaire_from_row`.
+const QUESTIONNAIRE_COLUMNS: &str =
+    "id, name, appointment_type, required, questions, created_by, created_at";
+/// Selects the columns read by `thread_from_row`, counting messages unread
+/// by the account bound to `?1`.
+const THREAD_SELECT: &str = "SELECT message_thread.id, patient, doctor, appointment, subject,
+        created_by, message_thread.created_at,
+        (SELECT max(created_at) FROM message WHERE thread = message_thread.id) AS last_message_at,
+        (SELECT count(*) FROM message WHERE thread = message_thread.id AND author != ?1
+            AND id > coalesce((SELECT last_read FROM message_read
+                WHERE thread = message_thread.id AND account = ?1), 0))
+    FROM message_thread";
+const MAX_SUBJECT_CHARS: usize = 200;
+const MAX_MESSAGE_CHARS: usize = 5000;
+/// Columns read by `lab_order_from_row`.
+const LAB_ORDER_COLUMNS: &str = "id, appointment, patient, doctor, test_code, test_name, notes,
+    lab_status, ordered_at, collected_at, resulted_at, reviewed_at";
+const MAX_LAB_CODE_CHARS: usize = 20;
+const MAX_LAB_NAME_CHARS: usize = 100;
+/// Columns read by `appointment_type_from_row`.
+const APPOINTMENT_TYPE_COLUMNS: &str =
+    "id, name, referral_required, fee, tax_rate_bps, deposit, telehealth";
+/// Columns read by `invoice_from_row`.
+const INVOICE_COLUMNS: &str = "id, appointment, patient, doctor, invoice_status, created_at,
+    issued_at, paid_at, voided_at, void_reason";
+const MAX_INVOICE_LINE_CHARS: usize = 200;
+/// 100%, in basis points.
+const MAX_TAX_RATE_BPS: i64 = 10_000;
+/// Columns read by `insurance_plan_from_row`.
+const INSURANCE_PLAN_COLUMNS: &str =
+    "id, payer_name, payer_id, plan_name, covered_types, covered_specialties, copay";
+const MAX_PLAN_NAME_CHARS: usize = 100;
+const MAX_PAYER_ID_CHARS: usize = 50;
+/// Columns read by `claim_from_row`.
+const CLAIM_COLUMNS: &str = "id, appointment, invoice, patient, plan, member_id, group_number,
+    billed_amount, copay, claim_status, batch, created_at, submitted_at, decided_at, paid_at,
+    paid_amount, denial_reason";
+/// Columns read by `consent_document_from_row`.
+const CONSENT_DOCUMENT_COLUMNS: &str =
+    "id, code, version, title, body, purpose, created_by, created_at";
+const MAX_CONSENT_CODE_CHARS: usize = 50;
+/// Control characters FTS5 wraps matches in before `mark_html` runs.
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i32,
+    pub account_type: AccountType,
+    pub iat: u64,
+    pub exp: u64,
+    /// Session the token was issued for. Only API key requests go without.
+    pub sid: Option<i32>,
+}
+
+impl Claims {
+    pub fn new(sub: i32, account_type: AccountType) -> Self {
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        Self {
+            sub,
+            account_type,
+            iat,
+            exp: iat + TOKEN_TTL_SECS,
+            sid: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengePurpose {
+    Totp,
+    TotpEnrollment,
+}
+
+/// Short-lived token handed out by `login` while the second factor is pending.
+/// It can't be decoded as `Claims`, so `AccountGuard` rejects it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub sub: i32,
+    pub account_type: AccountType,
+    pub purpose: ChallengePurpose,
+    pub exp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LoginOutcome {
+    Authenticated(JWT),
+    TotpRequired(JWT),
+    TotpEnrollmentRequired(JWT),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyData {
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<FixedOffset>>,
+}
+
+/// An API key as shown to admins. The key itself is only known at creation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub created_by: i32,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorInfo {
+    pub id: i32,
+    pub name: String,
+    pub specialty: String,
+    /// Free-text bio.
+    pub details: String,
+    pub languages: Vec<String>,
+    pub qualifications: Vec<String>,
+    /// In the smallest currency unit, e.g. cents.
+    pub consultation_fee: Option<i64>,
+    pub photo_url: Option<String>,
+    pub clinic: String,
+    /// Average star rating, `None` without reviews.
+    pub rating: Option<f64>,
+    pub review_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentType {
+    pub id: i32,
+    pub name: String,
+    /// Bookings need a referral to the doctor or their specialty.
+    pub referral_required: bool,
+    /// Charged on top of the doctor's consultation fee.
+    pub fee: Option<i64>,
+    /// Tax applied to the appointment's invoice lines, in basis points.
+    pub tax_rate_bps: i64,
+    /// Paid by card when booking.
+    pub deposit: Option<i64>,
+    /// Held remotely. Booking needs the patient's telehealth consents.
+    pub telehealth: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentTypeData {
+    pub name: String,
+    #[serde(default)]
+    pub referral_required: bool,
+    pub fee: Option<i64>,
+    #[serde(default)]
+    pub tax_rate_bps: i64,
+    pub deposit: Option<i64>,
+    #[serde(default)]
+    pub telehealth: bool,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ReferralUrgency {
+    Routine,
+    Urgent,
+    Emergency,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ReferralStatus {
+    /// Waiting to be booked.
+    Open,
+    Booked,
+}
+
+/// Refers to either `target_doctor_id` or any doctor with `target_specialty`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReferralData {
+    pub patient_id: i32,
+    pub target_doctor_id: Option<i32>,
+    pub target_specialty: Option<String>,
+    pub reason: String,
+    pub urgency: ReferralUrgency,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Referral {
+    pub id: i32,
+    pub patient_id: i32,
+    pub referring_doctor_id: i32,
+    pub target_doctor_id: Option<i32>,
+    pub target_specialty: Option<String>,
+    pub reason: String,
+    pub urgency: ReferralUrgency,
+    pub status: ReferralStatus,
+    /// The appointment booked with the referral.
+    pub appointment_id: Option<i32>,
+    pub created_at: i64,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ReviewStatus {
+    Visible,
+    /// Marked for a moderator's attention; still shown.
+    Flagged,
+    /// Removed from listings and ratings.
+    Hidden,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviewData {
+    /// 1 to 5 stars.
+    pub rating: i32,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Review {
+    pub id: i32,
+    pub appointment_id: i32,
+    pub doctor_id: i32,
+    pub rating: i32,
+    pub text: String,
+    pub status: ReviewStatus,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VisitNoteData {
+    pub chief_complaint: String,
+    #[serde(default)]
+    pub findings: String,
+    /// ICD-10 codes.
+    #[serde(default)]
+    pub diagnosis_codes: Vec<String>,
+    #[serde(default)]
+    pub plan: String,
+    /// Plain-language summary shown to the patient.
+    #[serde(default)]
+    pub patient_summary: String,
+    /// Required for every version after the first.
+    pub amendment_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VisitNote {
+    pub appointment_id: i32,
+    pub version: i32,
+    pub author_id: i32,
+    pub chief_complaint: String,
+    pub findings: String,
+    pub diagnosis_codes: Vec<String>,
+    pub plan: String,
+    pub patient_summary: String,
+    pub amendment_reason: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VisitSummary {
+    pub summary: String,
+    pub version: i32,
+    pub updated_at: i64,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum PrescriptionStatus {
+    Active,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrescriptionData {
+    pub drug: String,
+    /// e.g. "500 mg"
+    pub dose: String,
+    /// e.g. "twice daily"
+    pub frequency: String,
+    pub duration_days: i32,
+    #[serde(default)]
+    pub refills: i32,
+    #[serde(default)]
+    pub instructions: String,
+    /// Why the drug is prescribed despite a matching allergy.
+    pub allergy_override: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Prescription {
+    pub id: i32,
+    pub appointment_id: i32,
+    pub patient_id: i32,
+    pub doctor_id: i32,
+    pub drug: String,
+    pub dose: String,
+    pub frequency: String,
+    pub duration_days: i32,
+    pub refills: i32,
+    pub instructions: String,
+    pub allergy_override: Option<String>,
+    pub status: PrescriptionStatus,
+    pub created_at: i64,
+    /// When the last refill's course ends.
+    pub expires_at: i64,
+}
+
+pub struct AttachmentUpload<'a> {
+    pub patient_id: i32,
+    pub appointment_id: Option<i32>,
+    pub uploaded_by: i32,
+    pub filename: &'a str,
+    pub content_type: &'a str,
+    pub data: &'a [u8],
+    /// Checksum sent by the client, verified against the contents.
+    pub sha256: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: i32,
+    pub patient_id: i32,
+    /// `None` for attachments on the patient rather than a visit.
+    pub appointment_id: Option<i32>,
+    pub uploaded_by: i32,
+    pub filename: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    /// Hex SHA-256 of the contents.
+    pub sha256: String,
+    pub created_at: i64,
+    #[serde(skip)]
+    storage_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum QuestionKind {
+    Text,
+    Number,
+    YesNo,
+    SingleChoice,
+    MultipleChoice,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Question {
+    /// Answers are keyed by this, so keep it stable when editing the template.
+    pub key: String,
+    pub text: String,
+    pub kind: QuestionKind,
+    #[serde(default)]
+    pub required: bool,
+    /// Choices for `SingleChoice` and `MultipleChoice`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl Question {
+    /// Whether `value` is the JSON type this question expects: a string,
+    /// number, boolean, one option, or a list of distinct options.
+    pub fn accepts(&self, value: &serde_json::Value) -> bool {
+        let is_option = |v: &serde_json::Value| {
+            v.as_str()
+                .is_some_and(|v| self.options.iter().any(|o| o == v))
+        };
+        match self.kind {
+            QuestionKind::Text => value.as_str().is_some_and(|v| !v.trim().is_empty()),
+            QuestionKind::Number => value.is_number(),
+            QuestionKind::YesNo => value.is_boolean(),
+            QuestionKind::SingleChoice => is_option(value),
+            QuestionKind::MultipleChoice => value.as_array().is_some_and(|values| {
+                let distinct: HashSet<_> = values.iter().filter_map(|v| v.as_str()).collect();
+                values.iter().all(is_option) && distinct.len() == values.len()
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuestionnaireData {
+    pub name: String,
+    pub appointment_type_id: i32,
+    /// Whether bookings of the type must answer it before check-in.
+    #[serde(default)]
+    pub required: bool,
+    pub questions: Vec<Question>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Questionnaire {
+    pub id: i32,
+    pub name: String,
+    pub appointment_type_id: i32,
+    pub required: bool,
+    pub questions: Vec<Question>,
+    pub created_by: i32,
+    pub created_at: i64,
+}
+
+/// A questionnaire attached to a booking, with the answers if submitted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentQuestionnaire {
+    pub questionnaire: Questionnaire,
+    pub required: bool,
+    pub answers: Option<BTreeMap<String, serde_json::Value>>,
+    pub submitted_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadData {
+    pub patient_id: i32,
+    pub doctor_id: i32,
+    pub appointment_id: Option<i32>,
+    pub subject: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageData {
+    pub body: String,
+    /// One of the patient's attachments.
+    pub attachment_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: i32,
+    pub patient_id: i32,
+    pub doctor_id: i32,
+    pub appointment_id: Option<i32>,
+    pub subject: String,
+    pub created_by: i32,
+    pub created_at: i64,
+    pub last_message_at: Option<i64>,
+    /// Messages from others the viewer hasn't read.
+    pub unread: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub id: i32,
+    pub thread_id: i32,
+    pub author_id: i32,
+    pub body: String,
+    pub attachment_id: Option<i32>,
+    pub created_at: i64,
+    pub read_by: Vec<i32>,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum LabOrderStatus {
+    Ordered,
+    Collected,
+    Resulted,
+    /// Reviewed by the doctor and released to the patient.
+    Reviewed,
+}
+
+/// Abnormal flags, as in HL7 v2 table 0078.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum LabFlag {
+    Normal,
+    Low,
+    High,
+    CriticalLow,
+    CriticalHigh,
+    Abnormal,
+}
+
+impl LabFlag {
+    pub fn from_hl7(flag: &str) -> Option<Self> {
+        match flag {
+            "N" => Some(Self::Normal),
+            "L" => Some(Self::Low),
+            "H" => Some(Self::High),
+            "LL" => Some(Self::CriticalLow),
+            "HH" => Some(Self::CriticalHigh),
+            "A" => Some(Self::Abnormal),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LabOrderData {
+    /// e.g. a LOINC code
+    pub test_code: String,
+    pub test_name: String,
+    #[serde(default)]
+    pub notes: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LabResultData {
+    pub code: String,
+    #[serde(default)]
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub units: String,
+    /// e.g. "3.5-5.0", "<200" or ">=60"
+    #[serde(default)]
+    pub reference_range: String,
+    /// Derived from the reference range when left out.
+    pub flag: Option<LabFlag>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LabResult {
+    pub code: String,
+    pub name: String,
+    pub value: String,
+    pub units: String,
+    pub reference_range: String,
+    pub flag: Option<LabFlag>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LabOrder {
+    pub id: i32,
+    pub appointment_id: i32,
+    pub patient_id: i32,
+    pub doctor_id: i32,
+    pub test_code: String,
+    pub test_name: String,
+    pub notes: String,
+    pub status: LabOrderStatus,
+    pub ordered_at: i64,
+    pub collected_at: Option<i64>,
+    pub resulted_at: Option<i64>,
+    pub reviewed_at: Option<i64>,
+    pub results: Vec<LabResult>,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum InvoiceStatus {
+    Draft,
+    Issued,
+    Paid,
+    Void,
+}
+
+/// Amounts are in the smallest currency unit, like consultation fees.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InvoiceLineData {
+    pub description: String,
+    #[serde(default = "default_quantity")]
+    pub quantity: i64,
+    pub unit_price: i64,
+    /// Taken off the line's amount before tax.
+    #[serde(default)]
+    pub discount: i64,
+    #[serde(default)]
+    pub tax_rate_bps: i64,
+}
+
+fn default_quantity() -> i64 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct InvoiceLine {
+    pub description: String,
+    pub quantity: i64,
+    pub unit_price: i64,
+    pub discount: i64,
+    pub tax_rate_bps: i64,
+    /// Rounded half up to the smallest currency unit.
+    pub tax: i64,
+    pub total: i64,
+}
+
+/// Fails with `InvalidInvoice` when the amounts don't fit in an `i64`.
+impl TryFrom<InvoiceLineData> for InvoiceLine {
+    type Error = Error;
+
+    fn try_from(line: InvoiceLineData) -> Result<Self, Error> {
+        let amounts = line
+            .quantity
+            .checked_mul(line.unit_price)
+            .and_then(|amount| amount.checked_sub(line.discount))
+            .and_then(|taxable| {
+                let tax = taxable
+                    .checked_mul(line.tax_rate_bps)?
+                    .checked_add(MAX_TAX_RATE_BPS / 2)?
+                    / MAX_TAX_RATE_BPS;
+                Some((tax, taxable.checked_add(tax)?))
+            });
+        let (tax, total) =
+            amounts.ok_or_else(|| Error::InvalidInvoice("line amount is too large".to_string()))?;
+        Ok(Self {
+            description: line.description,
+            quantity: line.quantity,
+            unit_price: line.unit_price,
+            discount: line.discount,
+            tax_rate_bps: line.tax_rate_bps,
+            tax,
+            total,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invoice {
+    pub id: i32,
+    pub appointment_id: i32,
+    pub patient_id: i32,
+    pub doctor_id: i32,
+    pub status: InvoiceStatus,
+    pub lines: Vec<InvoiceLine>,
+    /// Sum of the lines before discounts and tax.
+    pub subtotal: i64,
+    pub discount: i64,
+    pub tax: i64,
+    pub total: i64,
+    pub created_at: i64,
+    pub issued_at: Option<i64>,
+    pub paid_at: Option<i64>,
+    pub voided_at: Option<i64>,
+    pub void_reason: Option<String>,
+}
+
+/// A booking deposit. Amounts are in the smallest currency unit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Payment {
+    pub id: i32,
+    pub appointment_id: i32,
+    /// The payment provider's reference.
+    pub reference: String,
+    pub amount: i64,
+    pub refunded: i64,
+    pub created_at: i64,
+    pub refunded_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InsurancePlanData {
+    pub payer_name: String,
+    /// The payer's id at the clearinghouse.
+    pub payer_id: String,
+    pub plan_name: String,
+    /// Empty covers every appointment type.
+    #[serde(default)]
+    pub covered_appointment_types: Vec<i32>,
+    /// Empty covers every specialty.
+    #[serde(default)]
+    pub covered_specialties: Vec<String>,
+    /// Owed by the patient per covered visit.
+    #[serde(default)]
+    pub copay: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InsurancePlan {
+    pub id: i32,
+    pub payer_name: String,
+    pub payer_id: String,
+    pub plan_name: String,
+    pub covered_appointment_types: Vec<i32>,
+    pub covered_specialties: Vec<String>,
+    pub copay: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Coverage {
+    pub covered: bool,
+    /// Why the visit isn't covered.
+    pub reason: Option<String>,
+    /// The plan on the patient's policy, if any.
+    pub plan: Option<InsurancePlan>,
+    pub copay: Option<i64>,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ClaimStatus {
+    /// Waiting for the next export.
+    Pending,
+    Submitted,
+    Approved,
+    Denied,
+    Paid,
+    /// The invoice was voided before the claim was sent.
+    Cancelled,
+}
+
+/// Amounts are in the smallest currency unit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InsuranceClaim {
+    pub id: i32,
+    pub appointment_id: i32,
+    pub invoice_id: i32,
+    pub patient_id: i32,
+    pub plan_id: i32,
+    pub member_id: String,
+    pub group_number: Option<String>,
+    /// The invoice total.
+    pub billed_amount: i64,
+    pub copay: i64,
+    pub status: ClaimStatus,
+    /// The export batch the claim was submitted in.
+    pub batch_id: Option<i32>,
+    pub created_at: i64,
+    pub submitted_at: Option<i64>,
+    pub decided_at: Option<i64>,
+    pub paid_at: Option<i64>,
+    pub paid_amount: Option<i64>,
+    pub denial_reason: Option<String>,
+}
+
+/// An exported claim file in X12 837P layout.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimBatch {
+    pub id: i32,
+    pub created_at: i64,
+    pub claim_count: i64,
+    pub contents: String,
+}
+
+/// What accepting a consent document allows.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum ConsentPurpose {
+    /// Booking telehealth appointment types.
+    Telehealth,
+    /// Doctors seeing the patient's appointments with other doctors.
+    RecordSharing,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsentDocumentData {
+    /// Stable name across versions, e.g. `telehealth`.
+    pub code: String,
+    pub title: String,
+    pub body: String,
+    pub purpose: ConsentPurpose,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsentDocument {
+    pub id: i32,
+    pub code: String,
+    /// Counts from 1 for each code.
+    pub version: i32,
+    pub title: String,
+    pub body: String,
+    pub purpose: ConsentPurpose,
+    pub created_by: i32,
+    pub created_at: i64,
+}
+
+/// A patient's acceptance of one document version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Consent {
+    pub id: i32,
+    pub patient_id: i32,
+    pub document_id: i32,
+    pub code: String,
+    pub version: i32,
+    pub title: String,
+    pub purpose: ConsentPurpose,
+    /// False once a newer version is published.
+    pub current: bool,
+    /// The patient or their guardian.
+    pub accepted_by: i32,
+    pub accepted_at: i64,
+    pub withdrawn_by: Option<i32>,
+    pub withdrawn_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorMatch {
+    pub doctor: DoctorInfo,
+    /// Higher is a better match.
+    pub score: f64,
+    /// HTML with matched words wrapped in `<mark>`.
+    pub specialty: String,
+    /// HTML excerpt of `details` around the matches.
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DoctorSort {
+    Name,
+    FeeAsc,
+    FeeDesc,
+    /// Best rated first; unrated doctors come last.
+    Rating,
+}
+
+impl DoctorSort {
+    /// Sort key expression; doctors without a fee or rating come last.
+    fn key(&self) -> String {
+        match self {
+            DoctorSort::Name => "lower(account.fullname)".to_string(),
+            DoctorSort::FeeAsc => {
+                "coalesce(doctor.consultation_fee, 9223372036854775807)".to_string()
+            }
+            DoctorSort::FeeDesc => "coalesce(doctor.consultation_fee, -1)".to_string(),
+            DoctorSort::Rating => format!(
+                "coalesce((SELECT avg(rating) FROM review
+                    WHERE review.doctor = account.id AND review_status != {}), 0)",
+                ReviewStatus::Hidden as i32
+            ),
+        }
+    }
+
+    fn descending(&self) -> bool {
+        matches!(self, DoctorSort::FeeDesc | DoctorSort::Rating)
+    }
+}
+
+/// Doctor directory filters. Text filters are case-insensitive.
+#[derive(Debug)]
+pub struct DoctorQuery {
+    /// Matches any part of the doctor's name.
+    pub name: Option<String>,
+    pub specialty: Option<String>,
+    pub clinic: Option<String>,
+    pub language: Option<String>,
+    /// Only doctors with room for another appointment on this day.
+    pub available_on: Option<NaiveDate>,
+    pub sort: DoctorSort,
+    pub limit: u32,
+    pub cursor: Option<String>,
+}
+
+impl Default for DoctorQuery {
+    fn default() -> Self {
+        Self {
+            name: None,
+            specialty: None,
+            clinic: None,
+            language: None,
+            available_on: None,
+            sort: DoctorSort::Name,
+            limit: 20,
+            cursor: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorPage {
+    pub doctors: Vec<DoctorInfo>,
+    /// Pass as `cursor` to get the next page; `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Position after the last doctor of a page: its sort key and id.
+#[derive(Debug, Serialize, Deserialize)]
+struct DoctorCursor {
+    sort: DoctorSort,
+    key: CursorKey,
+    id: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum CursorKey {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl DoctorCursor {
+    fn encode(&self) -> String {
+        totp::base32_encode(serde_json::to_string(self).unwrap().as_bytes())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&totp::base32_decode(cursor)?).ok()
+    }
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum Sex {
+    Female,
+    Male,
+    Other,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatientDemographics {
+    pub date_of_birth: Option<NaiveDate>,
+    pub sex: Option<Sex>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmergencyContact {
+    pub name: String,
+    pub phone: Option<String>,
+    pub relationship: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatientContact {
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    pub emergency_contact: Option<EmergencyContact>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatientInsurance {
+    pub provider: Option<String>,
+    /// Also the member id on claims.
+    pub policy_number: Option<String>,
+    /// The plan claims are billed to.
+    pub plan_id: Option<i32>,
+    pub group_number: Option<String>,
+    /// Last day the policy covers.
+    pub valid_until: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatientClinical {
+    pub allergies: Vec<String>,
+}
+
+/// Field groups the caller isn't allowed to see are `None` and left out of
+/// the JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatientProfile {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub demographics: Option<PatientDemographics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<PatientContact>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insurance: Option<PatientInsurance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clinical: Option<PatientClinical>,
+}
+
+/// Each group that is present replaces the stored one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PatientProfileUpdate {
+    pub demographics: Option<PatientDemographics>,
+    pub contact: Option<PatientContact>,
+    pub insurance: Option<PatientInsurance>,
+    pub clinical: Option<PatientClinical>,
+}
+
+/// Fields left out are unchanged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DoctorProfileUpdate {
+    pub specialty: Option<String>,
+    pub details: Option<String>,
+    pub languages: Option<Vec<String>>,
+    pub qualifications: Option<Vec<String>>,
+    pub consultation_fee: Option<i64>,
+    pub photo_url: Option<String>,
+    pub clinic: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Appointment {
+    id: i32,
+    pub doctor_id: i32,
+    pub patient_id: i32,
+    pub start_date: DateTime<FixedOffset>,
+    pub duration: i32,
+    pub status: AppointmentStatus,
+    pub appointment_type_id: Option<i32>,
+    pub referral_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentRequest {
+    pub doctor_id: i32,
+    pub patient_id: i32,
+    pub start_date: DateTime<FixedOffset>,
+    pub duration: i32,
+    pub appointment_type_id: Option<i32>,
+    /// Required when the appointment type is `referral_required`.
+    pub referral_id: Option<i32>,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum AppointmentStatus {
+    Booked,
+    Cancelled,
+    Done,
+    CheckedIn,
+    /// The doctor was deactivated; the patient needs a new appointment.
+    NeedsRebooking,
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
+)]
+#[repr(i32)]
+pub enum AppointmentAction {
+    Booked,
+    Rescheduled,
+    CheckedIn,
+    Cancelled,
+    Completed,
+    FlaggedForRebooking,
+}
+
+/// Audit entry for a change made to an appointment and who made it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppointmentActionRecord {
+    pub actor: i32,
+    pub action: AppointmentAction,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoctorAppointmentStats {
+    pub doctor_id: i32,
+    pub appointments_count: i32,
+    pub booked_mins: i32,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        sha256_hex, totp, AccountType, ApiKeyData, AppointmentAction, AppointmentRequest,
+        AppointmentStatus, AppointmentTypeData, AttachmentUpload, ClaimStatus, ClientInfo,
+        ConsentDocumentData, ConsentPurpose, DependentData, DoctorProfileUpdate, DoctorQuery,
+        DoctorSort, Error, InsurancePlanData, Invoice, InvoiceLineData, InvoiceStatus, LabFlag,
+        LabOrderData, LabOrderStatus, LabResultData, LoginData, LoginOutcome, LoginPolicy,
+        MessageData, PatientClinical, PatientDemographics, PatientInsurance, PatientProfileUpdate,
+        PrescriptionData, ProfileUpdate, Question, QuestionKind, QuestionnaireData, ReferralData,
+        ReferralStatus, ReferralUrgency, RegisterData, ReviewData, ReviewStatus, Role, Sex,
+        ThreadData, ThrottleScope, VisitNoteData, DB,
     };
+    use crate::storage::{LocalStorage, Storage};
+    use chrono::{DateTime, NaiveDate};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize)]
+    struct MockData {
+        registerations: Vec<RegisterData>,
+    }
+
+    /// A fresh database with one account per type, numbered from 1.
+    fn setup(account_types: &[AccountType]) -> DB<'static> {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        for (i, account_type) in account_types.iter().enumerate() {
+            db.register(RegisterData {
+                name: format!("Account {}", i),
+                email: format!("account{}@test.com", i),
+                password: "password".to_string(),
+                account_type: *account_type,
+            })
+            .unwrap();
+        }
+        db
+    }
+
+    /// Checks the booking in and marks it done, as its doctor.
+    fn complete(db: &DB, appointment_id: i32) {
+        let doctor_id = db.get_appointment(appointment_id).unwrap().doctor_id;
+        db.set_appointment_status(appointment_id, AppointmentStatus::CheckedIn, doctor_id)
+            .unwrap();
+        db.set_appointment_status(appointment_id, AppointmentStatus::Done, doctor_id)
+            .unwrap();
+    }
+
+    /// A 30 minute visit booked by the patient and completed.
+    fn completed_visit(
+        db: &DB,
+        doctor_id: i32,
+        patient_id: i32,
+        start_date: &str,
+        appointment_type_id: Option<i32>,
+    ) -> i32 {
+        let appointment_id = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id,
+                    patient_id,
+                    start_date: DateTime::parse_from_rfc3339(start_date).unwrap(),
+                    duration: 30,
+                    appointment_type_id,
+                    referral_id: None,
+                },
+                patient_id,
+            )
+            .unwrap();
+        complete(db, appointment_id);
+        appointment_id
+    }
+
+    #[test]
+    fn test_registeration() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+
+        let r = mock.registerations.get(0).unwrap().clone();
+        db.register(r.clone()).unwrap();
+        let jwt = db
+            .login(
+                LoginData {
+                    email: r.email.clone(),
+                    password: r.password.clone(),
+                },
+                &ClientInfo::default(),
+                &LoginPolicy::default(),
+            )
+            .unwrap();
+
+        println!("{:?}", jwt);
+    }
+
+    #[test]
+    fn test_login_lockout() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
+        db.register(r.clone()).unwrap();
+
+        let policy = LoginPolicy {
+            backoff_base_secs: 60,
+            ..LoginPolicy::default()
+        };
+        assert_eq!(policy.backoff_secs(0), 0);
+        assert_eq!(policy.backoff_secs(1), 60);
+        // Large bases and attempt counts saturate at the maximum.
+        let huge = LoginPolicy {
+            backoff_base_secs: i64::MAX / 2,
+            backoff_max_secs: 3600,
+            ..LoginPolicy::default()
+        };
+        assert_eq!(huge.backoff_secs(3), 3600);
+        assert_eq!(huge.backoff_secs(i32::MAX), 3600);
+        let login = |password: &str| {
+            db.login(
+                LoginData {
+                    email: r.email.clone(),
+                    password: password.to_string(),
+                },
+                &ClientInfo {
+                    ip: Some("127.0.0.1".to_string()),
+                    user_agent: None,
+                },
+                &policy,
+            )
+        };
+
+        assert!(matches!(login("wrong"), Err(Error::InvalidPassword)));
+        assert!(matches!(login(&r.password), Err(Error::LoginThrottled(_))));
+
+        let now = super::unix_now();
+        for _ in 1..policy.max_failed_logins {
+            db.record_login_failure(ThrottleScope::Email, &r.email, now, &policy)
+                .unwrap();
+        }
+        assert!(matches!(login(&r.password), Err(Error::AccountLocked(_))));
+        assert_eq!(db.login_throttles(now).unwrap().len(), 2);
+
+        db.clear_login_throttle(ThrottleScope::Email, &r.email)
+            .unwrap();
+        db.clear_login_throttle(ThrottleScope::Ip, "127.0.0.1")
+            .unwrap();
+        login(&r.password).unwrap();
+    }
+
+    #[test]
+    fn test_totp_login() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
+        db.register(r.clone()).unwrap();
+
+        let policy = LoginPolicy::default();
+        let login = || {
+            db.login(
+                LoginData {
+                    email: r.email.clone(),
+                    password: r.password.clone(),
+                },
+                &ClientInfo::default(),
+                &policy,
+            )
+            .unwrap()
+        };
+        assert!(matches!(login(), LoginOutcome::Authenticated(_)));
+
+        let enrollment = db.begin_totp_enrollment(1).unwrap();
+        assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/"));
+
+        let secret = totp::base32_decode(&enrollment.secret).unwrap();
+        let code = format!(
+            "{:06}",
+            totp::hotp(&secret, (super::unix_now() / totp::PERIOD) as u64)
+        );
+        let recovery_codes = db.confirm_totp_enrollment(1, &code).unwrap();
+
+        let challenge = match login() {
+            LoginOutcome::TotpRequired(challenge) => challenge,
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        assert!(super::decode_jwt(&challenge).is_err());
+
+        // The enrolment code was already used, so it can't be replayed.
+        let client = ClientInfo {
+            ip: Some("10.0.0.1".to_string()),
+            user_agent: None,
+        };
+        assert!(matches!(
+            db.login_totp(&challenge, &code, &client, &policy),
+            Err(Error::InvalidTotpCode)
+        ));
+        // Wrong codes count against the client's address too.
+        assert!(db
+            .login_throttles(super::unix_now())
+            .unwrap()
+            .iter()
+            .any(|t| t.scope == ThrottleScope::Ip && t.value == "10.0.0.1"));
+        db.clear_login_throttle(ThrottleScope::Email, &r.email)
+            .unwrap();
+        db.clear_login_throttle(ThrottleScope::Ip, "10.0.0.1")
+            .unwrap();
+
+        let jwt = db
+            .login_totp(
+                &challenge,
+                &recovery_codes[0],
+                &ClientInfo::default(),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(super::decode_jwt(&jwt).unwrap().sub, 1);
+        assert!(!db.verify_totp(1, &recovery_codes[0], 0).unwrap());
+
+        assert!(matches!(
+            db.disable_totp(1, Some("wrong"), None),
+            Err(Error::InvalidPassword)
+        ));
+        assert!(matches!(
+            db.disable_totp(1, None, Some(&recovery_codes[0])),
+            Err(Error::InvalidTotpCode)
+        ));
+        db.disable_totp(1, None, Some(&recovery_codes[1])).unwrap();
+        assert!(!db.totp_enabled(1).unwrap());
+        assert!(matches!(login(), LoginOutcome::Authenticated(_)));
+        assert!(matches!(
+            db.disable_totp(1, Some(&r.password), None),
+            Err(Error::TotpNotEnrolled)
+        ));
+
+        // Enrolling again works, and the password also turns it off.
+        let enrollment = db.begin_totp_enrollment(1).unwrap();
+        let secret = totp::base32_decode(&enrollment.secret).unwrap();
+        let code = format!(
+            "{:06}",
+            totp::hotp(&secret, (super::unix_now() / totp::PERIOD) as u64)
+        );
+        db.confirm_totp_enrollment(1, &code).unwrap();
+        db.disable_totp(1, Some(&r.password), None).unwrap();
+        assert!(!db.totp_enabled(1).unwrap());
+    }
+
+    #[test]
+    fn test_roles() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        db.register(mock.registerations.first().unwrap().clone())
+            .unwrap();
+
+        let permissions = db.account_permissions(1, AccountType::Doctor).unwrap();
+        assert!(permissions.contains("appointment.cancel.own"));
+        assert!(!permissions.contains("appointment.cancel.any"));
+
+        // Only the patient's side can give consent.
+        for account_type in [
+            AccountType::Doctor,
+            AccountType::Admin,
+            AccountType::Receptionist,
+        ] {
+            let permissions = db.account_permissions(1, account_type).unwrap();
+            assert!(!permissions.iter().any(|p| p.starts_with("consent.give")));
+        }
+        assert!(db
+            .account_permissions(1, AccountType::Patient)
+            .unwrap()
+            .contains("consent.give.own"));
+        // Completing a visit is the doctor's sign-off.
+        assert!(!db
+            .account_permissions(1, AccountType::Receptionist)
+            .unwrap()
+            .iter()
+            .any(|p| p.starts_with("appointment.complete")));
+
+        db.save_role(&Role {
+            name: "scheduler".to_string(),
+            permissions: vec!["appointment.cancel.any".to_string()],
+        })
+        .unwrap();
+        assert!(db.assign_role(1, "unknown").is_err());
+        db.assign_role(1, "scheduler").unwrap();
+        assert!(db
+            .account_permissions(1, AccountType::Doctor)
+            .unwrap()
+            .contains("appointment.cancel.any"));
+
+        db.revoke_role(1, "scheduler").unwrap();
+        assert!(!db
+            .account_permissions(1, AccountType::Doctor)
+            .unwrap()
+            .contains("appointment.cancel.any"));
+
+        // Re-seeding must not overwrite roles that already exist.
+        db.save_role(&Role {
+            name: "doctor".to_string(),
+            permissions: vec![],
+        })
+        .unwrap();
+        db.init_schema().unwrap();
+        assert!(db
+            .account_permissions(1, AccountType::Doctor)
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.roles().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_receptionist_booking() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        db.register(mock.registerations.first().unwrap().clone())
+            .unwrap();
+        db.register(RegisterData {
+            name: "Front Desk".to_string(),
+            email: "desk@test.com".to_string(),
+            password: "password".to_string(),
+            account_type: AccountType::Receptionist,
+        })
+        .unwrap();
+
+        db.con()
+            .execute(
+                "INSERT INTO account (fullname, email, passhash, account_type)
+                 VALUES ('Patient', 'patient@test.com', '', ?1)",
+                [AccountType::Patient as i32],
+            )
+            .unwrap();
+        db.con()
+            .execute("INSERT INTO patient (id) VALUES (3)", [])
+            .unwrap();
+
+        let permissions = db
+            .account_permissions(2, AccountType::Receptionist)
+            .unwrap();
+        assert!(permissions.contains("appointment.book.any"));
+        assert!(!permissions.contains("stats.read"));
+
+        let start_date = DateTime::parse_from_rfc3339("2022-03-01T10:00:00+04:00").unwrap();
+        assert!(db.is_valid_appointment_request(1, &start_date, 30).unwrap());
+        assert!(!db
+            .is_valid_appointment_request(99, &start_date, 30)
+            .unwrap());
+        // Only patients can be booked for.
+        for patient_id in [1, 2, 99] {
+            assert!(matches!(
+                db.book_appointment(
+                    AppointmentRequest {
+                        doctor_id: 1,
+                        patient_id,
+                        start_date,
+                        duration: 30,
+                        appointment_type_id: None,
+                        referral_id: None,
+                    },
+                    2,
+                ),
+                Err(Error::UnknownPatient)
+            ));
+        }
+        let appointment_id = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 3,
+                    start_date,
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap();
+
+        let later = DateTime::parse_from_rfc3339("2022-03-01T12:00:00+04:00").unwrap();
+        db.reschedule_appointment(appointment_id, &later, 45, 2)
+            .unwrap();
+        db.set_appointment_status(appointment_id, AppointmentStatus::CheckedIn, 2)
+            .unwrap();
+        // Only booked appointments can be checked in or moved.
+        assert!(matches!(
+            db.set_appointment_status(appointment_id, AppointmentStatus::CheckedIn, 2),
+            Err(Error::AppointmentStatus(AppointmentStatus::CheckedIn))
+        ));
+        assert!(matches!(
+            db.reschedule_appointment(appointment_id, &later, 30, 2),
+            Err(Error::AppointmentStatus(AppointmentStatus::CheckedIn))
+        ));
+
+        let appointment = db.get_appointment(appointment_id).unwrap();
+        assert_eq!(appointment.patient_id, 3);
+        assert_eq!(appointment.start_date, later);
+        assert_eq!(appointment.status, AppointmentStatus::CheckedIn);
+
+        let actions = db.get_appointment_actions(appointment_id).unwrap();
+        assert_eq!(
+            actions.iter().map(|a| a.action).collect::<Vec<_>>(),
+            vec![
+                AppointmentAction::Booked,
+                AppointmentAction::Rescheduled,
+                AppointmentAction::CheckedIn
+            ]
+        );
+        assert!(actions.iter().all(|a| a.actor == 2));
+    }
+
+    #[test]
+    fn test_dependents() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
+        db.register(r.clone()).unwrap();
+
+        let dependent = db
+            .create_dependent(
+                1,
+                DependentData {
+                    name: "Child".to_string(),
+                    relationship: "Son".to_string(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(db.dependents(1).unwrap()[0].id, dependent);
+        assert_eq!(db.patient_owners(dependent).unwrap(), vec![dependent, 1]);
+        assert!(db
+            .account_permissions(1, AccountType::Patient)
+            .unwrap()
+            .contains("dependents.manage"));
+
+        db.remove_dependent(1, dependent).unwrap();
+        assert!(db.dependents(1).unwrap().is_empty());
+        assert_eq!(db.patient_owners(dependent).unwrap(), vec![dependent]);
+        assert!(!db.account_active(dependent).unwrap());
+        assert!(db.remove_dependent(1, dependent).is_err());
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        db.register(mock.registerations.first().unwrap().clone())
+            .unwrap();
+
+        let data = ApiKeyData {
+            name: "reminders".to_string(),
+            permissions: vec!["stats.read".to_string()],
+            expires_at: Some(DateTime::parse_from_rfc3339("2030-01-01T00:00:00Z").unwrap()),
+        };
+        // A key can't hold more than the account that creates it.
+        assert!(matches!(
+            db.create_api_key(1, &data),
+            Err(Error::PermissionsNotHeld(p)) if p == vec!["stats.read".to_string()]
+        ));
+        db.assign_role(1, "admin").unwrap();
+        let (id, key) = db.create_api_key(1, &data).unwrap();
+
+        let now = super::unix_now();
+        let (claims, permissions) = db.authenticate_api_key(&key, now).unwrap().unwrap();
+        assert_eq!(claims.sub, 1);
+        assert!(permissions.contains("stats.read"));
+        assert!(!permissions.contains("appointment.cancel.own"));
+
+        let listed = db.api_keys().unwrap();
+        assert_eq!(listed[0].last_used_at, Some(now));
+        assert_eq!(listed[0].permissions, vec!["stats.read".to_string()]);
+
+        let expiry = DateTime::parse_from_rfc3339("2030-01-01T00:00:00Z").unwrap();
+        assert!(db
+            .authenticate_api_key(&key, expiry.timestamp())
+            .unwrap()
+            .is_none());
+        assert!(db.authenticate_api_key("bk_wrong", now).unwrap().is_none());
+
+        db.revoke_role(1, "admin").unwrap();
+        let (_, permissions) = db.authenticate_api_key(&key, now).unwrap().unwrap();
+        assert!(!permissions.contains("stats.read"));
+
+        db.revoke_api_key(id).unwrap();
+        assert!(db.authenticate_api_key(&key, now).unwrap().is_none());
+
+        db.assign_role(1, "admin").unwrap();
+        let (_, key) = db.create_api_key(1, &data).unwrap();
+        db.revoke_api_keys(1).unwrap();
+        assert!(db.authenticate_api_key(&key, now).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
+        db.register(r.clone()).unwrap();
+
+        let client = ClientInfo {
+            ip: Some("10.0.0.1".to_string()),
+            user_agent: Some("test-agent".to_string()),
+        };
+        let login = || match db
+            .login(
+                LoginData {
+                    email: r.email.clone(),
+                    password: r.password.clone(),
+                },
+                &client,
+                &LoginPolicy::default(),
+            )
+            .unwrap()
+        {
+            LoginOutcome::Authenticated(jwt) => super::decode_jwt(&jwt).unwrap(),
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+
+        let first = login().sid.unwrap();
+        let second = login().sid.unwrap();
+        let now = super::unix_now();
+
+        let sessions = db.sessions(1).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("test-agent"));
+        assert_eq!(sessions[0].ip.as_deref(), Some("10.0.0.1"));
+
+        db.revoke_session(1, first).unwrap();
+        assert!(db.revoke_session(1, first).is_err());
+        assert!(!db.touch_session(first, now).unwrap());
+        assert!(db.touch_session(second, now).unwrap());
+
+        db.revoke_sessions(1).unwrap();
+        assert!(!db.touch_session(second, now).unwrap());
+        assert!(db.sessions(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sso_login() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
+        db.register(r.clone()).unwrap();
+        db.con()
+            .execute(
+                "INSERT INTO account (id, fullname, email, passhash, account_type)
+                 VALUES (2, 'Patient', 'patient@test.com', '', ?1)",
+                [AccountType::Patient as i32],
+            )
+            .unwrap();
+        db.con()
+            .execute("INSERT INTO patient (id) VALUES (2)", [])
+            .unwrap();
+
+        let client = ClientInfo {
+            ip: None,
+            user_agent: None,
+        };
+        let issuer = "https://idp.test";
+
+        // Linked by email on first login, then by subject.
+        let jwt = db
+            .login_sso(issuer, "staff-1", Some(&r.email), &client)
+            .unwrap();
+        assert_eq!(super::decode_jwt(&jwt).unwrap().sub, 1);
+        assert!(db.sso_linked(1).unwrap());
+        let jwt = db.login_sso(issuer, "staff-1", None, &client).unwrap();
+        assert_eq!(super::decode_jwt(&jwt).unwrap().sub, 1);
+
+        assert!(matches!(
+            db.login_sso(issuer, "staff-2", Some("unknown@test.com"), &client),
+            Err(Error::SsoAccountNotFound)
+        ));
+        assert!(matches!(
+            db.login_sso(issuer, "patient", Some("patient@test.com"), &client),
+            Err(Error::SsoAccountNotFound)
+        ));
+
+        let login = |policy: &LoginPolicy| {
+            db.login(
+                LoginData {
+                    email: r.email.clone(),
+                    password: r.password.clone(),
+                },
+                &client,
+                policy,
+            )
+        };
+        assert!(matches!(
+            login(&LoginPolicy::default()),
+            Ok(LoginOutcome::Authenticated(_))
+        ));
+        assert!(matches!(
+            login(&LoginPolicy {
+                disable_sso_password_login: true,
+                ..LoginPolicy::default()
+            }),
+            Err(Error::PasswordLoginDisabled)
+        ));
+
+        let (state, nonce) = db.begin_oidc_login().unwrap();
+        assert_eq!(db.take_oidc_state(&state).unwrap(), Some(nonce));
+        assert_eq!(db.take_oidc_state(&state).unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrations() {
+        let db = DB::init(None).unwrap();
+        db.con()
+            .execute_batch(
+                "CREATE TABLE account (id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+                    fullname VARCHAR(100) NOT NULL, email VARCHAR(62) NOT NULL UNIQUE,
+                    passhash VARCHAR(60) NOT NULL, account_type INTEGER NOT NULL);
+                 CREATE TABLE doctor (id INTEGER PRIMARY KEY NOT NULL UNIQUE,
+                    specialty VARCHAR(100) NOT NULL default '', details TEXT NOT NULL default '',
+                    starting_hour char(5) NOT NULL default '08:00',
+                    ending_hour char(5) NOT NULL default '18:00');
+                 INSERT INTO account VALUES (1, 'Doc', 'doc@test.com', '', 0);
+                 INSERT INTO doctor (id) VALUES (1);",
+            )
+            .unwrap();
+
+        db.init_schema().unwrap();
+        db.init_schema().unwrap();
+
+        assert!(db.account_active(1).unwrap());
+        assert!(db.get_doctor_info(1).unwrap().languages.is_empty());
+    }
+
+    #[test]
+    fn test_doctor_profile() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        db.register(mock.registerations.first().unwrap().clone())
+            .unwrap();
+
+        let info = db.get_doctor_info(1).unwrap();
+        assert_eq!(info.specialty, "");
+        assert_eq!(info.consultation_fee, None);
+
+        db.update_doctor_profile(
+            1,
+            &DoctorProfileUpdate {
+                specialty: Some("Cardiology".to_string()),
+                languages: Some(vec!["en".to_string(), "ar".to_string()]),
+                consultation_fee: Some(5000),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+        db.update_doctor_profile(
+            1,
+            &DoctorProfileUpdate {
+                details: Some("Heart specialist".to_string()),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+
+        let info = db.get_doctor_info(1).unwrap();
+        assert_eq!(info.specialty, "Cardiology");
+        assert_eq!(info.details, "Heart specialist");
+        assert_eq!(info.languages, vec!["en", "ar"]);
+        assert_eq!(info.consultation_fee, Some(5000));
+
+        assert!(matches!(
+            db.update_doctor_profile(
+                1,
+                &DoctorProfileUpdate {
+                    consultation_fee: Some(-1),
+                    ..DoctorProfileUpdate::default()
+                }
+            ),
+            Err(Error::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            db.update_doctor_profile(2, &DoctorProfileUpdate::default()),
+            Err(Error::DBError(rusqlite::Error::QueryReturnedNoRows))
+        ));
+    }
+
+    #[test]
+    fn test_doctor_search() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let doctors = [
+            ("Alice Smith", "Cardiology", "North", "en", Some(5000)),
+            ("Bob Jones", "Cardiology", "South", "ar", None),
+            ("Carol Smith", "Dermatology", "North", "en", Some(8000)),
+        ];
+        for (i, (name, specialty, clinic, language, fee)) in doctors.iter().enumerate() {
+            db.register(RegisterData {
+                name: name.to_string(),
+                email: format!("doctor{}@test.com", i),
+                password: "password".to_string(),
+                account_type: AccountType::Doctor,
+            })
+            .unwrap();
+            db.update_doctor_profile(
+                i as i32 + 1,
+                &DoctorProfileUpdate {
+                    specialty: Some(specialty.to_string()),
+                    clinic: Some(clinic.to_string()),
+                    languages: Some(vec![language.to_string()]),
+                    consultation_fee: *fee,
+                    ..DoctorProfileUpdate::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let ids = |query: &DoctorQuery| -> Vec<i32> {
+            db.search_doctors(query)
+                .unwrap()
+                .doctors
+                .iter()
+                .map(|d| d.id)
+                .collect()
+        };
+
+        assert_eq!(ids(&DoctorQuery::default()), vec![1, 2, 3]);
+        assert_eq!(
+            ids(&DoctorQuery {
+                name: Some("smith".to_string()),
+                ..DoctorQuery::default()
+            }),
+            vec![1, 3]
+        );
+        assert_eq!(
+            ids(&DoctorQuery {
+                specialty: Some("cardiology".to_string()),
+                clinic: Some("north".to_string()),
+                ..DoctorQuery::default()
+            }),
+            vec![1]
+        );
+        assert_eq!(
+            ids(&DoctorQuery {
+                language: Some("AR".to_string()),
+                ..DoctorQuery::default()
+            }),
+            vec![2]
+        );
+        assert_eq!(
+            ids(&DoctorQuery {
+                sort: DoctorSort::FeeDesc,
+                ..DoctorQuery::default()
+            }),
+            vec![3, 1, 2]
+        );
+
+        // Walk the fee-ordered directory one doctor at a time.
+        let mut cursor = None;
+        let mut seen = vec![];
+        loop {
+            let page = db
+                .search_doctors(&DoctorQuery {
+                    sort: DoctorSort::FeeAsc,
+                    limit: 1,
+                    cursor: cursor.clone(),
+                    ..DoctorQuery::default()
+                })
+                .unwrap();
+            seen.extend(page.doctors.iter().map(|d| d.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec![1, 3, 2]);
+        assert!(matches!(
+            db.search_doctors(&DoctorQuery {
+                cursor: cursor.or(Some("not a cursor".to_string())),
+                ..DoctorQuery::default()
+            }),
+            Err(Error::InvalidCursor)
+        ));
+
+        db.register(RegisterData {
+            name: "Patient".to_string(),
+            email: "patient@test.com".to_string(),
+            password: "password".to_string(),
+            account_type: AccountType::Patient,
+        })
+        .unwrap();
+        for hour in [8, 10, 12, 14] {
+            db.book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 4,
+                    start_date: DateTime::parse_from_rfc3339(&format!(
+                        "2030-01-01T{:02}:00:00+00:00",
+                        hour
+                    ))
+                    .unwrap(),
+                    duration: 120,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                4,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            ids(&DoctorQuery {
+                available_on: NaiveDate::from_ymd_opt(2030, 1, 1),
+                ..DoctorQuery::default()
+            }),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn test_doctor_full_text_search() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let profiles = [
+            (
+                "Orthopedics",
+                "Sports injuries, knee pain and joint replacement.",
+            ),
+            ("Pediatrics", "Childhood asthma and pediatric allergy care."),
+            ("Allergy", "Adult allergies & <b>hay fever</b>."),
+        ];
+        for (i, (specialty, details)) in profiles.iter().enumerate() {
+            db.register(RegisterData {
+                name: format!("Doctor {}", i),
+                email: format!("doctor{}@test.com", i),
+                password: "password".to_string(),
+                account_type: AccountType::Doctor,
+            })
+            .unwrap();
+            db.update_doctor_profile(
+                i as i32 + 1,
+                &DoctorProfileUpdate {
+                    specialty: Some(specialty.to_string()),
+                    details: Some(details.to_string()),
+                    ..DoctorProfileUpdate::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let ids = |text: &str| -> Vec<i32> {
+            db.full_text_search_doctors(text, 20)
+                .unwrap()
+                .iter()
+                .map(|m| m.doctor.id)
+                .collect()
+        };
+
+        assert_eq!(ids("knee pain"), vec![1]);
+        assert_eq!(ids("pediatric allergy"), vec![2]);
+        // Stemming matches "allergies", and the specialty column weighs more.
+        assert_eq!(ids("allergy"), vec![3, 2]);
+        assert!(ids("\"unbalanced OR").is_empty());
+        assert!(ids("  ").is_empty());
+
+        let hit = &db.full_text_search_doctors("hay", 20).unwrap()[0];
+        assert_eq!(hit.doctor.id, 3);
+        assert!(hit
+            .snippet
+            .contains("&amp; &lt;b&gt;<mark>hay</mark> fever&lt;/b&gt;"));
+
+        db.update_doctor_profile(
+            1,
+            &DoctorProfileUpdate {
+                details: Some("Shoulder surgery.".to_string()),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+        assert!(ids("knee").is_empty());
+        assert_eq!(ids("shoulder"), vec![1]);
+
+        db.deactivate_account(1, 1).unwrap();
+        assert!(ids("shoulder").is_empty());
+    }
+
+    #[test]
+    fn test_reviews() {
+        let db = setup(&[
+            AccountType::Doctor,
+            AccountType::Doctor,
+            AccountType::Patient,
+        ]);
+
+        let visit = |doctor_id: i32, day: u32| {
+            let start_date = format!("2022-01-{:02}T10:00:00+00:00", day);
+            completed_visit(&db, doctor_id, 3, &start_date, None)
+        };
+        let review = |appointment_id: i32, rating: i32| {
+            db.create_review(
+                appointment_id,
+                3,
+                &ReviewData {
+                    rating,
+                    text: "Great".to_string(),
+                },
+            )
+        };
+
+        let first = visit(1, 1);
+        let second = visit(1, 2);
+        let third = visit(2, 3);
+
+        assert!(matches!(review(first, 6), Err(Error::InvalidReview(_))));
+        let first_review = review(first, 5).unwrap();
+        assert!(matches!(review(first, 4), Err(Error::ReviewExists)));
+        review(second, 2).unwrap();
+        review(third, 4).unwrap();
+
+        let info = db.get_doctor_info(1).unwrap();
+        assert_eq!(info.review_count, 2);
+        assert_eq!(info.rating, Some(3.5));
+
+        let ids = |sort: DoctorSort| -> Vec<i32> {
+            db.search_doctors(&DoctorQuery {
+                sort,
+                ..DoctorQuery::default()
+            })
+            .unwrap()
+            .doctors
+            .iter()
+            .map(|d| d.id)
+            .collect()
+        };
+        assert_eq!(ids(DoctorSort::Rating), vec![2, 1]);
+
+        db.moderate_review(first_review, ReviewStatus::Hidden, 2)
+            .unwrap();
+        let info = db.get_doctor_info(1).unwrap();
+        assert_eq!(info.review_count, 1);
+        assert_eq!(info.rating, Some(2.0));
+        assert_eq!(db.doctor_reviews(1).unwrap().len(), 1);
+        assert_eq!(
+            db.reviews_with_status(ReviewStatus::Hidden).unwrap()[0].id,
+            first_review
+        );
+
+        let booked = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 2,
+                    patient_id: 3,
+                    start_date: DateTime::parse_from_rfc3339("2022-02-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                3,
+            )
+            .unwrap();
+        assert!(matches!(review(booked, 5), Err(Error::InvalidReview(_))));
+    }
+
+    #[test]
+    fn test_visit_notes() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+
+        let appointment = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339("2022-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap();
+        let note = |codes: &[&str], amendment_reason: Option<&str>| VisitNoteData {
+            chief_complaint: "Sore throat".to_string(),
+            findings: "Red pharynx".to_string(),
+            diagnosis_codes: codes.iter().map(|c| c.to_string()).collect(),
+            plan: "Rest".to_string(),
+            patient_summary: "A cold, rest for a few days.".to_string(),
+            amendment_reason: amendment_reason.map(str::to_string),
+        };
+
+        assert!(matches!(
+            db.add_visit_note(appointment, 1, &note(&["J06.9"], None)),
+            Err(Error::InvalidVisitNote(_))
+        ));
+        assert_eq!(
+            db.visit_summary(appointment).unwrap().map(|s| s.version),
+            None
+        );
+        db.set_appointment_status(appointment, AppointmentStatus::CheckedIn, 1)
+            .unwrap();
+
+        assert!(matches!(
+            db.add_visit_note(appointment, 1, &note(&["sore throat"], None)),
+            Err(Error::InvalidVisitNote(_))
+        ));
+        assert_eq!(
+            db.add_visit_note(appointment, 1, &note(&["j06.9", "J06.9"], None))
+                .unwrap(),
+            1
+        );
+        assert!(matches!(
+            db.add_visit_note(appointment, 1, &note(&["J02"], None)),
+            Err(Error::InvalidVisitNote(_))
+        ));
+        assert_eq!(
+            db.add_visit_note(
+                appointment,
+                1,
+                &note(&["J02"], Some("Strep test came back"))
+            )
+            .unwrap(),
+            2
+        );
+
+        let notes = db.visit_notes(appointment).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].diagnosis_codes, vec!["J06.9"]);
+        assert_eq!(notes[0].amendment_reason, None);
+        assert_eq!(notes[1].diagnosis_codes, vec!["J02"]);
+        assert_eq!(db.visit_summary(appointment).unwrap().unwrap().version, 2);
+
+        // Earlier versions can't be rewritten.
+        assert!(db
+            .con()
+            .execute("UPDATE visit_note SET plan = 'Nothing'", [])
+            .is_err());
+        assert!(db.con().execute("DELETE FROM visit_note", []).is_err());
+    }
+
+    #[test]
+    fn test_prescriptions() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        db.update_patient_profile(
+            2,
+            &PatientProfileUpdate {
+                clinical: Some(PatientClinical {
+                    allergies: vec!["Penicillin".to_string()],
+                }),
+                ..PatientProfileUpdate::default()
+            },
+        )
+        .unwrap();
+
+        let appointment = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339("2022-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap();
+        let prescription =
+            |drug: &str, duration_days: i32, allergy_override: Option<&str>| PrescriptionData {
+                drug: drug.to_string(),
+                dose: "500 mg".to_string(),
+                frequency: "twice daily".to_string(),
+                duration_days,
+                refills: 1,
+                instructions: "With food".to_string(),
+                allergy_override: allergy_override.map(str::to_string),
+            };
+
+        assert!(matches!(
+            db.create_prescription(appointment, &prescription("Ibuprofen", 7, None)),
+            Err(Error::InvalidPrescription(_))
+        ));
+        complete(&db, appointment);
+        assert!(matches!(
+            db.create_prescription(appointment, &prescription("Ibuprofen", 0, None)),
+            Err(Error::InvalidPrescription(_))
+        ));
+
+        match db.create_prescription(appointment, &prescription("Penicillin V", 7, None)) {
+            Err(Error::AllergyConflict(allergies)) => assert_eq!(allergies, vec!["Penicillin"]),
+            other => panic!("{:?}", other),
+        }
+        let overridden = db
+            .create_prescription(
+                appointment,
+                &prescription("Penicillin V", 7, Some("Mild reaction only")),
+            )
+            .unwrap();
+        let ibuprofen = db
+            .create_prescription(appointment, &prescription("Ibuprofen", 7, Some("Unused")))
+            .unwrap();
+
+        let ibuprofen = db.get_prescription(ibuprofen).unwrap();
+        assert_eq!(ibuprofen.allergy_override, None);
+        assert_eq!(
+            ibuprofen.expires_at - ibuprofen.created_at,
+            14 * 24 * 60 * 60
+        );
+        assert_eq!(
+            db.get_prescription(overridden).unwrap().allergy_override,
+            Some("Mild reaction only".to_string())
+        );
+
+        db.cancel_prescription(overridden).unwrap();
+        let active: Vec<i32> = db
+            .patient_prescriptions(2, true)
+            .unwrap()
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(active, vec![ibuprofen.id]);
+        assert_eq!(db.patient_prescriptions(2, false).unwrap().len(), 2);
+        assert_eq!(db.prescription_owners(&ibuprofen).unwrap(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_attachments() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+        let root = std::env::temp_dir().join(format!("db-attachments-{}", std::process::id()));
+        let storage = LocalStorage::new(root.clone());
+
+        db.register(RegisterData {
+            name: "Patient".to_string(),
+            email: "patient@test.com".to_string(),
+            password: "password".to_string(),
+            account_type: AccountType::Patient,
+        })
+        .unwrap();
+
+        let pdf = b"%PDF-1.4 results".to_vec();
+        let upload =
+            |filename: &'static str, content_type: &'static str, sha256: Option<&'static str>| {
+                AttachmentUpload {
+                    patient_id: 1,
+                    appointment_id: None,
+                    uploaded_by: 1,
+                    filename,
+                    content_type,
+                    data: &pdf,
+                    sha256,
+                }
+            };
+
+        assert!(matches!(
+            db.create_attachment(
+                &upload("run.exe", "application/x-msdownload", None),
+                &storage
+            ),
+            Err(Error::InvalidAttachment(_))
+        ));
+        assert!(matches!(
+            db.create_attachment(&upload("scan.png", "image/png", None), &storage),
+            Err(Error::InvalidAttachment(_))
+        ));
+        assert!(matches!(
+            db.create_attachment(&upload("labs.pdf", "application/pdf", Some("00")), &storage),
+            Err(Error::ChecksumMismatch)
+        ));
+
+        let checksum = sha256_hex(&pdf);
+        let attachment = db
+            .create_attachment(
+                &AttachmentUpload {
+                    sha256: Some(&checksum),
+                    ..upload("../../labs\"2022\".pdf", "Application/PDF", None)
+                },
+                &storage,
+            )
+            .unwrap();
+        assert_eq!(attachment.filename, "labs2022.pdf");
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(attachment.size_bytes, pdf.len() as i64);
+
+        let stored = db.get_attachment(attachment.id).unwrap();
+        assert_eq!(db.read_attachment(&stored, &storage).unwrap(), pdf);
+        assert_eq!(db.patient_attachments(1).unwrap().len(), 1);
+
+        // Tampered contents fail the checksum.
+        storage
+            .put(&stored.storage_key, b"%PDF-1.4 changed")
+            .unwrap();
+        assert!(matches!(
+            db.read_attachment(&stored, &storage),
+            Err(Error::ChecksumMismatch)
+        ));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_questionnaires() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        let checkup = db
+            .save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Checkup".to_string(),
+                    referral_required: false,
+                    fee: None,
+                    tax_rate_bps: 0,
+                    deposit: None,
+                    telehealth: false,
+                },
+            )
+            .unwrap();
 
-    Ok(appointment)
-}
+        let question = |key: &str, kind: QuestionKind, required: bool, options: &[&str]| Question {
+            key: key.to_string(),
+            text: format!("{}?", key),
+            kind,
+            required,
+            options: options.iter().map(|o| o.to_string()).collect(),
+        };
+        let mut intake = QuestionnaireData {
+            name: "Intake".to_string(),
+            appointment_type_id: checkup,
+            required: true,
+            questions: vec![
+                question("symptoms", QuestionKind::Text, true, &[]),
+                question("pain", QuestionKind::Number, false, &[]),
+                question("smoker", QuestionKind::YesNo, false, &[]),
+                question(
+                    "since",
+                    QuestionKind::SingleChoice,
+                    false,
+                    &["days", "weeks"],
+                ),
+                question(
+                    "areas",
+                    QuestionKind::MultipleChoice,
+                    false,
+                    &["head", "chest"],
+                ),
+            ],
+        };
+        intake.appointment_type_id = checkup + 1;
+        assert!(matches!(
+            db.create_questionnaire(1, &intake),
+            Err(Error::UnknownAppointmentType)
+        ));
+        intake.appointment_type_id = checkup;
+        intake
+            .questions
+            .push(question("since", QuestionKind::Text, false, &[]));
+        assert!(matches!(
+            db.create_questionnaire(1, &intake),
+            Err(Error::InvalidQuestionnaire(_))
+        ));
+        intake.questions.pop();
+        let questionnaire = db.create_questionnaire(1, &intake).unwrap();
 
-pub fn decode_jwt(jwt: &str) -> Result<Claims, Error> {
-    let token = decode::<Claims>(
-        jwt,
-        &DecodingKey::from_secret("secret".as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )?;
+        let appointment = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339("2022-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: Some(checkup),
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap();
+        let attached = db.appointment_questionnaires(appointment).unwrap();
+        assert_eq!(attached.len(), 1);
+        assert!(attached[0].required);
+        assert!(attached[0].answers.is_none());
 
-    Ok(token.claims)
-}
+        assert!(matches!(
+            db.set_appointment_status(appointment, AppointmentStatus::CheckedIn, 1),
+            Err(Error::QuestionnairesPending)
+        ));
 
-#[derive(
-    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
-)]
-#[repr(i32)]
-pub enum AccountType {
-    Patient = 0,
-    Doctor = 1,
-    Admin = 2,
-}
+        let answers = |value: serde_json::Value| -> BTreeMap<String, serde_json::Value> {
+            serde_json::from_value(value).unwrap()
+        };
+        for invalid in [
+            json!({ "pain": 3 }),
+            json!({ "symptoms": "Cough", "pain": "a lot" }),
+            json!({ "symptoms": "Cough", "since": "years" }),
+            json!({ "symptoms": "Cough", "areas": ["head", "head"] }),
+            json!({ "symptoms": "Cough", "mood": "fine" }),
+        ] {
+            assert!(matches!(
+                db.submit_answers(appointment, questionnaire, &answers(invalid)),
+                Err(Error::InvalidAnswers(_))
+            ));
+        }
+        db.submit_answers(
+            appointment,
+            questionnaire,
+            &answers(json!({
+                "symptoms": "Cough",
+                "pain": 3,
+                "smoker": false,
+                "since": null,
+                "areas": ["chest"],
+            })),
+        )
+        .unwrap();
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct RegisterData {
-    name: String,
-    email: String,
-    password: String,
-    account_type: AccountType,
-}
+        let attached = db.appointment_questionnaires(appointment).unwrap();
+        let given = attached[0].answers.as_ref().unwrap();
+        assert_eq!(given["symptoms"], json!("Cough"));
+        assert!(!given.contains_key("since"));
 
-#[derive(Serialize, Deserialize)]
-pub struct LoginData {
-    email: String,
-    password: String,
-}
+        // Editing the template leaves forms already sent out alone.
+        intake.questions.truncate(1);
+        db.update_questionnaire(questionnaire, &intake).unwrap();
+        assert_eq!(
+            db.get_questionnaire(questionnaire).unwrap().questions.len(),
+            1
+        );
+        let attached = db.appointment_questionnaires(appointment).unwrap();
+        assert_eq!(attached[0].questionnaire.questions.len(), 5);
 
-pub type JWT = String;
+        db.set_appointment_status(appointment, AppointmentStatus::CheckedIn, 1)
+            .unwrap();
+        assert!(matches!(
+            db.submit_answers(
+                appointment,
+                questionnaire,
+                &answers(json!({ "symptoms": "Fever" }))
+            ),
+            Err(Error::InvalidAnswers(_))
+        ));
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: i32,
-    pub account_type: AccountType,
-    pub iat: u64,
-}
+    #[test]
+    fn test_messaging() {
+        let db = setup(&[
+            AccountType::Doctor,
+            AccountType::Doctor,
+            AccountType::Patient,
+        ]);
+        let child = db
+            .create_dependent(
+                3,
+                DependentData {
+                    name: "Child".to_string(),
+                    relationship: "Son".to_string(),
+                },
+            )
+            .unwrap();
+        let appointment = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: child,
+                    start_date: DateTime::parse_from_rfc3339("2022-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                3,
+            )
+            .unwrap();
+        let message = |body: &str| MessageData {
+            body: body.to_string(),
+            attachment_id: None,
+        };
+        let thread = |doctor_id: i32, appointment_id: Option<i32>| ThreadData {
+            patient_id: child,
+            doctor_id,
+            appointment_id,
+            subject: "Follow-up".to_string(),
+        };
 
-impl Claims {
-    pub fn is_valid<T>(&self) -> bool
-    where
-        T: ValidClaimsChecker,
-    {
-        T::is_valid(self)
+        // The other doctor has never seen the child.
+        assert!(matches!(
+            db.create_thread(3, &thread(2, None), &message("Hello")),
+            Err(Error::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            db.create_thread(3, &thread(2, Some(appointment)), &message("Hello")),
+            Err(Error::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            db.create_thread(3, &thread(1, None), &message("  ")),
+            Err(Error::InvalidMessage(_))
+        ));
+
+        let about_visit = db
+            .create_thread(
+                3,
+                &thread(1, Some(appointment)),
+                &message("Is the rash normal?"),
+            )
+            .unwrap();
+        let general = db
+            .create_thread(1, &thread(1, None), &message("Please book a follow-up"))
+            .unwrap();
+        assert_eq!(
+            db.thread_owners(&db.get_thread(general, 1).unwrap())
+                .unwrap(),
+            vec![1, child, 3]
+        );
+
+        // The guardian sees both threads through the dependent.
+        assert_eq!(db.account_threads(3).unwrap().len(), 2);
+        assert_eq!(db.unread_message_count(3).unwrap(), 1);
+        assert_eq!(db.unread_message_count(1).unwrap(), 1);
+        assert!(db.account_threads(2).unwrap().is_empty());
+
+        let reply = db
+            .add_message(about_visit, 1, &message("Yes, it should fade in a week"))
+            .unwrap();
+        assert_eq!(db.get_thread(about_visit, 3).unwrap().unread, 1);
+        assert_eq!(db.get_thread(about_visit, 1).unwrap().unread, 0);
+
+        let messages = db.thread_messages(about_visit).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].read_by, vec![1, 3]);
+        assert_eq!(messages[1].id, reply);
+        assert_eq!(messages[1].read_by, vec![1]);
+
+        db.mark_thread_read(about_visit, 3).unwrap();
+        db.mark_thread_read(general, 3).unwrap();
+        assert_eq!(db.unread_message_count(3).unwrap(), 0);
+        assert_eq!(db.thread_messages(about_visit).unwrap()[1].read_by.len(), 2);
+
+        // Attachments must belong to the thread's patient.
+        let root = std::env::temp_dir().join(format!("message-attachments-{}", std::process::id()));
+        let storage = LocalStorage::new(root.clone());
+        let other_patient = db
+            .create_attachment(
+                &AttachmentUpload {
+                    patient_id: 3,
+                    appointment_id: None,
+                    uploaded_by: 3,
+                    filename: "notes.txt",
+                    content_type: "text/plain",
+                    data: b"notes",
+                    sha256: None,
+                },
+                &storage,
+            )
+            .unwrap();
+        assert!(matches!(
+            db.add_message(
+                general,
+                3,
+                &MessageData {
+                    attachment_id: Some(other_patient.id),
+                    ..message("See attached")
+                }
+            ),
+            Err(Error::InvalidMessage(_))
+        ));
+        std::fs::remove_dir_all(root).unwrap();
     }
-}
 
-pub trait ValidClaimsChecker {
-    fn is_valid(claims: &Claims) -> bool;
-}
+    #[test]
+    fn test_lab_orders() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        let appointment = db
+            .book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339("2022-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap();
+        let glucose = LabOrderData {
+            test_code: "2345-7".to_string(),
+            test_name: "Glucose".to_string(),
+            notes: "Fasting".to_string(),
+        };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DoctorInfo {
-    name: String,
-    specialty: String,
-    details: String,
-}
+        assert!(matches!(
+            db.create_lab_order(appointment, &glucose),
+            Err(Error::InvalidLabOrder(_))
+        ));
+        db.set_appointment_status(appointment, AppointmentStatus::CheckedIn, 1)
+            .unwrap();
+        let order = db.create_lab_order(appointment, &glucose).unwrap();
+        assert_eq!(
+            db.get_lab_order(order).unwrap().status,
+            LabOrderStatus::Ordered
+        );
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Appointment {
-    id: i32,
-    pub doctor_id: i32,
-    pub patient_id: i32,
-    pub start_date: DateTime<FixedOffset>,
-    pub duration: i32,
-    pub status: AppointmentStatus,
-}
+        assert!(matches!(
+            db.review_lab_order(order),
+            Err(Error::LabOrderStatus(LabOrderStatus::Ordered))
+        ));
+        db.mark_lab_collected(order).unwrap();
+        assert!(matches!(
+            db.mark_lab_collected(order),
+            Err(Error::LabOrderStatus(LabOrderStatus::Collected))
+        ));
+        assert!(matches!(
+            db.record_lab_results(order, &[]),
+            Err(Error::InvalidLabResult(_))
+        ));
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AppointmentRequest {
-    pub doctor_id: i32,
-    pub patient_id: i32,
-    pub start_date: DateTime<FixedOffset>,
-    pub duration: i32,
-}
+        let result = |value: &str, range: &str, flag: Option<LabFlag>| LabResultData {
+            code: "2345-7".to_string(),
+            name: "Glucose".to_string(),
+            value: value.to_string(),
+            units: "mmol/L".to_string(),
+            reference_range: range.to_string(),
+            flag,
+        };
+        db.record_lab_results(order, &[result("9.0", "3.9-6.1", None)])
+            .unwrap();
+        // Corrections replace the earlier results.
+        db.record_lab_results(
+            order,
+            &[
+                result("3.1", "3.9-6.1", None),
+                result("5.0", "<=5", None),
+                result("positive", "negative", None),
+                result("25", ">30", Some(LabFlag::CriticalLow)),
+            ],
+        )
+        .unwrap();
+        let resulted = db.get_lab_order(order).unwrap();
+        assert_eq!(resulted.status, LabOrderStatus::Resulted);
+        assert!(resulted.collected_at.is_some() && resulted.resulted_at.is_some());
+        let flags: Vec<Option<LabFlag>> = resulted.results.iter().map(|r| r.flag).collect();
+        assert_eq!(
+            flags,
+            vec![
+                Some(LabFlag::Low),
+                Some(LabFlag::Normal),
+                None,
+                Some(LabFlag::CriticalLow)
+            ]
+        );
 
-#[derive(
-    Debug, Serialize, Deserialize, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq,
-)]
-#[repr(i32)]
-pub enum AppointmentStatus {
-    Booked,
-    Cancelled,
-    Done,
-}
+        db.review_lab_order(order).unwrap();
+        assert!(matches!(
+            db.record_lab_results(order, &[result("5.0", "", None)]),
+            Err(Error::LabOrderStatus(LabOrderStatus::Reviewed))
+        ));
+        let threads = db.account_threads(2).unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].appointment_id, Some(appointment));
+        assert_eq!(threads[0].unread, 1);
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DoctorAppointmentStats {
-    pub doctor_id: i32,
-    pub appointments_count: i32,
-    pub booked_mins: i32,
-}
+        // Later releases for the visit reuse its thread.
+        let second = db.create_lab_order(appointment, &glucose).unwrap();
+        db.record_lab_results(second, &[result("5.0", "3.9-6.1", None)])
+            .unwrap();
+        db.review_lab_order(second).unwrap();
+        assert_eq!(db.account_threads(2).unwrap().len(), 1);
+        assert_eq!(db.unread_message_count(2).unwrap(), 2);
 
-#[cfg(test)]
-mod tests {
+        // Imports record every order in one transaction, so a rejected
+        // order undoes the ones before it.
+        let third = db.create_lab_order(appointment, &glucose).unwrap();
+        {
+            let _tx = db.transaction().unwrap();
+            db.record_lab_results(third, &[result("5.0", "3.9-6.1", None)])
+                .unwrap();
+            assert!(db
+                .record_lab_results(second, &[result("5.0", "3.9-6.1", None)])
+                .is_err());
+        }
+        assert_eq!(
+            db.get_lab_order(third).unwrap().status,
+            LabOrderStatus::Ordered
+        );
+        assert_eq!(
+            db.get_lab_order(second).unwrap().status,
+            LabOrderStatus::Reviewed
+        );
 
-    use serde::{Deserialize, Serialize};
+        let orders: Vec<i32> = db
+            .patient_lab_orders(2)
+            .unwrap()
+            .iter()
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(orders, vec![third, second, order]);
+        assert_eq!(
+            db.lab_order_owners(&db.get_lab_order(order).unwrap())
+                .unwrap(),
+            vec![2, 1]
+        );
+    }
+
+    #[test]
+    fn test_invoices() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        db.update_doctor_profile(
+            1,
+            &DoctorProfileUpdate {
+                consultation_fee: Some(5000),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            db.save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Vaccination".to_string(),
+                    referral_required: false,
+                    fee: Some(1500),
+                    tax_rate_bps: 10_001,
+                    deposit: None,
+                    telehealth: false,
+                },
+            ),
+            Err(Error::InvalidAppointmentType(_))
+        ));
+        let vaccination = db
+            .save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Vaccination".to_string(),
+                    referral_required: false,
+                    fee: Some(1500),
+                    tax_rate_bps: 825,
+                    deposit: None,
+                    telehealth: false,
+                },
+            )
+            .unwrap();
+
+        let book = |start_date: &str, appointment_type_id: Option<i32>| {
+            completed_visit(&db, 1, 2, start_date, appointment_type_id)
+        };
+        let appointment = book("2022-01-01T10:00:00+00:00", Some(vaccination));
 
-    use super::{LoginData, RegisterData, DB};
+        let invoice = db.appointment_invoice(appointment).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Draft);
+        let descriptions: Vec<&str> = invoice
+            .lines
+            .iter()
+            .map(|l| l.description.as_str())
+            .collect();
+        assert_eq!(
+            descriptions,
+            vec!["Consultation with Account 0", "Vaccination"]
+        );
+        assert_eq!(invoice.subtotal, 6500);
+        // 8.25% of 50.00 and 15.00, each rounded half up.
+        assert_eq!(invoice.tax, 413 + 124);
+        assert_eq!(invoice.total, 6500 + 537);
 
-    #[derive(Serialize, Deserialize)]
-    struct MockData {
-        registerations: Vec<RegisterData>,
+        // Completing again is refused and keeps the first invoice.
+        assert!(matches!(
+            db.set_appointment_status(appointment, AppointmentStatus::Done, 1),
+            Err(Error::AppointmentStatus(AppointmentStatus::Done))
+        ));
+        assert_eq!(db.invoices(None, None).unwrap().len(), 1);
+
+        let line = |description: &str, quantity: i64, discount: i64| InvoiceLineData {
+            description: description.to_string(),
+            quantity,
+            unit_price: 2000,
+            discount,
+            tax_rate_bps: 1000,
+        };
+        assert!(matches!(
+            db.set_invoice_lines(invoice.id, &[line("Dressing", 1, 2001)]),
+            Err(Error::InvalidInvoice(_))
+        ));
+        assert!(matches!(
+            db.set_invoice_lines(invoice.id, &[line(" ", 1, 0)]),
+            Err(Error::InvalidInvoice(_))
+        ));
+        // Amounts that overflow are refused rather than wrapping.
+        for quantity in [i64::MAX, i64::MAX / 2000] {
+            assert!(matches!(
+                db.set_invoice_lines(invoice.id, &[line("Dressing", quantity, 0)]),
+                Err(Error::InvalidInvoice(_))
+            ));
+        }
+        db.set_invoice_lines(invoice.id, &[line("Dressing", 3, 1000)])
+            .unwrap();
+        let invoice = db.get_invoice(invoice.id).unwrap();
+        assert_eq!(
+            (
+                invoice.subtotal,
+                invoice.discount,
+                invoice.tax,
+                invoice.total
+            ),
+            (6000, 1000, 500, 5500)
+        );
+
+        assert!(db.patient_invoices(2).unwrap().is_empty());
+        assert!(matches!(
+            db.set_invoice_status(invoice.id, InvoiceStatus::Paid, None),
+            Err(Error::InvoiceStatus(InvoiceStatus::Draft))
+        ));
+        db.set_invoice_status(invoice.id, InvoiceStatus::Issued, None)
+            .unwrap();
+        assert!(matches!(
+            db.set_invoice_lines(invoice.id, &[]),
+            Err(Error::InvoiceStatus(InvoiceStatus::Issued))
+        ));
+        db.set_invoice_status(invoice.id, InvoiceStatus::Paid, None)
+            .unwrap();
+        assert!(matches!(
+            db.set_invoice_status(invoice.id, InvoiceStatus::Void, Some("Duplicate")),
+            Err(Error::InvoiceStatus(InvoiceStatus::Paid))
+        ));
+        let paid = db.get_invoice(invoice.id).unwrap();
+        assert!(paid.issued_at.is_some() && paid.paid_at.is_some());
+
+        // Without a type, only the consultation is billed, untaxed.
+        let second = db
+            .appointment_invoice(book("2022-01-02T10:00:00+00:00", None))
+            .unwrap();
+        assert_eq!((second.total, second.tax), (5000, 0));
+        assert!(matches!(
+            db.set_invoice_status(second.id, InvoiceStatus::Void, Some(" ")),
+            Err(Error::InvalidInvoice(_))
+        ));
+        db.set_invoice_status(second.id, InvoiceStatus::Void, Some("Goodwill"))
+            .unwrap();
+        assert_eq!(
+            db.get_invoice(second.id).unwrap().void_reason,
+            Some("Goodwill".to_string())
+        );
+
+        let ids = |invoices: Vec<Invoice>| -> Vec<i32> { invoices.iter().map(|i| i.id).collect() };
+        assert_eq!(
+            ids(db.patient_invoices(2).unwrap()),
+            vec![second.id, invoice.id]
+        );
+        assert_eq!(
+            ids(db.invoices(Some(InvoiceStatus::Void), Some(2)).unwrap()),
+            vec![second.id]
+        );
     }
 
     #[test]
-    fn test_registeration() {
+    fn test_payments() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        let surgery = |deposit: i64| AppointmentTypeData {
+            name: "Minor surgery".to_string(),
+            referral_required: false,
+            fee: None,
+            tax_rate_bps: 0,
+            deposit: Some(deposit),
+            telehealth: false,
+        };
+        assert!(matches!(
+            db.save_appointment_type(None, &surgery(-1)),
+            Err(Error::InvalidAppointmentType(_))
+        ));
+        let type_id = db.save_appointment_type(None, &surgery(0)).unwrap();
+        assert_eq!(db.get_appointment_type(type_id).unwrap().deposit, None);
+        db.save_appointment_type(Some(type_id), &surgery(2500))
+            .unwrap();
+        assert_eq!(
+            db.get_appointment_type(type_id).unwrap().deposit,
+            Some(2500)
+        );
+
+        let request = || AppointmentRequest {
+            doctor_id: 1,
+            patient_id: 2,
+            start_date: DateTime::parse_from_rfc3339("2999-01-01T10:00:00+00:00").unwrap(),
+            duration: 30,
+            appointment_type_id: Some(type_id),
+            referral_id: None,
+        };
+        let appointment = db.book_appointment(request(), 2).unwrap();
+        assert!(db.appointment_payment(appointment).is_err());
+
+        // A booking rolled back with its transaction leaves no payment behind.
+        let rolled_back = {
+            let _tx = db.transaction().unwrap();
+            let rolled_back = db.book_appointment(request(), 2).unwrap();
+            db.record_payment(rolled_back, "fake_2", 2500).unwrap();
+            rolled_back
+        };
+        assert!(db.get_appointment(rolled_back).is_err());
+        assert!(db.appointment_payment(rolled_back).is_err());
+
+        let payment = db.record_payment(appointment, "fake_1", 2500).unwrap();
+        db.record_refund(payment, 1250).unwrap();
+        let payment = db.appointment_payment(appointment).unwrap();
+        assert_eq!(payment.reference, "fake_1");
+        assert_eq!((payment.amount, payment.refunded), (2500, 1250));
+        assert!(payment.refunded_at.is_some());
+    }
+
+    #[test]
+    fn test_claims() {
+        let db = setup(&[AccountType::Doctor, AccountType::Patient]);
+        db.update_doctor_profile(
+            1,
+            &DoctorProfileUpdate {
+                specialty: Some("Cardiology".to_string()),
+                consultation_fee: Some(8000),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+        let checkup = db
+            .save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Checkup".to_string(),
+                    referral_required: false,
+                    fee: None,
+                    tax_rate_bps: 0,
+                    deposit: None,
+                    telehealth: false,
+                },
+            )
+            .unwrap();
+
+        let plan = |copay: i64| InsurancePlanData {
+            payer_name: "Acme Health".to_string(),
+            payer_id: "ACME1".to_string(),
+            plan_name: "Silver".to_string(),
+            covered_appointment_types: vec![],
+            covered_specialties: vec!["cardiology".to_string()],
+            copay,
+        };
+        assert!(matches!(
+            db.save_insurance_plan(None, &plan(-1)),
+            Err(Error::InvalidInsurancePlan(_))
+        ));
+        let plan_id = db.save_insurance_plan(None, &plan(2000)).unwrap();
+        assert!(db.save_insurance_plan(Some(plan_id + 1), &plan(0)).is_err());
+
+        let date = NaiveDate::from_ymd_opt(2022, 1, 1).unwrap();
+        let coverage = db.check_coverage(2, 1, None, date).unwrap();
+        assert!(!coverage.covered);
+        assert!(coverage.plan.is_none());
+
+        let insure = |plan_id: i32, valid_until: Option<NaiveDate>| {
+            db.update_patient_profile(
+                2,
+                &PatientProfileUpdate {
+                    insurance: Some(PatientInsurance {
+                        provider: Some("Acme Health".to_string()),
+                        policy_number: Some("M123".to_string()),
+                        plan_id: Some(plan_id),
+                        group_number: Some("G9".to_string()),
+                        valid_until,
+                    }),
+                    ..PatientProfileUpdate::default()
+                },
+            )
+        };
+        assert!(matches!(
+            insure(plan_id + 1, None),
+            Err(Error::InvalidProfile(_))
+        ));
+        insure(plan_id, NaiveDate::from_ymd_opt(2021, 12, 31)).unwrap();
+        assert_eq!(
+            db.check_coverage(2, 1, None, date)
+                .unwrap()
+                .reason
+                .as_deref(),
+            Some("the policy has expired")
+        );
+        insure(plan_id, None).unwrap();
+        let coverage = db.check_coverage(2, 1, Some(checkup), date).unwrap();
+        assert!(coverage.covered);
+        assert_eq!(coverage.copay, Some(2000));
+
+        // Plans listing appointment types only cover those.
+        db.save_insurance_plan(
+            Some(plan_id),
+            &InsurancePlanData {
+                covered_appointment_types: vec![checkup],
+                ..plan(2000)
+            },
+        )
+        .unwrap();
+        assert!(!db.check_coverage(2, 1, None, date).unwrap().covered);
+
+        let complete = |appointment_type_id: Option<i32>| {
+            let appointment = db
+                .book_appointment(
+                    AppointmentRequest {
+                        doctor_id: 1,
+                        patient_id: 2,
+                        start_date: DateTime::parse_from_rfc3339("2022-01-05T10:00:00+00:00")
+                            .unwrap(),
+                        duration: 30,
+                        appointment_type_id,
+                        referral_id: None,
+                    },
+                    2,
+                )
+                .unwrap();
+            db.set_appointment_status(appointment, AppointmentStatus::CheckedIn, 1)
+                .unwrap();
+            db.add_visit_note(
+                appointment,
+                1,
+                &VisitNoteData {
+                    chief_complaint: "Chest pain".to_string(),
+                    findings: String::new(),
+                    diagnosis_codes: vec!["R07.9".to_string()],
+                    plan: String::new(),
+                    patient_summary: String::new(),
+                    amendment_reason: None,
+                },
+            )
+            .unwrap();
+            db.set_appointment_status(appointment, AppointmentStatus::Done, 1)
+                .unwrap();
+            appointment
+        };
+        // Not covered, so no claim.
+        complete(None);
+        assert!(db.claims(None, None).unwrap().is_empty());
+
+        let appointment = complete(Some(checkup));
+        let claims = db.claims(None, Some(2)).unwrap();
+        assert_eq!(claims.len(), 1);
+        let claim = &claims[0];
+        assert_eq!(claim.appointment_id, appointment);
+        assert_eq!(claim.status, ClaimStatus::Pending);
+        assert_eq!((claim.billed_amount, claim.copay), (8000, 2000));
+        assert_eq!(claim.member_id, "M123");
+
+        // Pending claims follow invoice edits.
+        db.set_invoice_lines(
+            claim.invoice_id,
+            &[InvoiceLineData {
+                description: "Consultation".to_string(),
+                quantity: 1,
+                unit_price: 9000,
+                discount: 0,
+                tax_rate_bps: 0,
+            }],
+        )
+        .unwrap();
+        assert_eq!(db.get_claim(claim.id).unwrap().billed_amount, 9000);
+
+        // Voiding the invoice cancels its pending claim before export.
+        complete(Some(checkup));
+        let voided = db.claims(None, Some(2)).unwrap()[0].id;
+        assert_ne!(voided, claim.id);
+        db.set_invoice_status(
+            db.get_claim(voided).unwrap().invoice_id,
+            InvoiceStatus::Void,
+            Some("Billed twice"),
+        )
+        .unwrap();
+        assert_eq!(db.get_claim(voided).unwrap().status, ClaimStatus::Cancelled);
+        assert!(matches!(
+            db.set_claim_status(voided, ClaimStatus::Approved, None, None),
+            Err(Error::ClaimStatus(ClaimStatus::Cancelled))
+        ));
+
+        assert!(matches!(
+            db.set_claim_status(claim.id, ClaimStatus::Approved, None, None),
+            Err(Error::ClaimStatus(ClaimStatus::Pending))
+        ));
+        let batch = db.export_claims().unwrap().unwrap();
+        assert_eq!(batch.claim_count, 1);
+        assert!(batch.contents.contains("CLM*"));
+        assert!(batch.contents.contains("*90.00*"));
+        assert!(batch.contents.contains("HI*ABK:R079~"));
+        assert_eq!(
+            db.get_claim_batch(batch.id).unwrap().contents,
+            batch.contents
+        );
+        assert!(db.export_claims().unwrap().is_none());
+
+        let claim = db.get_claim(claim.id).unwrap();
+        assert_eq!(claim.status, ClaimStatus::Submitted);
+        assert_eq!(claim.batch_id, Some(batch.id));
+        assert!(matches!(
+            db.set_claim_status(claim.id, ClaimStatus::Denied, None, Some(" ")),
+            Err(Error::InvalidClaim(_))
+        ));
+        db.set_claim_status(claim.id, ClaimStatus::Approved, None, None)
+            .unwrap();
+        assert!(matches!(
+            db.set_claim_status(claim.id, ClaimStatus::Paid, Some(9001), None),
+            Err(Error::InvalidClaim(_))
+        ));
+        db.set_claim_status(claim.id, ClaimStatus::Paid, Some(7000), None)
+            .unwrap();
+        let claim = db.get_claim(claim.id).unwrap();
+        assert_eq!(claim.status, ClaimStatus::Paid);
+        assert_eq!(claim.paid_amount, Some(7000));
+        assert!(claim.decided_at.is_some() && claim.paid_at.is_some());
+    }
+
+    #[test]
+    fn test_consents() {
+        let db = setup(&[
+            AccountType::Doctor,
+            AccountType::Patient,
+            AccountType::Admin,
+        ]);
+        let video = db
+            .save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Video consultation".to_string(),
+                    referral_required: false,
+                    fee: None,
+                    tax_rate_bps: 0,
+                    deposit: None,
+                    telehealth: true,
+                },
+            )
+            .unwrap();
+        assert!(db.get_appointment_type(video).unwrap().telehealth);
+
+        let document = |code: &str, purpose: ConsentPurpose| ConsentDocumentData {
+            code: code.to_string(),
+            title: "Telehealth consent".to_string(),
+            body: "I agree to be seen over video.".to_string(),
+            purpose,
+        };
+        assert!(matches!(
+            db.publish_consent_document(&document("Tele Health", ConsentPurpose::Telehealth), 3),
+            Err(Error::InvalidConsent(_))
+        ));
+        let v1 = db
+            .publish_consent_document(&document("telehealth", ConsentPurpose::Telehealth), 3)
+            .unwrap();
+        assert!(matches!(
+            db.publish_consent_document(&document("telehealth", ConsentPurpose::RecordSharing), 3),
+            Err(Error::InvalidConsent(_))
+        ));
+
+        let book = || {
+            db.book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339("2999-01-01T10:00:00+00:00").unwrap(),
+                    duration: 30,
+                    appointment_type_id: Some(video),
+                    referral_id: None,
+                },
+                2,
+            )
+        };
+        match book() {
+            Err(Error::ConsentRequired(codes)) => assert_eq!(codes, vec!["telehealth"]),
+            other => panic!("{:?}", other),
+        }
+        db.accept_consent(2, v1, 2).unwrap();
+        assert_eq!(db.accept_consent(2, v1, 2).unwrap(), 1);
+        book().unwrap();
+
+        // A new version has to be accepted again, and the old one can't be.
+        let v2 = db
+            .publish_consent_document(&document("telehealth", ConsentPurpose::Telehealth), 3)
+            .unwrap();
+        assert_eq!(db.get_consent_document(v2).unwrap().version, 2);
+        assert_eq!(db.consent_documents().unwrap().len(), 1);
+        assert!(matches!(book(), Err(Error::ConsentRequired(_))));
+        assert!(matches!(
+            db.accept_consent(2, v1, 2),
+            Err(Error::InvalidConsent(_))
+        ));
+        db.accept_consent(2, v2, 2).unwrap();
+        book().unwrap();
+
+        db.withdraw_consent(2, "telehealth", 2).unwrap();
+        assert!(db.withdraw_consent(2, "telehealth", 2).is_err());
+        assert!(matches!(book(), Err(Error::ConsentRequired(_))));
+
+        let consents = db.patient_consents(2).unwrap();
+        assert_eq!(consents.len(), 2);
+        assert_eq!((consents[0].version, consents[0].current), (2, true));
+        assert_eq!((consents[1].version, consents[1].current), (1, false));
+        assert!(consents.iter().all(|c| c.withdrawn_at.is_some()));
+
+        assert!(db
+            .missing_consents(2, ConsentPurpose::RecordSharing)
+            .unwrap()
+            .is_empty());
+        let sharing = db
+            .publish_consent_document(
+                &document("record_sharing", ConsentPurpose::RecordSharing),
+                3,
+            )
+            .unwrap();
+        assert_eq!(
+            db.missing_consents(2, ConsentPurpose::RecordSharing)
+                .unwrap()
+                .len(),
+            1
+        );
+        db.accept_consent(2, sharing, 2).unwrap();
+        assert!(db
+            .missing_consents(2, ConsentPurpose::RecordSharing)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_referrals() {
+        let db = setup(&[
+            AccountType::Doctor,
+            AccountType::Doctor,
+            AccountType::Doctor,
+            AccountType::Patient,
+        ]);
+        db.update_doctor_profile(
+            3,
+            &DoctorProfileUpdate {
+                specialty: Some("Cardiology".to_string()),
+                ..DoctorProfileUpdate::default()
+            },
+        )
+        .unwrap();
+
+        let specialist = db
+            .save_appointment_type(
+                None,
+                &AppointmentTypeData {
+                    name: "Specialist consultation".to_string(),
+                    referral_required: true,
+                    fee: None,
+                    tax_rate_bps: 0,
+                    deposit: None,
+                    telehealth: false,
+                },
+            )
+            .unwrap();
+        let request = |doctor_id: i32, day: u32, referral_id: Option<i32>| AppointmentRequest {
+            doctor_id,
+            patient_id: 4,
+            start_date: DateTime::parse_from_rfc3339(&format!("2022-01-{:02}T10:00:00+00:00", day))
+                .unwrap(),
+            duration: 30,
+            appointment_type_id: Some(specialist),
+            referral_id,
+        };
+        let referral_data =
+            |target_doctor_id: Option<i32>, target_specialty: Option<&str>| ReferralData {
+                patient_id: 4,
+                target_doctor_id,
+                target_specialty: target_specialty.map(str::to_string),
+                reason: "Chest pain".to_string(),
+                urgency: ReferralUrgency::Urgent,
+            };
+
+        // Doctors can only refer patients they have seen.
+        assert!(matches!(
+            db.create_referral(1, &referral_data(Some(2), None)),
+            Err(Error::InvalidReferral(_))
+        ));
+        let visit = db
+            .book_appointment(
+                AppointmentRequest {
+                    appointment_type_id: None,
+                    ..request(1, 1, None)
+                },
+                4,
+            )
+            .unwrap();
+        complete(&db, visit);
+
+        assert!(matches!(
+            db.create_referral(1, &referral_data(Some(2), Some("Cardiology"))),
+            Err(Error::InvalidReferral(_))
+        ));
+        assert!(matches!(
+            db.create_referral(1, &referral_data(Some(1), None)),
+            Err(Error::InvalidReferral(_))
+        ));
+        let to_doctor = db
+            .create_referral(1, &referral_data(Some(2), None))
+            .unwrap();
+        let to_specialty = db
+            .create_referral(1, &referral_data(None, Some("cardiology")))
+            .unwrap();
+
+        assert!(matches!(
+            db.book_appointment(request(2, 2, None), 4),
+            Err(Error::ReferralRequired)
+        ));
+        assert!(matches!(
+            db.book_appointment(request(2, 2, Some(to_specialty)), 4),
+            Err(Error::InvalidReferral(_))
+        ));
+        assert!(matches!(
+            db.book_appointment(
+                AppointmentRequest {
+                    appointment_type_id: Some(specialist + 1),
+                    ..request(2, 2, None)
+                },
+                4
+            ),
+            Err(Error::UnknownAppointmentType)
+        ));
+
+        let booked = db
+            .book_appointment(request(3, 2, Some(to_specialty)), 4)
+            .unwrap();
+        let referral = db.get_referral(to_specialty).unwrap();
+        assert_eq!(referral.status, ReferralStatus::Booked);
+        assert_eq!(referral.appointment_id, Some(booked));
+        assert_eq!(
+            db.get_appointment(booked).unwrap().referral_id,
+            Some(to_specialty)
+        );
+        assert!(matches!(
+            db.book_appointment(request(3, 3, Some(to_specialty)), 4),
+            Err(Error::InvalidReferral(_))
+        ));
+
+        // Cancelling gives the referral back.
+        db.set_appointment_status(booked, AppointmentStatus::Cancelled, 4)
+            .unwrap();
+        let referral = db.get_referral(to_specialty).unwrap();
+        assert_eq!(referral.status, ReferralStatus::Open);
+        assert_eq!(referral.appointment_id, None);
+
+        db.book_appointment(request(2, 3, Some(to_doctor)), 4)
+            .unwrap();
+        assert_eq!(db.patient_referrals(4).unwrap().len(), 2);
+        assert_eq!(db.doctor_referrals(2).unwrap().len(), 1);
+        assert_eq!(db.doctor_referrals(1).unwrap().len(), 2);
+        assert_eq!(
+            db.referral_owners(&db.get_referral(to_doctor).unwrap())
+                .unwrap(),
+            vec![4, 1, 2]
+        );
+
+        // The doctor booked for a specialty referral receives it.
+        assert!(db.doctor_referrals(3).unwrap().is_empty());
+        db.book_appointment(request(3, 4, Some(to_specialty)), 4)
+            .unwrap();
+        assert_eq!(db.doctor_referrals(3).unwrap()[0].id, to_specialty);
+        assert_eq!(
+            db.referral_owners(&db.get_referral(to_specialty).unwrap())
+                .unwrap(),
+            vec![4, 1, 3]
+        );
+    }
+
+    #[test]
+    fn test_patient_profile() {
         let db = DB::init(None).unwrap();
         db.init_schema().unwrap();
 
-        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        db.register(RegisterData {
+            name: "Patient".to_string(),
+            email: "patient@test.com".to_string(),
+            password: "password".to_string(),
+            account_type: AccountType::Patient,
+        })
+        .unwrap();
 
-        let r = mock.registerations.get(0).unwrap().clone();
+        let profile = db.get_patient_profile(1).unwrap();
+        assert_eq!(profile.name, "Patient");
+        assert!(profile.demographics.unwrap().date_of_birth.is_none());
+        assert!(profile.clinical.unwrap().allergies.is_empty());
+
+        db.update_patient_profile(
+            1,
+            &PatientProfileUpdate {
+                demographics: Some(PatientDemographics {
+                    date_of_birth: NaiveDate::from_ymd_opt(1990, 5, 17),
+                    sex: Some(Sex::Female),
+                }),
+                clinical: Some(PatientClinical {
+                    allergies: vec!["Penicillin".to_string(), " ".to_string()],
+                }),
+                ..PatientProfileUpdate::default()
+            },
+        )
+        .unwrap();
+
+        let profile = db.get_patient_profile(1).unwrap();
+        let demographics = profile.demographics.unwrap();
+        assert_eq!(
+            demographics.date_of_birth,
+            NaiveDate::from_ymd_opt(1990, 5, 17)
+        );
+        assert_eq!(demographics.sex, Some(Sex::Female));
+        assert_eq!(profile.clinical.unwrap().allergies, vec!["Penicillin"]);
+        assert!(profile.contact.unwrap().phone.is_none());
+
+        assert!(matches!(
+            db.update_patient_profile(
+                1,
+                &PatientProfileUpdate {
+                    demographics: Some(PatientDemographics {
+                        date_of_birth: NaiveDate::from_ymd_opt(2999, 1, 1),
+                        sex: None,
+                    }),
+                    ..PatientProfileUpdate::default()
+                }
+            ),
+            Err(Error::InvalidProfile(_))
+        ));
+
+        let dependent = db
+            .create_dependent(
+                1,
+                DependentData {
+                    name: "Child".to_string(),
+                    relationship: "child".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(db.get_patient_profile(dependent).unwrap().name, "Child");
+    }
+
+    #[test]
+    fn test_profile_and_deactivation() {
+        let db = DB::init(None).unwrap();
+        db.init_schema().unwrap();
+
+        let mock: MockData = serde_json::from_str(include_str!("./mock.json")).unwrap();
+        let r = mock.registerations.first().unwrap().clone();
         db.register(r.clone()).unwrap();
-        let jwt = db
-            .login(LoginData {
-                email: r.email.clone(),
-                password: r.password.clone(),
-            })
+        db.con()
+            .execute(
+                "INSERT INTO account (id, fullname, email, passhash, account_type)
+                 VALUES (2, 'Patient', 'patient@test.com', '', ?1)",
+                [AccountType::Patient as i32],
+            )
+            .unwrap();
+        db.con()
+            .execute("INSERT INTO patient (id) VALUES (2)", [])
             .unwrap();
 
-        println!("{}", jwt);
+        let client = ClientInfo {
+            ip: None,
+            user_agent: None,
+        };
+        let login = |email: &str, password: &str| {
+            db.login(
+                LoginData {
+                    email: email.to_string(),
+                    password: password.to_string(),
+                },
+                &client,
+                &LoginPolicy {
+                    backoff_base_secs: 0,
+                    ..LoginPolicy::default()
+                },
+            )
+        };
+
+        db.update_profile(
+            1,
+            &ProfileUpdate {
+                name: Some("Dr. Test".to_string()),
+                ..ProfileUpdate::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(db.account_profile(1).unwrap().name, "Dr. Test");
+
+        let change_email = |email: &str, current_password: Option<&str>| {
+            db.update_profile(
+                1,
+                &ProfileUpdate {
+                    email: Some(email.to_string()),
+                    current_password: current_password.map(String::from),
+                    ..ProfileUpdate::default()
+                },
+                None,
+            )
+        };
+        assert!(matches!(
+            change_email("new@test.com", None),
+            Err(Error::InvalidPassword)
+        ));
+        assert!(matches!(
+            change_email("patient@test.com", Some(&r.password)),
+            Err(Error::EmailTaken)
+        ));
+        change_email("new@test.com", Some(&r.password)).unwrap();
+
+        let first = match login("new@test.com", &r.password).unwrap() {
+            LoginOutcome::Authenticated(jwt) => super::decode_jwt(&jwt).unwrap().sid.unwrap(),
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        let current = match login("new@test.com", &r.password).unwrap() {
+            LoginOutcome::Authenticated(jwt) => super::decode_jwt(&jwt).unwrap().sid.unwrap(),
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        db.update_profile(
+            1,
+            &ProfileUpdate {
+                password: Some("changed".to_string()),
+                current_password: Some(r.password.clone()),
+                ..ProfileUpdate::default()
+            },
+            Some(current),
+        )
+        .unwrap();
+        let now = super::unix_now();
+        assert!(!db.touch_session(first, now).unwrap());
+        assert!(db.touch_session(current, now).unwrap());
+        assert!(matches!(
+            login("new@test.com", &r.password),
+            Err(Error::InvalidPassword)
+        ));
+
+        let book = |start_date: &str| {
+            db.book_appointment(
+                AppointmentRequest {
+                    doctor_id: 1,
+                    patient_id: 2,
+                    start_date: DateTime::parse_from_rfc3339(start_date).unwrap(),
+                    duration: 30,
+                    appointment_type_id: None,
+                    referral_id: None,
+                },
+                2,
+            )
+            .unwrap()
+        };
+        let past = book("2020-01-01T10:00:00+00:00");
+        let upcoming = book("2999-01-01T10:00:00+02:00");
+
+        assert_eq!(db.deactivate_account(1, 2).unwrap(), vec![upcoming]);
+        assert_eq!(
+            db.get_appointment(upcoming).unwrap().status,
+            AppointmentStatus::NeedsRebooking
+        );
+        assert_eq!(
+            db.get_appointment(past).unwrap().status,
+            AppointmentStatus::Booked
+        );
+        assert!(!db.touch_session(current, now).unwrap());
+        assert!(matches!(
+            login("new@test.com", "changed"),
+            Err(Error::AccountDeactivated)
+        ));
+        assert!(db
+            .search_doctors(&DoctorQuery::default())
+            .unwrap()
+            .doctors
+            .is_empty());
+        assert!(!db
+            .is_valid_appointment_request(
+                1,
+                &DateTime::parse_from_rfc3339("2999-02-01T10:00:00+00:00").unwrap(),
+                30
+            )
+            .unwrap());
+
+        db.reactivate_account(1).unwrap();
+        assert!(matches!(
+            login("new@test.com", "changed"),
+            Ok(LoginOutcome::Authenticated(_))
+        ));
     }
 }
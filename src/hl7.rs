@@ -0,0 +1,140 @@
+//! Reader for the subset of HL7 v2 ORU^R01 messages lab systems send back
+//! with results.
+//!
+//! Each OBR segment names one of our lab orders in its placer order number
+//! (OBR-2) and is followed by its OBX observations. Other segments such as
+//! PID, ORC and NTE are ignored.
+
+use crate::db::{LabFlag, LabResultData};
+
+#[derive(Debug, PartialEq)]
+pub struct OrderResults {
+    pub order_id: i32,
+    pub results: Vec<LabResultData>,
+}
+
+/// Results grouped by order, in message order. Errors name the offending
+/// segment, counting from 1.
+pub fn parse_oru(message: &str) -> Result<Vec<OrderResults>, String> {
+    let segments: Vec<&str> = message
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let header = match segments.first() {
+        Some(header) if header.starts_with("MSH") => header,
+        _ => return Err("message must start with an MSH segment".to_string()),
+    };
+    // MSH-1 is the field separator itself and MSH-2 starts with the
+    // component separator.
+    let mut separators = header[3..].chars();
+    let (field_separator, component_separator) = match (separators.next(), separators.next()) {
+        (Some(field), Some(component)) if field.is_ascii() && component.is_ascii() => {
+            (field, component)
+        }
+        _ => return Err("segment 1: MSH must start with ASCII separators".to_string()),
+    };
+    let component = |field: &str, i: usize| -> String {
+        field
+            .split(component_separator)
+            .nth(i)
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    };
+
+    let header: Vec<&str> = header.split(field_separator).collect();
+    if component(header.get(8).unwrap_or(&""), 0) != "ORU" {
+        return Err("segment 1: only ORU messages are accepted".to_string());
+    }
+
+    let mut orders: Vec<OrderResults> = Vec::new();
+    for (i, segment) in segments.iter().enumerate().skip(1) {
+        let fields: Vec<&str> = segment.split(field_separator).collect();
+        let field = |n: usize| *fields.get(n).unwrap_or(&"");
+        match fields[0] {
+            "OBR" => {
+                let order_id = component(field(2), 0)
+                    .parse()
+                    .map_err(|_| format!("segment {}: OBR-2 must be a lab order id", i + 1))?;
+                orders.push(OrderResults {
+                    order_id,
+                    results: Vec::new(),
+                });
+            }
+            "OBX" => {
+                let order = orders
+                    .last_mut()
+                    .ok_or_else(|| format!("segment {}: OBX before any OBR", i + 1))?;
+                let flag = match component(field(8), 0).as_str() {
+                    "" => None,
+                    flag => Some(LabFlag::from_hl7(flag).ok_or_else(|| {
+                        format!("segment {}: unknown abnormal flag {}", i + 1, flag)
+                    })?),
+                };
+                order.results.push(LabResultData {
+                    code: component(field(3), 0),
+                    name: component(field(3), 1),
+                    value: field(5).trim().to_string(),
+                    units: component(field(6), 0),
+                    reference_range: field(7).trim().to_string(),
+                    flag,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if orders.is_empty() {
+        return Err("message has no OBR segments".to_string());
+    }
+    if let Some(order) = orders.iter().find(|order| order.results.is_empty()) {
+        return Err(format!("order {} has no OBX segments", order.order_id));
+    }
+    Ok(orders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_oru;
+    use crate::db::LabFlag;
+
+    #[test]
+    fn test_parse_oru() {
+        let message = "MSH|^~\\&|LAB|CLINIC|||20220101120000||ORU^R01|MSG1|P|2.5\r\
+            PID|1||2^^^CLINIC||Doe^Jane\r\
+            OBR|1|7|L123|2345-7^Glucose\r\
+            OBX|1|NM|2345-7^Glucose||5.6|mmol/L^^UCUM|3.9-6.1|N|||F\r\
+            OBX|2|NM|2823-3^Potassium||6.2|mmol/L|3.5-5.0|HH|||F\r\
+            NTE|1||Hemolysed sample\r\
+            OBR|2|8|L124|718-7^Hemoglobin\r\
+            OBX|1|NM|718-7^Hemoglobin||135|g/L|120-160||||F\r";
+
+        let orders = parse_oru(message).unwrap();
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].order_id, 7);
+        assert_eq!(orders[0].results.len(), 2);
+        assert_eq!(orders[0].results[0].code, "2345-7");
+        assert_eq!(orders[0].results[0].name, "Glucose");
+        assert_eq!(orders[0].results[0].value, "5.6");
+        assert_eq!(orders[0].results[0].units, "mmol/L");
+        assert_eq!(orders[0].results[0].reference_range, "3.9-6.1");
+        assert_eq!(orders[0].results[0].flag, Some(LabFlag::Normal));
+        assert_eq!(orders[0].results[1].flag, Some(LabFlag::CriticalHigh));
+        assert_eq!(orders[1].order_id, 8);
+        assert_eq!(orders[1].results[0].flag, None);
+
+        // Newline separated segments are accepted too.
+        assert_eq!(parse_oru(&message.replace('\r', "\n")).unwrap(), orders);
+
+        assert!(parse_oru("PID|1\r").is_err());
+        assert!(parse_oru("MSH|\r").is_err());
+        assert!(parse_oru("MSHé^~\\&|LAB||||||ORU^R01\r").is_err());
+        assert!(parse_oru("MSH|^~\\&|LAB||||||ADT^A01\rOBR|1|7\r").is_err());
+        assert!(parse_oru("MSH|^~\\&|LAB||||||ORU^R01\rOBX|1|NM|A||1\r").is_err());
+        assert!(parse_oru("MSH|^~\\&|LAB||||||ORU^R01\rOBR|1|x\rOBX|1|NM|A||1\r").is_err());
+        assert!(parse_oru("MSH|^~\\&|LAB||||||ORU^R01\rOBR|1|7\r").is_err());
+        assert!(parse_oru("MSH|^~\\&|LAB||||||ORU^R01\rOBR|1|7\rOBX|1|NM|A||1||1-2|Q\r").is_err());
+    }
+}
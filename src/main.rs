@@ -5,6 +5,16 @@ extern crate derive_more;
 
 mod api;
 mod db;
+mod hl7;
+mod oidc;
+mod payments;
+mod print;
+mod rbac;
+mod storage;
+mod totp;
+mod x12;
+
+use rocket::fairing::AdHoc;
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::error::Error> {
@@ -19,18 +29,132 @@ async fn main() -> Result<(), rocket::error::Error> {
             routes![
                 api::register,
                 api::login,
+                api::login_totp,
+                api::oidc_login,
+                api::oidc_callback,
+                api::totp_enroll,
+                api::totp_confirm,
+                api::totp_recovery_codes,
+                api::totp_disable,
+                api::account_profile,
+                api::update_account_profile,
+                api::deactivate_account,
+                api::reactivate_account,
+                api::api_keys,
+                api::create_api_key,
+                api::revoke_api_key,
+                api::dependents,
+                api::create_dependent,
+                api::remove_dependent,
                 api::doctors,
+                api::search_doctors,
                 api::doctor_info,
+                api::update_doctor_profile,
                 api::doctor_booked_slots,
+                api::doctor_reviews,
+                api::review_appointment,
+                api::reviews,
+                api::moderate_review,
+                api::appointment_types,
+                api::create_appointment_type,
+                api::update_appointment_type,
+                api::questionnaires,
+                api::create_questionnaire,
+                api::update_questionnaire,
+                api::appointment_questionnaires,
+                api::attach_questionnaire,
+                api::submit_answers,
+                api::create_referral,
+                api::referral,
+                api::doctor_referrals,
+                api::patient_referrals,
+                api::visit_notes,
+                api::add_visit_note,
+                api::create_prescription,
+                api::prescription,
+                api::print_prescription,
+                api::cancel_prescription,
+                api::patient_prescriptions,
+                api::create_lab_order,
+                api::lab_order,
+                api::collect_lab_order,
+                api::record_lab_results,
+                api::review_lab_order,
+                api::import_lab_results,
+                api::patient_lab_orders,
+                api::invoices,
+                api::invoice,
+                api::appointment_invoice,
+                api::update_invoice_lines,
+                api::update_invoice_status,
+                api::patient_invoices,
+                api::insurance_plans,
+                api::create_insurance_plan,
+                api::update_insurance_plan,
+                api::patient_coverage,
+                api::claims,
+                api::claim,
+                api::export_claims,
+                api::claim_batch,
+                api::update_claim_status,
+                api::patient_claims,
+                api::consent_documents,
+                api::consent_document,
+                api::publish_consent_document,
+                api::patient_consents,
+                api::accept_consent,
+                api::withdraw_consent,
+                api::upload_appointment_attachment,
+                api::upload_patient_attachment,
+                api::appointment_attachments,
+                api::patient_attachments,
+                api::download_attachment,
+                api::create_thread,
+                api::threads,
+                api::unread_messages,
+                api::thread_messages,
+                api::send_message,
+                api::mark_thread_read,
                 api::book_doctor,
                 api::cancel_appointment,
+                api::reschedule_appointment,
+                api::check_in_appointment,
+                api::complete_appointment,
+                api::appointment_actions,
+                api::appointment_payment,
                 api::available_doctors,
                 api::appointment_details,
                 api::patient_history,
+                api::patient_profile,
+                api::update_patient_profile,
                 api::stats_top_appointments,
                 api::stats_greaterthan_hours,
+                api::sessions,
+                api::revoke_session,
+                api::revoke_sessions,
+                api::force_logout,
+                api::lockouts,
+                api::clear_lockout,
+                api::roles,
+                api::save_role,
+                api::assign_role,
+                api::revoke_role,
             ],
         )
+        .attach(AdHoc::config::<db::LoginPolicy>())
+        .attach(AdHoc::config::<oidc::OidcSettings>())
+        .attach(AdHoc::config::<storage::AttachmentSettings>())
+        .attach(AdHoc::config::<payments::PaymentSettings>())
+        .attach(AdHoc::on_ignite("Attachment storage", |rocket| async {
+            let root = rocket
+                .state::<storage::AttachmentSettings>()
+                .map(|settings| settings.attachments_dir.clone())
+                .unwrap_or_default();
+            let storage: Box<dyn storage::Storage> = Box::new(storage::LocalStorage::new(root));
+            rocket.manage(storage)
+        }))
+        // Swap in a real gateway's `PaymentProvider` to take actual payments.
+        .manage(Box::new(payments::FakeProvider::default()) as Box<dyn payments::PaymentProvider>)
         .launch()
         .await
 }
@@ -0,0 +1,357 @@
+//! OpenID Connect authorization-code flow against the staff identity provider.
+
+use derive_more::{Display, From};
+use hyper::body::Buf;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rocket::http::RawStr;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Display, From)]
+pub enum Error {
+    #[display(fmt = "request failed: {}", _0)]
+    Http(hyper::Error),
+    #[display(fmt = "invalid request: {}", _0)]
+    Request(hyper::http::Error),
+    #[display(fmt = "invalid response: {}", _0)]
+    Json(serde_json::Error),
+    #[display(fmt = "invalid token: {}", _0)]
+    Jwt(jsonwebtoken::errors::Error),
+    #[from(ignore)]
+    #[display(fmt = "provider answered {}", _0)]
+    Provider(String),
+    UnknownSigningKey,
+    InvalidNonce,
+    UnverifiedEmail,
+}
+
+/// Identity provider settings, read from the `oidc` table of the Rocket config.
+/// SSO is disabled when it's missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer URL; `/.well-known/openid-configuration` is resolved against it.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Must point at `/oidc/callback` and be registered with the provider.
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+}
+
+fn default_scopes() -> String {
+    "openid email".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OidcSettings {
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+}
+
+impl IdTokenClaims {
+    /// The email, if the provider vouches for it. Only such an email may be
+    /// used to link the identity to an existing account.
+    pub fn verified_email(&self) -> Option<&str> {
+        match self.email_verified {
+            Some(true) => self.email.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+fn client() -> Client<HttpsConnector<HttpConnector>> {
+    Client::builder().build(HttpsConnector::with_webpki_roots())
+}
+
+async fn send_json<T: DeserializeOwned>(request: Request<Body>) -> Result<T, Error> {
+    let response = client().request(request).await?;
+    let status = response.status();
+    let body = hyper::body::aggregate(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(Error::Provider(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(body.chunk())
+        )));
+    }
+
+    Ok(serde_json::from_reader(body.reader())?)
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
+    send_json(Request::get(url).body(Body::empty())?).await
+}
+
+pub async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, Error> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    );
+    get_json(&url).await
+}
+
+fn form_encode(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, RawStr::new(v).percent_encode()))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Where to send the user's browser to start the login.
+pub fn authorization_url(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    state: &str,
+    nonce: &str,
+) -> String {
+    let separator = if metadata.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!(
+        "{}{}{}",
+        metadata.authorization_endpoint,
+        separator,
+        form_encode(&[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_uri),
+            ("scope", &config.scopes),
+            ("state", state),
+            ("nonce", nonce),
+        ])
+    )
+}
+
+/// Trades the authorization code for the provider's ID token.
+pub async fn exchange_code(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    code: &str,
+) -> Result<String, Error> {
+    let body = form_encode(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &config.redirect_uri),
+        ("client_id", &config.client_id),
+        ("client_secret", &config.client_secret),
+    ]);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&metadata.token_endpoint)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/json")
+        .body(Body::from(body))?;
+
+    let response: TokenResponse = send_json(request).await?;
+    Ok(response.id_token)
+}
+
+/// Checks the ID token signature, issuer, audience, expiry and nonce.
+/// HMAC tokens are verified with the client secret and RSA tokens against
+/// the provider's JWKS.
+pub async fn verify_id_token(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, Error> {
+    let header = decode_header(id_token)?;
+    let key = match header.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            DecodingKey::from_secret(config.client_secret.as_bytes())
+        }
+        _ => {
+            let jwks: Jwks = get_json(&metadata.jwks_uri).await?;
+            let jwk = jwks
+                .keys
+                .iter()
+                .filter(|k| k.kty == "RSA")
+                .find(|k| header.kid.is_none() || k.kid == header.kid)
+                .ok_or(Error::UnknownSigningKey)?;
+            match (&jwk.n, &jwk.e) {
+                (Some(n), Some(e)) => DecodingKey::from_rsa_components(n, e)?,
+                _ => return Err(Error::UnknownSigningKey),
+            }
+        }
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_issuer(&[&metadata.issuer]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)?.claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(Error::InvalidNonce);
+    }
+    if claims.email_verified == Some(false) {
+        return Err(Error::UnverifiedEmail);
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        authorization_url, discover, exchange_code, verify_id_token, IdTokenClaims, OidcConfig,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves discovery and token requests like a provider would, issuing an
+    /// HS256 ID token for `nonce`.
+    fn mock_idp(secret: &'static str, nonce: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let base = issuer.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Both requests are small enough to arrive with their headers.
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+                let body = match path.as_str() {
+                    "/.well-known/openid-configuration" => json!({
+                        "issuer": base,
+                        "authorization_endpoint": format!("{}/authorize", base),
+                        "token_endpoint": format!("{}/token", base),
+                        "jwks_uri": format!("{}/jwks", base),
+                    }),
+                    "/token" => {
+                        let claims = json!({
+                            "iss": base,
+                            "sub": "staff-1",
+                            "aud": "backend",
+                            "exp": crate::db::unix_now() + 60,
+                            "nonce": nonce,
+                            "email": "doctor@hospital.test",
+                            "email_verified": true,
+                        });
+                        let token = encode(
+                            &Header::default(),
+                            &claims,
+                            &EncodingKey::from_secret(secret.as_bytes()),
+                        )
+                        .unwrap();
+                        json!({ "id_token": token, "token_type": "Bearer" })
+                    }
+                    _ => json!({ "keys": [] }),
+                }
+                .to_string();
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        issuer
+    }
+
+    #[rocket::async_test]
+    async fn test_oidc_flow() {
+        let issuer = mock_idp("client-secret", "nonce-1");
+        let config = OidcConfig {
+            issuer: issuer.clone(),
+            client_id: "backend".to_string(),
+            client_secret: "client-secret".to_string(),
+            redirect_uri: "http://localhost:8000/oidc/callback".to_string(),
+            scopes: "openid email".to_string(),
+        };
+
+        let metadata = discover(&config).await.unwrap();
+        assert_eq!(metadata.token_endpoint, format!("{}/token", issuer));
+
+        let url = authorization_url(&config, &metadata, "state-1", "nonce-1");
+        assert!(url.starts_with(&format!("{}/authorize?response_type=code", issuer)));
+        assert!(url.contains("&state=state-1&nonce=nonce-1"));
+
+        let id_token = exchange_code(&config, &metadata, "code").await.unwrap();
+        let claims = verify_id_token(&config, &metadata, &id_token, "nonce-1")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "staff-1");
+        assert_eq!(claims.email.as_deref(), Some("doctor@hospital.test"));
+        assert_eq!(claims.verified_email(), Some("doctor@hospital.test"));
+
+        // Without the claim the email can't be trusted for linking.
+        let unverified = IdTokenClaims {
+            email_verified: None,
+            ..claims
+        };
+        assert_eq!(unverified.verified_email(), None);
+
+        assert!(verify_id_token(&config, &metadata, &id_token, "other")
+            .await
+            .is_err());
+
+        let wrong_secret = OidcConfig {
+            client_secret: "other".to_string(),
+            ..config
+        };
+        assert!(
+            verify_id_token(&wrong_secret, &metadata, &id_token, "nonce-1")
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Card payments for booking deposits. Providers hold funds with `authorize`,
+//! take them with `capture` and return them with `refund`. Holds that won't
+//! be captured are released with `void`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The card was refused; the reason can be shown to the payer.
+    Declined(String),
+    /// The request doesn't match the payment, e.g. capturing too much.
+    Provider(String),
+}
+
+pub trait PaymentProvider: Send + Sync {
+    /// Holds `amount` on the card behind `token` and returns the provider's
+    /// payment reference.
+    fn authorize(&self, amount: i64, token: &str) -> Result<String, Error>;
+    fn capture(&self, payment: &str, amount: i64) -> Result<(), Error>;
+    fn refund(&self, payment: &str, amount: i64) -> Result<(), Error>;
+    /// Releases the hold on a payment that wasn't captured.
+    fn void(&self, payment: &str) -> Result<(), Error>;
+}
+
+/// Deposit refund rules, read from the Rocket config
+/// (e.g. `ROCKET_FULL_REFUND_HOURS=48`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaymentSettings {
+    /// Cancelling at least this long before the start refunds everything.
+    pub full_refund_hours: i64,
+    /// Share refunded for later cancellations made before the start.
+    pub late_refund_percent: i64,
+}
+
+impl Default for PaymentSettings {
+    fn default() -> Self {
+        Self {
+            full_refund_hours: 24,
+            late_refund_percent: 50,
+        }
+    }
+}
+
+impl PaymentSettings {
+    /// How much of `paid` goes back when an appointment is cancelled
+    /// `notice_secs` before it starts. Cancellations by the clinic are
+    /// refunded in full.
+    pub fn refund_amount(&self, paid: i64, notice_secs: i64, by_clinic: bool) -> i64 {
+        if by_clinic || notice_secs >= self.full_refund_hours * 60 * 60 {
+            paid
+        } else if notice_secs > 0 {
+            paid * self.late_refund_percent.clamp(0, 100) / 100
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FakePayment {
+    authorized: i64,
+    captured: i64,
+    refunded: i64,
+}
+
+/// Keeps payments in memory and numbers them in order. Every token is
+/// accepted except `tok_declined`.
+#[derive(Default)]
+pub struct FakeProvider {
+    payments: Mutex<HashMap<String, FakePayment>>,
+}
+
+impl FakeProvider {
+    pub const DECLINED_TOKEN: &'static str = "tok_declined";
+}
+
+impl PaymentProvider for FakeProvider {
+    fn authorize(&self, amount: i64, token: &str) -> Result<String, Error> {
+        if token.trim().is_empty() || token == Self::DECLINED_TOKEN {
+            return Err(Error::Declined("card declined".to_string()));
+        }
+        if amount <= 0 {
+            return Err(Error::Provider("amount must be positive".to_string()));
+        }
+
+        let mut payments = self.payments.lock().unwrap();
+        let payment = format!("fake_{}", payments.len() + 1);
+        payments.insert(
+            payment.clone(),
+            FakePayment {
+                authorized: amount,
+                ..FakePayment::default()
+            },
+        );
+        Ok(payment)
+    }
+
+    fn capture(&self, payment: &str, amount: i64) -> Result<(), Error> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments
+            .get_mut(payment)
+            .ok_or_else(|| Error::Provider("unknown payment".to_string()))?;
+        if amount <= 0 || payment.captured + amount > payment.authorized {
+            return Err(Error::Provider(
+                "capture exceeds the authorized amount".to_string(),
+            ));
+        }
+        payment.captured += amount;
+        Ok(())
+    }
+
+    fn refund(&self, payment: &str, amount: i64) -> Result<(), Error> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments
+            .get_mut(payment)
+            .ok_or_else(|| Error::Provider("unknown payment".to_string()))?;
+        if amount <= 0 || payment.refunded + amount > payment.captured {
+            return Err(Error::Provider(
+                "refund exceeds the captured amount".to_string(),
+            ));
+        }
+        payment.refunded += amount;
+        Ok(())
+    }
+
+    fn void(&self, payment: &str) -> Result<(), Error> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments
+            .get_mut(payment)
+            .ok_or_else(|| Error::Provider("unknown payment".to_string()))?;
+        if payment.captured > 0 {
+            return Err(Error::Provider(
+                "captured payments must be refunded".to_string(),
+            ));
+        }
+        payment.authorized = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, FakeProvider, PaymentProvider, PaymentSettings};
+
+    #[test]
+    fn test_fake_provider() {
+        let provider = FakeProvider::default();
+        assert!(matches!(
+            provider.authorize(1000, FakeProvider::DECLINED_TOKEN),
+            Err(Error::Declined(_))
+        ));
+
+        let payment = provider.authorize(1000, "tok_visa").unwrap();
+        assert_eq!(payment, "fake_1");
+        assert!(provider.refund(&payment, 100).is_err());
+        assert!(provider.capture(&payment, 1001).is_err());
+        provider.capture(&payment, 1000).unwrap();
+        provider.refund(&payment, 600).unwrap();
+        provider.refund(&payment, 400).unwrap();
+        assert!(provider.refund(&payment, 1).is_err());
+
+        assert!(provider.void(&payment).is_err());
+
+        assert_eq!(provider.authorize(500, "tok_visa").unwrap(), "fake_2");
+        assert!(provider.capture("fake_3", 100).is_err());
+        provider.void("fake_2").unwrap();
+        assert!(provider.capture("fake_2", 500).is_err());
+    }
+
+    #[test]
+    fn test_refund_amount() {
+        let settings = PaymentSettings::default();
+        let hour = 60 * 60;
+        assert_eq!(settings.refund_amount(1000, 48 * hour, false), 1000);
+        assert_eq!(settings.refund_amount(1000, 24 * hour, false), 1000);
+        assert_eq!(settings.refund_amount(1000, 2 * hour, false), 500);
+        assert_eq!(settings.refund_amount(1000, -hour, false), 0);
+        assert_eq!(settings.refund_amount(1000, -hour, true), 1000);
+    }
+}
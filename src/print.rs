@@ -0,0 +1,149 @@
+//! Printable HTML documents.
+
+use crate::db::{DoctorInfo, PatientProfile, Prescription, PrescriptionStatus};
+use chrono::NaiveDateTime;
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn format_date(unix_time: i64) -> String {
+    NaiveDateTime::from_timestamp(unix_time, 0)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// A standalone page meant to be printed or saved as PDF from the browser.
+pub fn prescription_html(
+    prescription: &Prescription,
+    patient: &PatientProfile,
+    doctor: &DoctorInfo,
+) -> String {
+    let date_of_birth = patient
+        .demographics
+        .as_ref()
+        .and_then(|d| d.date_of_birth)
+        .map(|d| d.to_string())
+        .unwrap_or_default();
+    let refills = match prescription.refills {
+        0 => "No refills".to_string(),
+        1 => "1 refill".to_string(),
+        n => format!("{} refills", n),
+    };
+    let cancelled = match prescription.status {
+        PrescriptionStatus::Cancelled => "<p class=\"cancelled\">CANCELLED</p>",
+        PrescriptionStatus::Active => "",
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Prescription #{id}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; }}
+th {{ text-align: left; padding-right: 1em; }}
+.cancelled {{ color: #b00; font-weight: bold; }}
+@media print {{ body {{ margin: 0; }} }}
+</style>
+</head>
+<body>
+<h1>Prescription #{id}</h1>
+{cancelled}<table>
+<tr><th>Prescriber</th><td>{doctor}</td></tr>
+<tr><th>Clinic</th><td>{clinic}</td></tr>
+<tr><th>Patient</th><td>{patient}</td></tr>
+<tr><th>Date of birth</th><td>{date_of_birth}</td></tr>
+<tr><th>Issued</th><td>{issued}</td></tr>
+<tr><th>Valid until</th><td>{expires}</td></tr>
+</table>
+<h2>{drug}</h2>
+<p>{dose}, {frequency}, for {days} days. {refills}.</p>
+<p>{instructions}</p>
+</body>
+</html>
+"#,
+        id = prescription.id,
+        cancelled = cancelled,
+        doctor = escape_html(&doctor.name),
+        clinic = escape_html(&doctor.clinic),
+        patient = escape_html(&patient.name),
+        date_of_birth = date_of_birth,
+        issued = format_date(prescription.created_at),
+        expires = format_date(prescription.expires_at),
+        drug = escape_html(&prescription.drug),
+        dose = escape_html(&prescription.dose),
+        frequency = escape_html(&prescription.frequency),
+        days = prescription.duration_days,
+        refills = refills,
+        instructions = escape_html(&prescription.instructions),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prescription_html;
+    use crate::db::{DoctorInfo, PatientProfile, Prescription, PrescriptionStatus};
+
+    #[test]
+    fn test_prescription_html() {
+        let prescription = Prescription {
+            id: 7,
+            appointment_id: 1,
+            patient_id: 2,
+            doctor_id: 1,
+            drug: "Amoxicillin <script>".to_string(),
+            dose: "500 mg".to_string(),
+            frequency: "twice daily".to_string(),
+            duration_days: 7,
+            refills: 0,
+            instructions: "With food & water".to_string(),
+            allergy_override: None,
+            status: PrescriptionStatus::Active,
+            created_at: 1_640_995_200,
+            expires_at: 1_641_600_000,
+        };
+        let patient = PatientProfile {
+            id: 2,
+            name: "Jane O'Neil".to_string(),
+            demographics: None,
+            contact: None,
+            insurance: None,
+            clinical: None,
+        };
+        let doctor = DoctorInfo {
+            id: 1,
+            name: "Dr. Smith".to_string(),
+            specialty: "General".to_string(),
+            details: String::new(),
+            languages: Vec::new(),
+            qualifications: Vec::new(),
+            consultation_fee: None,
+            photo_url: None,
+            clinic: "Downtown".to_string(),
+            rating: None,
+            review_count: 0,
+        };
+
+        let html = prescription_html(&prescription, &patient, &doctor);
+        assert!(html.contains("<h1>Prescription #7</h1>"));
+        assert!(html.contains("<h2>Amoxicillin &lt;script&gt;</h2>"));
+        assert!(html.contains("Jane O&#39;Neil"));
+        assert!(html.contains("With food &amp; water"));
+        assert!(html.contains("<td>2022-01-01</td>"));
+        assert!(html.contains("No refills."));
+        assert!(!html.contains("CANCELLED"));
+    }
+}
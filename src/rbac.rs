@@ -0,0 +1,207 @@
+//! Permission names checked by `AccountGuard`.
+//!
+//! Scoped permissions are stored with an `.any` or `.own` suffix, e.g.
+//! `appointment.cancel.any` lets an account cancel every appointment while
+//! `appointment.cancel.own` only covers appointments it owns: as the doctor,
+//! the patient, or the patient's guardian.
+
+pub const ANY: &str = "any";
+pub const OWN: &str = "own";
+
+// Scoped
+pub const APPOINTMENT_BOOK: &str = "appointment.book";
+pub const APPOINTMENT_READ: &str = "appointment.read";
+pub const APPOINTMENT_CANCEL: &str = "appointment.cancel";
+pub const APPOINTMENT_RESCHEDULE: &str = "appointment.reschedule";
+pub const APPOINTMENT_CHECK_IN: &str = "appointment.check_in";
+/// `.own` covers the appointment's doctor.
+pub const APPOINTMENT_COMPLETE: &str = "appointment.complete";
+pub const PATIENT_HISTORY_READ: &str = "patient.history.read";
+pub const DOCTOR_PROFILE_EDIT: &str = "doctor.profile.edit";
+pub const REVIEW_WRITE: &str = "review.write";
+/// `.own` covers the patient, their guardians, and the referring and target doctors.
+pub const REFERRAL_READ: &str = "referral.read";
+/// `.own` covers only the treating doctor. Other staff need a role granting `.any`.
+pub const VISIT_NOTE_READ: &str = "visit_note.read";
+pub const VISIT_NOTE_WRITE: &str = "visit_note.write";
+/// `.own` covers the patient, their guardians, and the prescribing doctor.
+pub const PRESCRIPTION_READ: &str = "prescription.read";
+/// `.own` covers the appointment's doctor.
+pub const PRESCRIPTION_WRITE: &str = "prescription.write";
+/// Uploads follow the ownership rules of the appointment or patient.
+pub const ATTACHMENT_WRITE: &str = "attachment.write";
+/// `.own` covers the patient and their guardians.
+pub const QUESTIONNAIRE_ANSWER: &str = "questionnaire.answer";
+/// Adding templates and attaching them to bookings. Editing a template
+/// needs `.any` unless the caller created it.
+pub const QUESTIONNAIRES_MANAGE: &str = "questionnaires.manage";
+/// `.own` covers the thread's doctor, its patient and the patient's guardians.
+pub const MESSAGE_READ: &str = "message.read";
+pub const MESSAGE_WRITE: &str = "message.write";
+/// `.own` covers the patient, their guardians, and the ordering doctor.
+/// The patient's side only sees results once they are reviewed.
+pub const LAB_READ: &str = "lab.read";
+/// Ordering tests and reviewing their results. `.own` covers the
+/// appointment's doctor.
+pub const LAB_ORDER: &str = "lab.order";
+/// `.own` covers the patient and their guardians. Drafts need `invoices.manage`.
+pub const INVOICE_READ: &str = "invoice.read";
+/// `.own` covers the patient and their guardians.
+pub const CLAIM_READ: &str = "claim.read";
+/// Reading and withdrawing a patient's consents. `.own` covers the patient
+/// and their guardians.
+pub const CONSENT_MANAGE: &str = "consent.manage";
+/// Accepting consent documents for a patient. Only the patient's side can
+/// consent, so roles should only grant `.own`.
+pub const CONSENT_GIVE: &str = "consent.give";
+// Each patient profile field group is read and updated under its own permission.
+pub const PATIENT_PROFILE_DEMOGRAPHICS: &str = "patient.profile.demographics";
+pub const PATIENT_PROFILE_CONTACT: &str = "patient.profile.contact";
+pub const PATIENT_PROFILE_INSURANCE: &str = "patient.profile.insurance";
+pub const PATIENT_PROFILE_CLINICAL: &str = "patient.profile.clinical";
+
+// Unscoped
+pub const DOCTOR_SLOTS_READ: &str = "doctor.slots.read";
+pub const DOCTOR_SLOTS_PATIENTS: &str = "doctor.slots.patients";
+pub const DOCTOR_AVAILABILITY_READ: &str = "doctor.availability.read";
+pub const STATS_READ: &str = "stats.read";
+pub const LOCKOUTS_MANAGE: &str = "lockouts.manage";
+pub const ROLES_MANAGE: &str = "roles.manage";
+pub const API_KEYS_MANAGE: &str = "api_keys.manage";
+pub const SESSIONS_MANAGE: &str = "sessions.manage";
+pub const DEPENDENTS_MANAGE: &str = "dependents.manage";
+pub const ACCOUNTS_MANAGE: &str = "accounts.manage";
+pub const REVIEWS_MODERATE: &str = "reviews.moderate";
+/// Doctors can only refer patients they have seen.
+pub const REFERRAL_CREATE: &str = "referral.create";
+pub const APPOINTMENT_TYPES_MANAGE: &str = "appointment_types.manage";
+/// Marking specimens collected and recording or importing results, e.g.
+/// through an API key for the lab's system.
+pub const LAB_RESULTS_RECORD: &str = "lab.results.record";
+/// Listing every invoice, editing drafts and moving invoices through their statuses.
+pub const INVOICES_MANAGE: &str = "invoices.manage";
+pub const INSURANCE_PLANS_MANAGE: &str = "insurance_plans.manage";
+/// Listing every claim, exporting batches and recording payer decisions.
+pub const CLAIMS_MANAGE: &str = "claims.manage";
+/// Publishing consent documents and their new versions.
+pub const CONSENT_DOCUMENTS_MANAGE: &str = "consent_documents.manage";
+
+/// Default roles. Each permission is granted the first time it appears here,
+/// after which admins are free to edit the roles. Every account implicitly has
+/// the role named after its `AccountType`.
+pub const DEFAULT_ROLES: &[(&str, &[&str])] = &[
+    (
+        "patient",
+        &[
+            "appointment.book.own",
+            "appointment.read.own",
+            "appointment.cancel.own",
+            "patient.history.read.own",
+            "patient.profile.demographics.own",
+            "patient.profile.contact.own",
+            "patient.profile.insurance.own",
+            "patient.profile.clinical.own",
+            "review.write.own",
+            "referral.read.own",
+            "prescription.read.own",
+            "attachment.write.own",
+            "questionnaire.answer.own",
+            "message.read.own",
+            "message.write.own",
+            "lab.read.own",
+            "invoice.read.own",
+            "claim.read.own",
+            "consent.manage.own",
+            "consent.give.own",
+            DOCTOR_SLOTS_READ,
+            DEPENDENTS_MANAGE,
+        ],
+    ),
+    (
+        "doctor",
+        &[
+            "appointment.read.own",
+            "appointment.cancel.own",
+            "patient.history.read.any",
+            "patient.profile.demographics.any",
+            "patient.profile.contact.any",
+            "patient.profile.clinical.any",
+            DOCTOR_SLOTS_READ,
+            DOCTOR_SLOTS_PATIENTS,
+            "doctor.profile.edit.own",
+            "referral.read.own",
+            REFERRAL_CREATE,
+            "visit_note.read.own",
+            "visit_note.write.own",
+            "prescription.read.own",
+            "prescription.write.own",
+            "attachment.write.own",
+            "message.read.own",
+            "message.write.own",
+            "lab.read.own",
+            "lab.order.own",
+            "appointment.complete.own",
+            "questionnaires.manage.own",
+        ],
+    ),
+    (
+        "admin",
+        &[
+            "appointment.read.any",
+            "appointment.cancel.any",
+            "appointment.reschedule.any",
+            "appointment.check_in.any",
+            "patient.history.read.any",
+            "doctor.profile.edit.any",
+            "patient.profile.demographics.any",
+            "patient.profile.contact.any",
+            "patient.profile.insurance.any",
+            "referral.read.any",
+            "prescription.read.any",
+            "attachment.write.any",
+            "lab.read.any",
+            "appointment.complete.any",
+            "invoice.read.any",
+            "claim.read.any",
+            "consent.manage.any",
+            DOCTOR_SLOTS_READ,
+            DOCTOR_SLOTS_PATIENTS,
+            DOCTOR_AVAILABILITY_READ,
+            STATS_READ,
+            LOCKOUTS_MANAGE,
+            ROLES_MANAGE,
+            API_KEYS_MANAGE,
+            SESSIONS_MANAGE,
+            ACCOUNTS_MANAGE,
+            REVIEWS_MODERATE,
+            APPOINTMENT_TYPES_MANAGE,
+            "questionnaires.manage.any",
+            LAB_RESULTS_RECORD,
+            INVOICES_MANAGE,
+            INSURANCE_PLANS_MANAGE,
+            CLAIMS_MANAGE,
+            CONSENT_DOCUMENTS_MANAGE,
+        ],
+    ),
+    (
+        "receptionist",
+        &[
+            "appointment.book.any",
+            "appointment.read.any",
+            "appointment.cancel.any",
+            "appointment.reschedule.any",
+            "appointment.check_in.any",
+            "patient.profile.demographics.any",
+            "patient.profile.contact.any",
+            "patient.profile.insurance.any",
+            "referral.read.any",
+            "attachment.write.any",
+            "invoice.read.any",
+            "claim.read.any",
+            "consent.manage.any",
+            DOCTOR_SLOTS_READ,
+            DOCTOR_SLOTS_PATIENTS,
+            DOCTOR_AVAILABILITY_READ,
+        ],
+    ),
+];
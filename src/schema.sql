@@ -4,7 +4,8 @@ CREATE TABLE IF NOT EXISTS account (
     fullname VARCHAR(100) NOT NULL,
     email VARCHAR(62) NOT NULL UNIQUE,
     passhash VARCHAR(60) NOT NULL,
-    account_type INTEGER NOT NULL
+    account_type INTEGER NOT NULL,
+    deactivated_at INTEGER
 );
 
 
@@ -14,6 +15,36 @@ CREATE TABLE IF NOT EXISTS doctor (
     details TEXT NOT NULL default "",
     starting_hour char(5) NOT NULL default "08:00",
     ending_hour char(5) NOT NULL default "18:00",
+    languages TEXT NOT NULL default "[]",
+    qualifications TEXT NOT NULL default "[]",
+    consultation_fee INTEGER,
+    photo_url TEXT,
+    clinic VARCHAR(100) NOT NULL default "",
+    FOREIGN KEY(id) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS patient ( 
+    id INTEGER PRIMARY KEY NOT NULL UNIQUE,
+    date_of_birth CHAR(10),
+    sex INTEGER,
+    phone VARCHAR(30),
+    address TEXT,
+    emergency_contact_name VARCHAR(100),
+    emergency_contact_phone VARCHAR(30),
+    emergency_contact_relationship VARCHAR(50),
+    insurance_provider VARCHAR(100),
+    insurance_policy_number VARCHAR(50),
+    insurance_plan INTEGER REFERENCES insurance_plan(id),
+    insurance_group_number VARCHAR(50),
+    insurance_valid_until CHAR(10),
+    allergies TEXT NOT NULL default "[]",
+    FOREIGN KEY(id) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS admin ( 
+    id INTEGER PRIMARY KEY NOT NULL UNIQUE,
     FOREIGN KEY(id) REFERENCES account(id)
 );
 
@@ -26,7 +57,508 @@ CREATE TABLE IF NOT EXISTS appointment (
     starting_date INTEGER NOT NULL,
     duration_mins INTEGER NOT NULL 
     check (duration_mins >= 15 and duration_mins <= 120),
+    appointment_type INTEGER REFERENCES appointment_type(id),
+    referral INTEGER REFERENCES referral(id),
     FOREIGN KEY(patient) REFERENCES account(id), 
     FOREIGN KEY(doctor) REFERENCES account(id)
 );
+
+
+CREATE TABLE IF NOT EXISTS receptionist ( 
+    id INTEGER PRIMARY KEY NOT NULL UNIQUE,
+    FOREIGN KEY(id) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS guardian ( 
+    guardian INTEGER NOT NULL,
+    dependent INTEGER NOT NULL,
+    relationship VARCHAR(50) NOT NULL default "",
+    PRIMARY KEY(guardian, dependent),
+    FOREIGN KEY(guardian) REFERENCES account(id),
+    FOREIGN KEY(dependent) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS appointment_action ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL,
+    actor INTEGER NOT NULL,
+    action INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(actor) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS login_throttle ( 
+    scope INTEGER NOT NULL,
+    value VARCHAR(62) NOT NULL,
+    failed_attempts INTEGER NOT NULL default 0,
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER,
+    PRIMARY KEY(scope, value)
+);
+
+
+CREATE TABLE IF NOT EXISTS totp ( 
+    account INTEGER PRIMARY KEY NOT NULL UNIQUE,
+    secret VARCHAR(32) NOT NULL,
+    enabled INTEGER NOT NULL default 0,
+    last_used_step INTEGER NOT NULL default 0,
+    FOREIGN KEY(account) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS recovery_code ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    account INTEGER NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY(account) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS role ( 
+    name VARCHAR(50) PRIMARY KEY NOT NULL UNIQUE
+);
+
+
+CREATE TABLE IF NOT EXISTS role_permission ( 
+    role VARCHAR(50) NOT NULL,
+    permission VARCHAR(100) NOT NULL,
+    PRIMARY KEY(role, permission),
+    FOREIGN KEY(role) REFERENCES role(name)
+);
+
+
+CREATE TABLE IF NOT EXISTS seeded_permission ( 
+    role VARCHAR(50) NOT NULL,
+    permission VARCHAR(100) NOT NULL,
+    PRIMARY KEY(role, permission)
+);
+
+
+CREATE TABLE IF NOT EXISTS account_role ( 
+    account INTEGER NOT NULL,
+    role VARCHAR(50) NOT NULL,
+    PRIMARY KEY(account, role),
+    FOREIGN KEY(account) REFERENCES account(id),
+    FOREIGN KEY(role) REFERENCES role(name)
+);
+
+
+CREATE TABLE IF NOT EXISTS api_key ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    name VARCHAR(100) NOT NULL,
+    key_hash CHAR(64) NOT NULL UNIQUE,
+    created_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    last_used_at INTEGER,
+    revoked_at INTEGER,
+    FOREIGN KEY(created_by) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS api_key_permission ( 
+    api_key INTEGER NOT NULL,
+    permission VARCHAR(100) NOT NULL,
+    PRIMARY KEY(api_key, permission),
+    FOREIGN KEY(api_key) REFERENCES api_key(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS session ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    account INTEGER NOT NULL,
+    user_agent TEXT,
+    ip VARCHAR(45),
+    created_at INTEGER NOT NULL,
+    last_seen_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked_at INTEGER,
+    FOREIGN KEY(account) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS oidc_state ( 
+    state CHAR(64) PRIMARY KEY,
+    nonce CHAR(64) NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+
+CREATE TABLE IF NOT EXISTS oidc_identity ( 
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    account INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY(issuer, subject),
+    FOREIGN KEY(account) REFERENCES account(id)
+);
+
+
+
+CREATE TABLE IF NOT EXISTS review ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL UNIQUE,
+    doctor INTEGER NOT NULL,
+    author INTEGER NOT NULL,
+    rating INTEGER NOT NULL check (rating >= 1 and rating <= 5),
+    body TEXT NOT NULL default "",
+    review_status INTEGER NOT NULL default 0,
+    created_at INTEGER NOT NULL,
+    moderated_by INTEGER,
+    moderated_at INTEGER,
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(doctor) REFERENCES account(id),
+    FOREIGN KEY(author) REFERENCES account(id),
+    FOREIGN KEY(moderated_by) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS appointment_type ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    referral_required INTEGER NOT NULL default 0,
+    fee INTEGER,
+    tax_rate_bps INTEGER NOT NULL default 0,
+    deposit INTEGER,
+    telehealth INTEGER NOT NULL default 0
+);
+
+
+CREATE TABLE IF NOT EXISTS referral ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    patient INTEGER NOT NULL,
+    referring_doctor INTEGER NOT NULL,
+    target_doctor INTEGER,
+    target_specialty VARCHAR(100),
+    reason TEXT NOT NULL,
+    urgency INTEGER NOT NULL,
+    referral_status INTEGER NOT NULL,
+    appointment INTEGER,
+    created_at INTEGER NOT NULL,
+    check (target_doctor IS NOT NULL OR target_specialty IS NOT NULL),
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(referring_doctor) REFERENCES account(id),
+    FOREIGN KEY(target_doctor) REFERENCES account(id),
+    FOREIGN KEY(appointment) REFERENCES appointment(id)
+);
+
+
+-- Visit notes are append-only: amendments are stored as new versions.
+CREATE TABLE IF NOT EXISTS visit_note ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    author INTEGER NOT NULL,
+    chief_complaint TEXT NOT NULL,
+    findings TEXT NOT NULL,
+    diagnosis_codes TEXT NOT NULL default "[]",
+    plan TEXT NOT NULL,
+    patient_summary TEXT NOT NULL,
+    amendment_reason TEXT,
+    created_at INTEGER NOT NULL,
+    UNIQUE(appointment, version),
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(author) REFERENCES account(id)
+);
+
+CREATE TRIGGER IF NOT EXISTS visit_note_no_update BEFORE UPDATE ON visit_note BEGIN
+    SELECT RAISE(ABORT, 'visit notes are append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS visit_note_no_delete BEFORE DELETE ON visit_note BEGIN
+    SELECT RAISE(ABORT, 'visit notes are append-only');
+END;
+
+
+CREATE TABLE IF NOT EXISTS prescription ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL,
+    patient INTEGER NOT NULL,
+    doctor INTEGER NOT NULL,
+    drug VARCHAR(200) NOT NULL,
+    dose VARCHAR(100) NOT NULL,
+    frequency VARCHAR(100) NOT NULL,
+    duration_days INTEGER NOT NULL,
+    refills INTEGER NOT NULL default 0,
+    instructions TEXT NOT NULL default "",
+    allergy_override TEXT,
+    prescription_status INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    check (duration_days >= 1 and duration_days <= 365),
+    check (refills >= 0 and refills <= 12),
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(doctor) REFERENCES account(id)
+);
+
+
+-- Contents are kept in the attachment storage under storage_key.
+CREATE TABLE IF NOT EXISTS attachment ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    patient INTEGER NOT NULL,
+    appointment INTEGER,
+    uploaded_by INTEGER NOT NULL,
+    filename VARCHAR(200) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    sha256 CHAR(64) NOT NULL,
+    storage_key VARCHAR(64) NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(uploaded_by) REFERENCES account(id)
+);
+
+
+-- Intake form templates, attached to bookings of their appointment type.
+CREATE TABLE IF NOT EXISTS questionnaire ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    name VARCHAR(100) NOT NULL,
+    appointment_type INTEGER NOT NULL,
+    required INTEGER NOT NULL default 0,
+    questions TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(appointment_type) REFERENCES appointment_type(id),
+    FOREIGN KEY(created_by) REFERENCES account(id)
+);
+
+
+-- Forms sent out with a booking. The name and questions are copied from the
+-- template when attaching, so later template edits don't change them.
+CREATE TABLE IF NOT EXISTS appointment_questionnaire ( 
+    appointment INTEGER NOT NULL,
+    questionnaire INTEGER NOT NULL,
+    required INTEGER NOT NULL,
+    name VARCHAR(100),
+    questions TEXT,
+    answers TEXT,
+    submitted_at INTEGER,
+    PRIMARY KEY(appointment, questionnaire),
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(questionnaire) REFERENCES questionnaire(id)
+);
+
+
+-- Threads between a doctor and a patient, optionally about one appointment.
+CREATE TABLE IF NOT EXISTS message_thread ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    patient INTEGER NOT NULL,
+    doctor INTEGER NOT NULL,
+    appointment INTEGER,
+    subject VARCHAR(200) NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(doctor) REFERENCES account(id),
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(created_by) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS message ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    thread INTEGER NOT NULL,
+    author INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    attachment INTEGER,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(thread) REFERENCES message_thread(id),
+    FOREIGN KEY(author) REFERENCES account(id),
+    FOREIGN KEY(attachment) REFERENCES attachment(id)
+);
+
+
+-- Read receipts: the last message each participant has read.
+CREATE TABLE IF NOT EXISTS message_read ( 
+    thread INTEGER NOT NULL,
+    account INTEGER NOT NULL,
+    last_read INTEGER NOT NULL,
+    PRIMARY KEY(thread, account),
+    FOREIGN KEY(thread) REFERENCES message_thread(id),
+    FOREIGN KEY(account) REFERENCES account(id)
+);
+
+
+-- Lab tests ordered during a visit.
+CREATE TABLE IF NOT EXISTS lab_order ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL,
+    patient INTEGER NOT NULL,
+    doctor INTEGER NOT NULL,
+    test_code VARCHAR(20) NOT NULL,
+    test_name VARCHAR(100) NOT NULL,
+    notes TEXT NOT NULL,
+    lab_status INTEGER NOT NULL,
+    ordered_at INTEGER NOT NULL,
+    collected_at INTEGER,
+    resulted_at INTEGER,
+    reviewed_at INTEGER,
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(doctor) REFERENCES account(id)
+);
+
+
+-- One row per observation. Corrected results replace the order's rows.
+CREATE TABLE IF NOT EXISTS lab_result ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    lab_order INTEGER NOT NULL,
+    code VARCHAR(20) NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    value VARCHAR(100) NOT NULL,
+    units VARCHAR(20) NOT NULL,
+    reference_range VARCHAR(50) NOT NULL,
+    flag INTEGER,
+    FOREIGN KEY(lab_order) REFERENCES lab_order(id)
+);
+
+-- One invoice per completed appointment. Amounts are in the smallest
+-- currency unit.
+CREATE TABLE IF NOT EXISTS invoice ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL UNIQUE,
+    patient INTEGER NOT NULL,
+    doctor INTEGER NOT NULL,
+    invoice_status INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    issued_at INTEGER,
+    paid_at INTEGER,
+    voided_at INTEGER,
+    void_reason TEXT,
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(doctor) REFERENCES account(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS invoice_line ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    invoice INTEGER NOT NULL,
+    description VARCHAR(200) NOT NULL,
+    quantity INTEGER NOT NULL,
+    unit_price INTEGER NOT NULL,
+    discount INTEGER NOT NULL default 0,
+    tax_rate_bps INTEGER NOT NULL default 0,
+    FOREIGN KEY(invoice) REFERENCES invoice(id)
+);
+
+-- Deposits taken at booking and what was refunded of them.
+CREATE TABLE IF NOT EXISTS payment ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL UNIQUE,
+    reference VARCHAR(100) NOT NULL,
+    amount INTEGER NOT NULL,
+    refunded INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    refunded_at INTEGER,
+    FOREIGN KEY(appointment) REFERENCES appointment(id)
+);
+-- Plans the clinic can bill. Empty coverage lists cover every appointment
+-- type or specialty.
+CREATE TABLE IF NOT EXISTS insurance_plan ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    payer_name VARCHAR(100) NOT NULL,
+    payer_id VARCHAR(50) NOT NULL,
+    plan_name VARCHAR(100) NOT NULL,
+    covered_types TEXT NOT NULL default "[]",
+    covered_specialties TEXT NOT NULL default "[]",
+    copay INTEGER NOT NULL default 0
+);
+
+-- One claim per covered completed appointment. The policy details are
+-- copied from the patient so later profile edits don't change the claim.
+CREATE TABLE IF NOT EXISTS claim ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    appointment INTEGER NOT NULL UNIQUE,
+    invoice INTEGER NOT NULL,
+    patient INTEGER NOT NULL,
+    plan INTEGER NOT NULL,
+    member_id VARCHAR(50) NOT NULL,
+    group_number VARCHAR(50),
+    billed_amount INTEGER NOT NULL,
+    copay INTEGER NOT NULL,
+    claim_status INTEGER NOT NULL,
+    batch INTEGER,
+    created_at INTEGER NOT NULL,
+    submitted_at INTEGER,
+    decided_at INTEGER,
+    paid_at INTEGER,
+    paid_amount INTEGER,
+    denial_reason TEXT,
+    FOREIGN KEY(appointment) REFERENCES appointment(id),
+    FOREIGN KEY(invoice) REFERENCES invoice(id),
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(plan) REFERENCES insurance_plan(id),
+    FOREIGN KEY(batch) REFERENCES claim_batch(id)
+);
+
+-- Exported claim files, kept so they can be downloaded again.
+CREATE TABLE IF NOT EXISTS claim_batch ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    created_at INTEGER NOT NULL,
+    claim_count INTEGER NOT NULL,
+    contents TEXT NOT NULL
+);
+-- Consent forms. Each code, e.g. "telehealth", is published in numbered
+-- versions and only the latest one can be accepted.
+CREATE TABLE IF NOT EXISTS consent_document ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    code VARCHAR(50) NOT NULL,
+    version INTEGER NOT NULL,
+    title VARCHAR(200) NOT NULL,
+    body TEXT NOT NULL,
+    purpose INTEGER NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE(code, version),
+    FOREIGN KEY(created_by) REFERENCES account(id)
+);
+
+-- Acceptances of consent documents, kept after they are withdrawn.
+CREATE TABLE IF NOT EXISTS consent ( 
+    id INTEGER PRIMARY KEY AUTOINCREMENT UNIQUE,
+    patient INTEGER NOT NULL,
+    document INTEGER NOT NULL,
+    accepted_by INTEGER NOT NULL,
+    accepted_at INTEGER NOT NULL,
+    withdrawn_by INTEGER,
+    withdrawn_at INTEGER,
+    FOREIGN KEY(patient) REFERENCES account(id),
+    FOREIGN KEY(document) REFERENCES consent_document(id),
+    FOREIGN KEY(accepted_by) REFERENCES account(id),
+    FOREIGN KEY(withdrawn_by) REFERENCES account(id)
+);
+
+-- Full-text index over doctor profiles. The rowid is the doctor id.
+CREATE VIRTUAL TABLE IF NOT EXISTS doctor_fts USING fts5(
+    specialty,
+    details,
+    tokenize = 'porter unicode61'
+);
+
+CREATE TRIGGER IF NOT EXISTS doctor_fts_insert AFTER INSERT ON doctor BEGIN
+    INSERT INTO doctor_fts (rowid, specialty, details) VALUES (new.id, new.specialty, new.details);
+END;
+
+CREATE TRIGGER IF NOT EXISTS doctor_fts_update AFTER UPDATE OF specialty, details ON doctor BEGIN
+    UPDATE doctor_fts SET specialty = new.specialty, details = new.details WHERE rowid = new.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS doctor_fts_delete AFTER DELETE ON doctor BEGIN
+    DELETE FROM doctor_fts WHERE rowid = old.id;
+END;
+
+INSERT INTO doctor_fts (rowid, specialty, details)
+    SELECT id, specialty, details FROM doctor WHERE id NOT IN (SELECT rowid FROM doctor_fts);
+
+-- Accounts created while the patient and admin tables were missing.
+INSERT OR IGNORE INTO patient (id) SELECT id FROM account WHERE account_type = 0;
+INSERT OR IGNORE INTO admin (id) SELECT id FROM account WHERE account_type = 2;
 COMMIT;
\ No newline at end of file
@@ -0,0 +1,101 @@
+//! Where attachment contents live. The database only keeps their metadata
+//! and the key they were stored under.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// Attachment settings, read from the Rocket config
+/// (e.g. `ROCKET_MAX_ATTACHMENT_BYTES=5242880`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttachmentSettings {
+    /// Root directory of the local filesystem storage.
+    pub attachments_dir: PathBuf,
+    pub max_attachment_bytes: u64,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        Self {
+            attachments_dir: PathBuf::from("attachments"),
+            max_attachment_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Keeps each object in its own file, fanned out by the key's first two
+/// characters.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        // Keys are generated by us, but never let one escape the root.
+        if key.len() < 3 || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"));
+        }
+        Ok(self.root.join(&key[..2]).join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Write to a temporary file first so readers never see half an upload.
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalStorage, Storage};
+
+    #[test]
+    fn test_local_storage() {
+        let root = std::env::temp_dir().join(format!("attachments-{}", std::process::id()));
+        let storage = LocalStorage::new(root.clone());
+
+        storage.put("abcdef", b"contents").unwrap();
+        assert!(root.join("ab").join("abcdef").exists());
+        assert_eq!(storage.get("abcdef").unwrap(), b"contents");
+
+        storage.put("abcdef", b"replaced").unwrap();
+        assert_eq!(storage.get("abcdef").unwrap(), b"replaced");
+
+        storage.delete("abcdef").unwrap();
+        storage.delete("abcdef").unwrap();
+        assert!(storage.get("abcdef").is_err());
+
+        assert!(storage.put("../etc", b"").is_err());
+        assert!(storage.get("a").is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Claim batches in the layout of an X12 837P (005010X222A1) professional
+//! claim file, trimmed to the segments the clearinghouse reads.
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+const SENDER_ID: &str = "CLINIC";
+const RECEIVER_ID: &str = "CLEARINGHOUSE";
+const VERSION: &str = "005010X222A1";
+
+pub struct ClaimRecord {
+    pub claim_id: i32,
+    pub patient_name: String,
+    pub member_id: String,
+    pub group_number: Option<String>,
+    pub payer_name: String,
+    pub payer_id: String,
+    pub doctor_name: String,
+    pub service_date: NaiveDate,
+    /// In the smallest currency unit.
+    pub billed_amount: i64,
+    /// ICD-10 codes, e.g. "J45.909".
+    pub diagnosis_codes: Vec<String>,
+}
+
+/// Separators can't be escaped in X12, so they are blanked out of values.
+fn element(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '*' | '~' | ':' | '^' | '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn amount(cents: i64) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+/// One interchange holding a claim per record. The batch id is used as the
+/// control number.
+pub fn claim_batch(batch_id: i32, created_at: NaiveDateTime, claims: &[ClaimRecord]) -> String {
+    let date = created_at.format("%Y%m%d").to_string();
+    let time = created_at.format("%H%M").to_string();
+
+    let mut segments = vec![
+        format!("ST*837*0001*{}", VERSION),
+        format!("BHT*0019*00*{}*{}*{}*CH", batch_id, date, time),
+    ];
+    for (i, claim) in claims.iter().enumerate() {
+        segments.push(format!("HL*{}**22*0", i + 1));
+        segments.push(format!(
+            "SBR*P*18*{}******CI",
+            element(claim.group_number.as_deref().unwrap_or(""))
+        ));
+        segments.push(format!(
+            "NM1*IL*1*{}****MI*{}",
+            element(&claim.patient_name),
+            element(&claim.member_id)
+        ));
+        segments.push(format!(
+            "NM1*PR*2*{}*****PI*{}",
+            element(&claim.payer_name),
+            element(&claim.payer_id)
+        ));
+        segments.push(format!(
+            "CLM*{}*{}***11:B:1*Y*A*Y*Y",
+            claim.claim_id,
+            amount(claim.billed_amount)
+        ));
+        segments.push(format!(
+            "DTP*472*D8*{}",
+            claim.service_date.format("%Y%m%d")
+        ));
+        if !claim.diagnosis_codes.is_empty() {
+            // The first code is the principal diagnosis.
+            let codes: Vec<String> = claim
+                .diagnosis_codes
+                .iter()
+                .enumerate()
+                .map(|(i, code)| {
+                    let qualifier = if i == 0 { "ABK" } else { "ABF" };
+                    format!("{}:{}", qualifier, element(&code.replace('.', "")))
+                })
+                .collect();
+            segments.push(format!("HI*{}", codes.join("*")));
+        }
+        segments.push(format!("NM1*82*1*{}", element(&claim.doctor_name)));
+    }
+    segments.push(format!("SE*{}*0001", segments.len() + 1));
+
+    let mut file = vec![
+        format!(
+            "ISA*00*{:10}*00*{:10}*ZZ*{:15}*ZZ*{:15}*{}*{}*^*00501*{:09}*0*P*:",
+            "",
+            "",
+            SENDER_ID,
+            RECEIVER_ID,
+            created_at.format("%y%m%d"),
+            time,
+            batch_id
+        ),
+        format!(
+            "GS*HC*{}*{}*{}*{}*{}*X*{}",
+            SENDER_ID, RECEIVER_ID, date, time, batch_id, VERSION
+        ),
+    ];
+    file.extend(segments);
+    file.push(format!("GE*1*{}", batch_id));
+    file.push(format!("IEA*1*{:09}", batch_id));
+    file.iter()
+        .map(|segment| format!("{}~\n", segment))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{claim_batch, ClaimRecord};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_claim_batch() {
+        let claim = ClaimRecord {
+            claim_id: 12,
+            patient_name: "Jane *Doe".to_string(),
+            member_id: "M123".to_string(),
+            group_number: Some("G9".to_string()),
+            payer_name: "Acme Health".to_string(),
+            payer_id: "ACME1".to_string(),
+            doctor_name: "Dr. Smith".to_string(),
+            service_date: NaiveDate::from_ymd(2022, 1, 5),
+            billed_amount: 12_505,
+            diagnosis_codes: vec!["J45.909".to_string(), "R05".to_string()],
+        };
+        let created_at = NaiveDate::from_ymd(2022, 1, 6).and_hms(9, 30, 0);
+
+        let batch = claim_batch(7, created_at, &[claim]);
+        let segments: Vec<&str> = batch.lines().collect();
+        assert_eq!(
+            segments[0],
+            "ISA*00*          *00*          *ZZ*CLINIC         *ZZ*CLEARINGHOUSE  *220106*0930*^*00501*000000007*0*P*:~"
+        );
+        assert!(segments.contains(&"NM1*IL*1*Jane  Doe****MI*M123~"));
+        assert!(segments.contains(&"CLM*12*125.05***11:B:1*Y*A*Y*Y~"));
+        assert!(segments.contains(&"DTP*472*D8*20220105~"));
+        assert!(segments.contains(&"HI*ABK:J45909*ABF:R05~"));
+        // ST through SE: 2 header segments, 8 for the claim, and SE itself.
+        assert!(segments.contains(&"SE*11*0001~"));
+        assert_eq!(segments.last(), Some(&"IEA*1*000000007~"));
+    }
+}